@@ -0,0 +1,391 @@
+//! コマンドラインの解析。サブコマンドとオプションを型のある値にする。
+//! サブコマンドを書かずに `packetdump <NETWORK INTERFACE>` や `packetdump --read <PCAP FILE>` と
+//! 書いた以前の形も live / read として受け付ける。
+
+use super::Framing;
+
+pub const USAGE: &str = "\
+USAGE:
+    packetdump live <NETWORK INTERFACE | any> [OPTIONS]
+    packetdump live -i <NETWORK INTERFACE> [-i <NETWORK INTERFACE>]... [OPTIONS]
+    packetdump read <PCAP FILE> [OPTIONS]
+    packetdump stats <PCAP FILE> [OPTIONS]
+    packetdump export <PCAP FILE> [OPTIONS]
+    packetdump list-interfaces
+    packetdump help
+
+COMMANDS:
+    live               Capture from network interfaces and decode packets
+    read               Decode packets from a pcap file
+    stats              Count Modbus transactions in a pcap file per device and function
+    export             Print only the Modbus traffic of a pcap file
+    list-interfaces    List network interfaces with their addresses and flags
+
+OPTIONS:
+    -i, --interface <NAME>           Interface for live (repeatable; any: every interface that is up)
+    --rtu-port <PORT>                Port carrying raw RTU frames without an MBAP header (repeatable)
+    --ascii-port <PORT>              Port carrying Modbus ASCII (repeatable)
+    --tls-port <PORT>                Modbus/TCP Security (TLS) port in addition to 802 (repeatable)
+    --keylog <FILE>                  SSLKEYLOGFILE-format key log for decrypting TLS
+    --filter <EXPRESSION>            tcpdump-style capture filter (port and portrange also let
+                                     through non-first IP fragments so that they can be reassembled)
+    --display-filter <EXPRESSION>    Filter on decoded Modbus fields
+    --register-map <FILE>            TOML or CSV file naming registers per device and unit (e.g. TankLevel = 73.4 %)
+    --format <FORMAT>                Output format: text (default), jsonl (one JSON object per Modbus ADU)
+                                     or csv (one row per register or coil value read or written)
+    --write <FILE>                   Write the frames matching --filter and --display-filter to a pcap file
+    -v                               Print every layer of each frame as a field tree with offsets and lengths
+    -x                               Print a hex and ASCII dump of each frame with the Modbus bytes highlighted
+    --verbosity <LEVEL>              summary: one line per request/reply pair,
+                                     modbus: only Modbus traffic (default for export),
+                                     full: every packet (default for live and read)
+    -q                               Same as --verbosity modbus
+    --time <FORMAT>                  absolute: UTC date and time (default), relative: seconds since the first frame,
+                                     delta: seconds since the previous frame of the same flow, none: no time in text
+    -h, --help                       Print this help";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// 複数のインタフェースから同時に受信する。"any" は起動しているすべてのインタフェース
+    Live { interfaces: Vec<String> },
+    Read { path: String },
+    Stats { path: String },
+    Export { path: String },
+    ListInterfaces,
+    Help,
+}
+
+/// 出力形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    /// ADU ごとに 1 行の JSON
+    JsonLines,
+    /// 読み書きされたコイル・レジスタの値ごとに 1 行
+    Csv,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "text" => Ok(Format::Text),
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format: {}", name)),
+        }
+    }
+}
+
+/// 時刻の表し方 (--time)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// UTC の日時
+    Absolute,
+    /// 最初のフレームからの秒
+    Relative,
+    /// 同じフローの直前のフレームからの秒
+    Delta,
+    /// テキストの行に時刻を付けない
+    Hidden,
+}
+
+impl TimeFormat {
+    fn parse(name: &str) -> Result<TimeFormat, String> {
+        match name {
+            "absolute" => Ok(TimeFormat::Absolute),
+            "relative" => Ok(TimeFormat::Relative),
+            "delta" => Ok(TimeFormat::Delta),
+            "none" => Ok(TimeFormat::Hidden),
+            _ => Err(format!("unknown time format: {}", name)),
+        }
+    }
+}
+
+/// 表示する詳しさ
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// 何も表示しない (stats の集計中)
+    Silent,
+    /// リクエストと応答の組ごとに 1 行だけ
+    Summary,
+    /// Modbus のポートのパケットと ADU だけ
+    Modbus,
+    /// すべてのパケット
+    Full,
+}
+
+impl Verbosity {
+    fn parse(name: &str) -> Result<Verbosity, String> {
+        match name {
+            "summary" => Ok(Verbosity::Summary),
+            "modbus" => Ok(Verbosity::Modbus),
+            "full" => Ok(Verbosity::Full),
+            _ => Err(format!("unknown verbosity: {}", name)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub command: Command,
+    /// 502番 (ModbusTCP) と 802番 (TLS) のほかに解析するポート
+    pub modbus_ports: Vec<(u16, Framing)>,
+    pub keylog: Option<String>,
+    pub filter: Option<String>,
+    pub display_filter: Option<String>,
+    /// タグ名を付けるレジスタマップ
+    pub register_map: Option<String>,
+    /// 一致したフレームを書き出す pcap ファイル
+    pub write: Option<String>,
+    pub format: Format,
+    /// 指定がなければコマンドごとの既定値
+    pub verbosity: Option<Verbosity>,
+    /// -v: 層ごとのフィールドの階層表示
+    pub tree: bool,
+    /// -x: 16 進ダンプ
+    pub hex_dump: bool,
+    pub time: TimeFormat,
+}
+
+fn value(args: &mut dyn Iterator<Item = String>, option: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} requires a value", option))
+}
+
+fn port(args: &mut dyn Iterator<Item = String>, option: &str) -> Result<u16, String> {
+    let text = value(args, option)?;
+    text.parse()
+        .map_err(|_| format!("invalid port for {}: {}", option, text))
+}
+
+/// 引数 (プログラム名を除く) を解析する。誤りがあればメッセージを返す
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.into_iter().peekable();
+    let command_name = match args.peek().map(|a| &a[..]) {
+        Some("live") | Some("read") | Some("stats") | Some("export") | Some("list-interfaces")
+        | Some("help") => args.next(),
+        _ => None,
+    };
+    let mut options = Options {
+        command: Command::Help,
+        modbus_ports: Vec::new(),
+        keylog: None,
+        filter: None,
+        display_filter: None,
+        register_map: None,
+        write: None,
+        format: Format::Text,
+        verbosity: None,
+        tree: false,
+        hex_dump: false,
+        time: TimeFormat::Absolute,
+    };
+    let mut target: Option<String> = None;
+    let mut interfaces = Vec::new();
+    /* 以前の形の --read */
+    let mut read_path: Option<String> = None;
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-h" | "--help" => {
+                options.command = Command::Help;
+                return Ok(options);
+            }
+            "-i" | "--interface" => interfaces.push(value(&mut args, &arg)?),
+            "--read" if command_name.is_none() => read_path = Some(value(&mut args, &arg)?),
+            "--rtu-port" => options.modbus_ports.push((port(&mut args, &arg)?, Framing::Rtu)),
+            "--ascii-port" => options.modbus_ports.push((port(&mut args, &arg)?, Framing::Ascii)),
+            "--tls-port" => options.modbus_ports.push((port(&mut args, &arg)?, Framing::Tls)),
+            "--keylog" => options.keylog = Some(value(&mut args, &arg)?),
+            "--filter" => options.filter = Some(value(&mut args, &arg)?),
+            "--display-filter" => options.display_filter = Some(value(&mut args, &arg)?),
+            "--register-map" => options.register_map = Some(value(&mut args, &arg)?),
+            "-q" => options.verbosity = Some(Verbosity::Modbus),
+            "-v" => options.tree = true,
+            "-x" => options.hex_dump = true,
+            "--write" => options.write = Some(value(&mut args, &arg)?),
+            "--format" => options.format = Format::parse(&value(&mut args, &arg)?)?,
+            "--verbosity" => options.verbosity = Some(Verbosity::parse(&value(&mut args, &arg)?)?),
+            "--time" => options.time = TimeFormat::parse(&value(&mut args, &arg)?)?,
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+            _ if target.is_none() => target = Some(arg),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+    let missing = |what: &str| format!("{} is required", what);
+    if !interfaces.is_empty() && !matches!(command_name.as_deref(), Some("live") | None) {
+        return Err("-i is only for live".to_string());
+    }
+    options.command = match command_name.as_deref() {
+        Some("live") => {
+            interfaces.splice(0..0, target);
+            if interfaces.is_empty() {
+                return Err(missing("<NETWORK INTERFACE>"));
+            }
+            Command::Live { interfaces }
+        }
+        Some("read") => Command::Read {
+            path: target.ok_or_else(|| missing("<PCAP FILE>"))?,
+        },
+        Some("stats") => Command::Stats {
+            path: target.ok_or_else(|| missing("<PCAP FILE>"))?,
+        },
+        Some("export") => Command::Export {
+            path: target.ok_or_else(|| missing("<PCAP FILE>"))?,
+        },
+        Some("list-interfaces") => match target {
+            Some(arg) => return Err(format!("unexpected argument: {}", arg)),
+            None => Command::ListInterfaces,
+        },
+        Some(_) => Command::Help,
+        None => {
+            interfaces.splice(0..0, target);
+            match (read_path, interfaces.is_empty()) {
+                (Some(path), true) => Command::Read { path },
+                (None, false) => Command::Live { interfaces },
+                (Some(_), false) => return Err("--read and an interface are exclusive".to_string()),
+                (None, true) => return Err("no command given".to_string()),
+            }
+        }
+    };
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Options, String> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse_args(args).unwrap().command
+    }
+
+    fn error(args: &[&str]) -> String {
+        parse_args(args).err().unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            command(&["live", "eth0", "-i", "eth1"]),
+            Command::Live {
+                interfaces: vec!["eth0".to_string(), "eth1".to_string()]
+            }
+        );
+        assert_eq!(
+            command(&["live", "-i", "eth1"]),
+            Command::Live {
+                interfaces: vec!["eth1".to_string()]
+            }
+        );
+        assert_eq!(
+            command(&["read", "a.pcap"]),
+            Command::Read {
+                path: "a.pcap".to_string()
+            }
+        );
+        assert_eq!(
+            command(&["stats", "a.pcap"]),
+            Command::Stats {
+                path: "a.pcap".to_string()
+            }
+        );
+        assert_eq!(command(&["list-interfaces"]), Command::ListInterfaces);
+        assert_eq!(command(&["help"]), Command::Help);
+        assert_eq!(command(&["read", "a.pcap", "--help"]), Command::Help);
+    }
+
+    #[test]
+    fn legacy_forms() {
+        assert_eq!(
+            command(&["eth0"]),
+            Command::Live {
+                interfaces: vec!["eth0".to_string()]
+            }
+        );
+        assert_eq!(
+            command(&["-i", "eth0", "-i", "eth1"]),
+            Command::Live {
+                interfaces: vec!["eth0".to_string(), "eth1".to_string()]
+            }
+        );
+        assert_eq!(
+            command(&["--read", "a.pcap"]),
+            Command::Read {
+                path: "a.pcap".to_string()
+            }
+        );
+        assert_eq!(
+            error(&["--read", "a.pcap", "eth0"]),
+            "--read and an interface are exclusive"
+        );
+        assert_eq!(error(&[]), "no command given");
+        /* サブコマンドを書いたときは --read は使えない */
+        assert_eq!(error(&["read", "--read", "a.pcap"]), "unknown option: --read");
+    }
+
+    #[test]
+    fn options() {
+        let options = parse_args(&[
+            "read",
+            "a.pcap",
+            "--rtu-port",
+            "5020",
+            "--ascii-port",
+            "5021",
+            "--tls-port",
+            "8802",
+            "--filter",
+            "tcp port 502",
+            "--format",
+            "jsonl",
+            "-q",
+            "-v",
+            "-x",
+            "--time",
+            "none",
+        ])
+        .unwrap();
+        assert_eq!(
+            options.modbus_ports,
+            vec![
+                (5020, Framing::Rtu),
+                (5021, Framing::Ascii),
+                (8802, Framing::Tls)
+            ]
+        );
+        assert_eq!(options.filter.as_deref(), Some("tcp port 502"));
+        assert_eq!(options.format, Format::JsonLines);
+        assert_eq!(options.verbosity, Some(Verbosity::Modbus));
+        assert!(options.tree && options.hex_dump);
+        assert_eq!(options.time, TimeFormat::Hidden);
+        let defaults = parse_args(&["read", "a.pcap"]).unwrap();
+        assert_eq!(defaults.format, Format::Text);
+        assert_eq!(defaults.verbosity, None);
+        assert_eq!(defaults.time, TimeFormat::Absolute);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(&["read", "a.pcap", "-i", "eth0"]), "-i is only for live");
+        assert_eq!(error(&["stats", "-i", "eth0", "a.pcap"]), "-i is only for live");
+        assert_eq!(error(&["read", "a.pcap", "--bogus"]), "unknown option: --bogus");
+        assert_eq!(error(&["read", "a.pcap", "b.pcap"]), "unexpected argument: b.pcap");
+        assert_eq!(error(&["read"]), "<PCAP FILE> is required");
+        assert_eq!(error(&["live"]), "<NETWORK INTERFACE> is required");
+        assert_eq!(error(&["list-interfaces", "eth0"]), "unexpected argument: eth0");
+        assert_eq!(error(&["read", "a.pcap", "--filter"]), "--filter requires a value");
+        assert_eq!(
+            error(&["read", "a.pcap", "--rtu-port", "70000"]),
+            "invalid port for --rtu-port: 70000"
+        );
+        assert_eq!(error(&["read", "a.pcap", "--format", "xml"]), "unknown format: xml");
+        assert_eq!(error(&["read", "a.pcap", "--time", "local"]), "unknown time format: local");
+        assert_eq!(
+            error(&["read", "a.pcap", "--verbosity", "loud"]),
+            "unknown verbosity: loud"
+        );
+    }
+}
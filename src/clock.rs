@@ -0,0 +1,151 @@
+//! --time のためのキャプチャ時刻の計算。最初のフレームの時刻と、フロー (TCP・UDP の接続を
+//! 両方向まとめたもの) ごとの直前のフレームの時刻を覚えておく。TCP・UDP 以外のフレーム
+//! (ARP, ICMP, シリアルバスなど) はまとめて 1 つのフローとして扱う。
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::output::Time;
+use super::Flow;
+
+/// 覚えておくフローの数がこれを超えたら、しばらくフレームのないものを捨てる
+const MAX_FLOWS: usize = 10000;
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct Clock {
+    /// 最初のフレームの時刻
+    first: Option<Duration>,
+    /// フローごとの直前のフレームの時刻 (None は TCP・UDP 以外のフレーム)
+    last: HashMap<Option<Flow>, Duration>,
+    /// 解析中のフレームの時刻
+    time: Time,
+    /// 解析中のフレームが TCP・UDP のフローのものと分かったか
+    in_flow: bool,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    /// フレームの解析を始める。フローが分かるまでは TCP・UDP 以外のフレームとしての差にしておく
+    pub fn start_frame(&mut self, timestamp: Duration) {
+        let first = *self.first.get_or_insert(timestamp);
+        self.time = Time {
+            absolute: timestamp,
+            relative: timestamp.saturating_sub(first),
+            delta: self.since(&None, timestamp),
+        };
+        self.in_flow = false;
+    }
+
+    /// 解析中のフレームが flow のものと分かった (トンネルの中のフローが分かれば、そちらに変わる)
+    pub fn enter_flow(&mut self, flow: &Flow) {
+        let key = Some(flow.connection());
+        let now = self.time.absolute;
+        self.time.delta = self.since(&key, now);
+        if self.last.len() >= MAX_FLOWS {
+            self.last
+                .retain(|_, last| now.saturating_sub(*last) < FLOW_TIMEOUT);
+        }
+        self.last.insert(key, now);
+        self.in_flow = true;
+    }
+
+    /// フレームの解析を終える
+    pub fn finish_frame(&mut self) {
+        if !self.in_flow {
+            self.last.insert(None, self.time.absolute);
+        }
+    }
+
+    /// 解析中のフレームの時刻
+    pub fn time(&self) -> &Time {
+        &self.time
+    }
+
+    fn since(&self, key: &Option<Flow>, now: Duration) -> Option<Duration> {
+        self.last.get(key).map(|last| now.saturating_sub(*last))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(source_port: u16, destination_port: u16) -> Flow {
+        Flow {
+            vlans: Vec::new(),
+            source: "10.0.0.1".parse().unwrap(),
+            source_port,
+            destination: "10.0.0.2".parse().unwrap(),
+            destination_port,
+        }
+    }
+
+    /// flows の順にフローに入るフレームを 1 つ解析し、(最初からの時間, 直前からの時間) をミリ秒で返す
+    fn frame(clock: &mut Clock, millis: u64, flows: &[&Flow]) -> (u64, Option<u64>) {
+        clock.start_frame(Duration::from_millis(millis));
+        for flow in flows {
+            clock.enter_flow(flow);
+        }
+        let time = clock.time();
+        let millis_of = |duration: Duration| duration.as_millis() as u64;
+        let result = (millis_of(time.relative), time.delta.map(millis_of));
+        assert_eq!(time.absolute, Duration::from_millis(millis));
+        clock.finish_frame();
+        result
+    }
+
+    #[test]
+    fn deltas() {
+        let mut clock = Clock::new();
+        let (a, b) = (flow(50000, 502), flow(50001, 502));
+        /* 最初のフレームには直前がない */
+        assert_eq!(frame(&mut clock, 1000, &[&a]), (0, None));
+        assert_eq!(frame(&mut clock, 1200, &[&b]), (200, None));
+        /* 同じフローの直前との差で、逆向きも同じフロー */
+        assert_eq!(frame(&mut clock, 1250, &[&a.reversed()]), (250, Some(250)));
+        assert_eq!(frame(&mut clock, 1300, &[&a]), (300, Some(50)));
+        assert_eq!(frame(&mut clock, 1700, &[&b.reversed()]), (700, Some(500)));
+        /* TCP・UDP 以外のフレームはまとめて 1 つのフロー */
+        assert_eq!(frame(&mut clock, 2000, &[]), (1000, None));
+        assert_eq!(frame(&mut clock, 2100, &[]), (1100, Some(100)));
+        assert_eq!(frame(&mut clock, 2150, &[&a]), (1150, Some(850)));
+        assert_eq!(frame(&mut clock, 2400, &[]), (1400, Some(300)));
+        /* 時刻が戻っても負にしない */
+        assert_eq!(frame(&mut clock, 500, &[]), (0, Some(0)));
+    }
+
+    #[test]
+    fn tunnels() {
+        let mut clock = Clock::new();
+        let (outer, inner) = (flow(40000, 4789), flow(50000, 502));
+        assert_eq!(frame(&mut clock, 0, &[&outer, &inner]), (0, None));
+        assert_eq!(frame(&mut clock, 100, &[&outer]), (100, Some(100)));
+        /* 中のフローが分かれば、その直前との差に変わる */
+        assert_eq!(frame(&mut clock, 300, &[&outer, &inner]), (300, Some(300)));
+        assert_eq!(frame(&mut clock, 350, &[&inner]), (350, Some(50)));
+        assert_eq!(frame(&mut clock, 400, &[&outer]), (400, Some(100)));
+    }
+
+    #[test]
+    fn pruning() {
+        let mut clock = Clock::new();
+        clock.start_frame(Duration::ZERO);
+        for port in 0..MAX_FLOWS as u16 - 1 {
+            clock.enter_flow(&flow(port, 502));
+        }
+        clock.finish_frame();
+        let recent = flow(60000, 502);
+        assert_eq!(frame(&mut clock, 30_000, &[&recent]), (30_000, None));
+        assert_eq!(clock.last.len(), MAX_FLOWS);
+        /* 上限に達したら FLOW_TIMEOUT の間フレームのないものを捨てる */
+        let timeout = FLOW_TIMEOUT.as_millis() as u64;
+        assert_eq!(frame(&mut clock, timeout, &[&flow(60001, 502)]), (timeout, None));
+        assert_eq!(clock.last.len(), 2);
+        assert_eq!(frame(&mut clock, timeout + 1, &[&flow(0, 502)]), (timeout + 1, None));
+        assert_eq!(frame(&mut clock, timeout + 2, &[&recent]), (timeout + 2, Some(30_002)));
+    }
+}
@@ -0,0 +1,595 @@
+//! デコードした Modbus の値に対する表示フィルタ (--display-filter)。
+//! 例: `unit == 3 && fc in {5,6,15,16} && addr in 40100..40200`, `exception && src == 10.0.0.5`
+//!
+//! フィールド:
+//!   unit, fc (function), addr (address, ref, reference: 40001 などの Modicon 形式の番号),
+//!   pdu_addr (pdu_address: PDU 上の 0 始まりの番号),
+//!   qty (quantity), value (values), exception (例外コード, 単独なら例外応答かどうか),
+//!   direction (request / reply), request, reply, latency (応答時間 ms), transaction (tid),
+//!   src, dst, host (送信元か送信先), sport, dport, port (送信元か送信先)
+//! 演算子: == != < <= > >=, `in {1,2,5..9}`, `in 1..9` (範囲は両端を含む),
+//!   && (and), || (or), ! (not), 括弧。
+//! value のように複数の値を持つフィールドは、どれか 1 つが条件を満たせば一致とする。
+//! addr と pdu_addr は先頭番号から個数ぶんの番号をすべて持つので、40090 から 21 個の読み出しは
+//! `addr in 40100..40200` に一致する。
+
+use std::net::IpAddr;
+
+use super::packet::fields::AduFields;
+use super::Flow;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Number(f64),
+    Address(IpAddr),
+    /// true ならリクエスト
+    Direction(bool),
+}
+
+impl Value {
+    fn compare(&self, other: &Value) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::Address(a), Value::Address(b)) if a.is_ipv4() == b.is_ipv4() => {
+                Some(a.cmp(b))
+            }
+            (Value::Direction(a), Value::Direction(b)) if a == b => {
+                Some(std::cmp::Ordering::Equal)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// 先頭番号から個数ぶん (個数が分からなければ先頭だけ) の番号
+fn covered(start: Option<u32>, quantity: Option<u16>) -> Vec<Value> {
+    match start {
+        Some(start) => (0..quantity.unwrap_or(1).max(1) as u32)
+            .map(|i| Value::Number((start + i) as f64))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Unit,
+    Function,
+    Reference,
+    PduAddress,
+    Quantity,
+    Values,
+    Exception,
+    Direction,
+    Request,
+    Reply,
+    Latency,
+    Transaction,
+    Source,
+    Destination,
+    Host,
+    SourcePort,
+    DestinationPort,
+    Port,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "unit" => Some(Field::Unit),
+            "fc" | "function" => Some(Field::Function),
+            "addr" | "address" | "ref" | "reference" => Some(Field::Reference),
+            "pdu_addr" | "pdu_address" => Some(Field::PduAddress),
+            "qty" | "quantity" => Some(Field::Quantity),
+            "value" | "values" => Some(Field::Values),
+            "exception" => Some(Field::Exception),
+            "direction" => Some(Field::Direction),
+            "request" => Some(Field::Request),
+            "reply" => Some(Field::Reply),
+            "latency" => Some(Field::Latency),
+            "transaction" | "tid" => Some(Field::Transaction),
+            "src" => Some(Field::Source),
+            "dst" => Some(Field::Destination),
+            "host" => Some(Field::Host),
+            "sport" => Some(Field::SourcePort),
+            "dport" => Some(Field::DestinationPort),
+            "port" => Some(Field::Port),
+            _ => None,
+        }
+    }
+
+    /// ADU とフローからフィールドの値を取り出す。値がなければ空
+    fn values(&self, fields: &AduFields, flow: Option<&Flow>) -> Vec<Value> {
+        let number = |n: Option<f64>| n.map(Value::Number).into_iter().collect::<Vec<_>>();
+        match self {
+            Field::Unit => number(Some(fields.unit as f64)),
+            Field::Function => number(Some(fields.function as f64)),
+            Field::Reference => covered(fields.reference(), fields.quantity),
+            Field::PduAddress => covered(fields.address.map(u32::from), fields.quantity),
+            Field::Quantity => number(fields.quantity.map(|q| q as f64)),
+            Field::Values => fields.values.iter().map(|v| Value::Number(*v as f64)).collect(),
+            Field::Exception => number(fields.exception_code.map(|e| e as f64)),
+            Field::Direction => vec![Value::Direction(fields.is_request)],
+            Field::Request => number(Some(fields.is_request as u8 as f64)),
+            Field::Reply => number(Some(!fields.is_request as u8 as f64)),
+            Field::Latency => number(fields.latency.map(|l| l.as_secs_f64() * 1000.0)),
+            Field::Transaction => number(Some(fields.transaction as f64)),
+            Field::Source => flow.map(|f| Value::Address(f.source)).into_iter().collect(),
+            Field::Destination => flow.map(|f| Value::Address(f.destination)).into_iter().collect(),
+            Field::Host => flow
+                .map(|f| vec![Value::Address(f.source), Value::Address(f.destination)])
+                .unwrap_or_default(),
+            Field::SourcePort => number(flow.map(|f| f.source_port as f64)),
+            Field::DestinationPort => number(flow.map(|f| f.destination_port as f64)),
+            Field::Port => flow
+                .map(|f| {
+                    vec![
+                        Value::Number(f.source_port as f64),
+                        Value::Number(f.destination_port as f64),
+                    ]
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Member {
+    Value(Value),
+    /// 両端を含む範囲
+    Range(f64, f64),
+}
+
+impl Member {
+    fn contains(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Member::Range(low, high), Value::Number(n)) => low <= n && n <= high,
+            (Member::Value(member), value) => {
+                member.compare(value) == Some(std::cmp::Ordering::Equal)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    /// フィールドだけを書いたもの (値があり 0 でなければ真。exception は例外応答なら真)
+    Present(Field),
+    Compare(Field, Operator, Value),
+    In(Field, Vec<Member>),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    fn matches(&self, fields: &AduFields, flow: Option<&Flow>) -> bool {
+        use std::cmp::Ordering::*;
+        match self {
+            /* 例外コードが読めない (0 や切れた) 例外応答も例外応答とする */
+            Expression::Present(Field::Exception) => fields.exception,
+            Expression::Present(field) => field
+                .values(fields, flow)
+                .iter()
+                .any(|v| *v != Value::Number(0.0)),
+            Expression::Compare(field, operator, operand) => {
+                field.values(fields, flow).iter().any(|v| {
+                    match (operator, v.compare(operand)) {
+                        (_, None) => false,
+                        (Operator::Equal, Some(o)) => o == Equal,
+                        (Operator::NotEqual, Some(o)) => o != Equal,
+                        (Operator::Less, Some(o)) => o == Less,
+                        (Operator::LessEqual, Some(o)) => o != Greater,
+                        (Operator::Greater, Some(o)) => o == Greater,
+                        (Operator::GreaterEqual, Some(o)) => o != Less,
+                    }
+                })
+            }
+            Expression::In(field, members) => field
+                .values(fields, flow)
+                .iter()
+                .any(|v| members.iter().any(|m| m.contains(v))),
+            Expression::Not(e) => !e.matches(fields, flow),
+            Expression::And(a, b) => a.matches(fields, flow) && b.matches(fields, flow),
+            Expression::Or(a, b) => a.matches(fields, flow) || b.matches(fields, flow),
+        }
+    }
+}
+
+/// 記号は 1 つの字句に、それ以外は英数字と . : の並びを 1 つの字句にする。
+/// "40100..40200" は "40100", "..", "40200" に分ける。
+fn tokenize(expression: &str) -> Result<Vec<String>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphanumeric() || c == '_' || c == '.' || c == ':' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == ':')
+            {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let mut parts = word.splitn(2, "..");
+            let first = parts.next().unwrap_or("");
+            match parts.next() {
+                Some(rest) => {
+                    if !first.is_empty() {
+                        tokens.push(first.to_string());
+                    }
+                    tokens.push("..".to_string());
+                    if !rest.is_empty() {
+                        tokens.push(rest.to_string());
+                    }
+                }
+                None => tokens.push(word),
+            }
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            match &two[..] {
+                "==" | "!=" | "<=" | ">=" | "&&" | "||" => {
+                    tokens.push(two);
+                    i += 2;
+                }
+                _ => match c {
+                    '<' | '>' | '!' | '(' | ')' | '{' | '}' | ',' => {
+                        tokens.push(c.to_string());
+                        i += 1;
+                    }
+                    _ => return Err(format!("unexpected character '{}'", c)),
+                },
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_value(token: &str) -> Result<Value, String> {
+    match token {
+        "request" => return Ok(Value::Direction(true)),
+        "reply" => return Ok(Value::Direction(false)),
+        _ => {}
+    }
+    if let Some(hex) = token.strip_prefix("0x") {
+        if let Ok(n) = u32::from_str_radix(hex, 16) {
+            return Ok(Value::Number(n as f64));
+        }
+    }
+    if let Ok(n) = token.parse::<f64>() {
+        return Ok(Value::Number(n));
+    }
+    if let Ok(address) = token.parse::<IpAddr>() {
+        return Ok(Value::Address(address));
+    }
+    Err(format!("invalid value: {}", token))
+}
+
+fn parse_number(token: &str) -> Result<f64, String> {
+    match parse_value(token)? {
+        Value::Number(n) => Ok(n),
+        _ => Err(format!("expected a number: {}", token)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|t| &t[..])
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "unexpected end of expression".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(format!("expected '{}' but found '{}'", expected, token))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.and()?;
+        while let Some("||") | Some("or") = self.peek() {
+            self.position += 1;
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.unary()?;
+        while let Some("&&") | Some("and") = self.peek() {
+            self.position += 1;
+            expression = Expression::And(Box::new(expression), Box::new(self.unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.peek() {
+            Some("!") | Some("not") => {
+                self.position += 1;
+                Ok(Expression::Not(Box::new(self.unary()?)))
+            }
+            Some("(") => {
+                self.position += 1;
+                let expression = self.or()?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    /// 集合の要素 (値または範囲)
+    fn member(&mut self) -> Result<Member, String> {
+        let token = self.next()?;
+        if self.peek() == Some("..") {
+            self.position += 1;
+            let high = self.next()?;
+            Ok(Member::Range(parse_number(&token)?, parse_number(&high)?))
+        } else {
+            Ok(Member::Value(parse_value(&token)?))
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expression, String> {
+        let name = self.next()?;
+        let field = Field::from_name(&name).ok_or_else(|| format!("unknown field: {}", name))?;
+        let operator = match self.peek() {
+            Some("==") => Operator::Equal,
+            Some("!=") => Operator::NotEqual,
+            Some("<") => Operator::Less,
+            Some("<=") => Operator::LessEqual,
+            Some(">") => Operator::Greater,
+            Some(">=") => Operator::GreaterEqual,
+            Some("in") => {
+                self.position += 1;
+                let mut members = Vec::new();
+                if self.peek() == Some("{") {
+                    self.position += 1;
+                    loop {
+                        members.push(self.member()?);
+                        match self.next()?.as_str() {
+                            "," => continue,
+                            "}" => break,
+                            token => return Err(format!("expected ',' or '}}' but found '{}'", token)),
+                        }
+                    }
+                } else {
+                    members.push(self.member()?);
+                }
+                return Ok(Expression::In(field, members));
+            }
+            _ => return Ok(Expression::Present(field)),
+        };
+        self.position += 1;
+        let value = parse_value(&self.next()?)?;
+        Ok(Expression::Compare(field, operator, value))
+    }
+}
+
+pub struct DisplayFilter {
+    expression: Expression,
+}
+
+impl DisplayFilter {
+    /// 式を解析する。解析できなければ理由を返す
+    pub fn parse(expression: &str) -> Result<DisplayFilter, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            position: 0,
+        };
+        let expression = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected '{}'", token));
+        }
+        Ok(DisplayFilter { expression })
+    }
+
+    /// シリアルバスのフレームのようにフローがないときは src などのフィールドは値なしになる
+    pub fn matches(&self, fields: &AduFields, flow: Option<&Flow>) -> bool {
+        self.expression.matches(fields, flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn field(field: Field) -> Box<Expression> {
+        Box::new(Expression::Present(field))
+    }
+
+    /// 保持レジスタ 40090 (PDU 上の 89 番) から 21 個を読むリクエスト
+    fn read_request() -> AduFields {
+        AduFields {
+            transaction: 1,
+            unit: 3,
+            function: 3,
+            is_request: true,
+            exception_code: None,
+            address: Some(89),
+            quantity: Some(21),
+            ..AduFields::default()
+        }
+    }
+
+    fn flow() -> Flow {
+        Flow {
+            vlans: Vec::new(),
+            source: "10.0.0.5".parse().unwrap(),
+            source_port: 502,
+            destination: "10.0.0.9".parse().unwrap(),
+            destination_port: 40000,
+        }
+    }
+
+    fn matches(expression: &str, fields: &AduFields, flow: Option<&Flow>) -> bool {
+        DisplayFilter::parse(expression)
+            .unwrap()
+            .matches(fields, flow)
+    }
+
+    #[test]
+    fn parse() {
+        let parsed = |expression| DisplayFilter::parse(expression).unwrap().expression;
+        assert_eq!(
+            parsed("fc in {5,6,15..16}"),
+            Expression::In(
+                Field::Function,
+                vec![
+                    Member::Value(Value::Number(5.0)),
+                    Member::Value(Value::Number(6.0)),
+                    Member::Range(15.0, 16.0),
+                ]
+            )
+        );
+        assert_eq!(
+            parsed("addr in 40100..40200"),
+            Expression::In(Field::Reference, vec![Member::Range(40100.0, 40200.0)])
+        );
+        assert_eq!(
+            parsed("exception && !(src == 10.0.0.5 || direction == reply)"),
+            Expression::And(
+                field(Field::Exception),
+                Box::new(Expression::Not(Box::new(Expression::Or(
+                    Box::new(Expression::Compare(
+                        Field::Source,
+                        Operator::Equal,
+                        Value::Address("10.0.0.5".parse().unwrap())
+                    )),
+                    Box::new(Expression::Compare(
+                        Field::Direction,
+                        Operator::Equal,
+                        Value::Direction(false)
+                    )),
+                ))))
+            )
+        );
+        assert_eq!(
+            parsed("unit == 0x10 and pdu_addr >= 100"),
+            Expression::And(
+                Box::new(Expression::Compare(Field::Unit, Operator::Equal, Value::Number(16.0))),
+                Box::new(Expression::Compare(
+                    Field::PduAddress,
+                    Operator::GreaterEqual,
+                    Value::Number(100.0)
+                )),
+            )
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |expression| DisplayFilter::parse(expression).err().unwrap();
+        assert_eq!(error("register == 1"), "unknown field: register");
+        assert_eq!(error("unit == "), "unexpected end of expression");
+        assert_eq!(error("unit == three"), "invalid value: three");
+        assert_eq!(error("fc in {1, 2"), "unexpected end of expression");
+        assert_eq!(error("fc in {1 2}"), "expected ',' or '}' but found '2'");
+        assert_eq!(error("addr in 10.0.0.1..5"), "expected a number: 10.0.0.1");
+        assert_eq!(error("(unit == 1"), "unexpected end of expression");
+        assert_eq!(error("unit == 1 unit"), "unexpected 'unit'");
+        assert_eq!(error("unit = 1"), "unexpected character '='");
+    }
+
+    #[test]
+    fn register_ranges() {
+        let request = read_request();
+        /* 40090..40110 の読み出しは 40100..40200 と重なる */
+        assert!(matches(
+            "unit == 3 && fc in {3,4} && addr in 40100..40200",
+            &request,
+            None
+        ));
+        assert!(matches("addr == 40110 && ref == 40090", &request, None));
+        assert!(!matches("addr in 40111..40200", &request, None));
+        assert!(!matches("addr < 40090 || addr > 40110", &request, None));
+        assert!(matches("pdu_addr in 100..109 && !(pdu_addr == 110)", &request, None));
+        /* 個数の分からない応答は先頭番号だけ、先頭番号も分からなければ値なし */
+        let mut reply = AduFields {
+            is_request: false,
+            address: None,
+            quantity: None,
+            values: vec![1, 2],
+            ..read_request()
+        };
+        assert!(!matches("addr", &reply, None));
+        reply.pair(&request, Duration::from_millis(12));
+        assert!(matches("addr in 40100..40200 && latency < 20 && reply", &reply, None));
+        /* コイルは 00001 から */
+        let coil = AduFields {
+            function: 5,
+            address: Some(0),
+            quantity: Some(1),
+            values: vec![1],
+            ..read_request()
+        };
+        assert!(matches("addr == 1 && pdu_addr == 0 && value == 1", &coil, None));
+        assert!(!matches("addr == 40001", &coil, None));
+    }
+
+    #[test]
+    fn exceptions_and_flows() {
+        let exception = AduFields {
+            is_request: false,
+            exception: true,
+            exception_code: Some(2),
+            address: None,
+            quantity: None,
+            ..read_request()
+        };
+        let flow = flow();
+        assert!(matches("exception && src == 10.0.0.5", &exception, Some(&flow)));
+        assert!(matches("exception == 2 && port == 40000 && dport > 1024", &exception, Some(&flow)));
+        assert!(!matches("exception && host == 10.0.0.6", &exception, Some(&flow)));
+        /* フローのないシリアルバスのフレームでは src などは値なし */
+        assert!(!matches("src == 10.0.0.5", &exception, None));
+        assert!(matches("!src", &exception, None));
+        assert!(!matches("exception", &read_request(), Some(&flow)));
+        /* 例外コードのない (短すぎる) 例外応答も exception は真で、コードとの比較は偽 */
+        let truncated = AduFields {
+            exception_code: None,
+            ..exception.clone()
+        };
+        assert!(matches("exception", &truncated, None));
+        assert!(!matches("exception == 2", &truncated, None));
+        assert!(!matches("!exception", &truncated, None));
+        let zero = AduFields {
+            exception_code: Some(0),
+            ..exception.clone()
+        };
+        assert!(matches("exception && exception == 0", &zero, None));
+        assert!(matches("direction == request && tid == 1", &read_request(), None));
+    }
+}
@@ -0,0 +1,1271 @@
+//! tcpdump 形式のキャプチャフィルタ (--filter) を解析し、フレームごとに評価する。
+//! ライブキャプチャでもファイルの読み込みでもリンク層ヘッダを外した直後にソフトウェアで評価する。
+//! Linux のライブキャプチャでは Ethernet 用の classic BPF にも変換してソケットに取り付け、
+//! 一致しないフレームをカーネルで捨てる。VLAN タグ付きや IPv6 の拡張ヘッダ付きなど
+//! BPF で正確に判定しにくいフレームはカーネルでは通し、ソフトウェアの評価に任せる。
+//!
+//! 対応する書き方:
+//!   [src|dst] host <ADDR>, [src|dst] net <ADDR>/<LEN>, [src|dst] port <PORT>,
+//!   [src|dst] portrange <PORT>-<PORT>, ether [src|dst] host <MAC>, vlan [<ID>],
+//!   ip, ip6, arp, tcp, udp, icmp, icmp6 と、それらの and (&&), or (||), not (!), 括弧。
+//!   "tcp port 502" のようにプロトコルを前に付けると and でつないだものとして扱い、
+//!   "port 502 or 802" のように値だけを書くと直前の種類を繰り返す。
+//!   先頭以外の IP フラグメントにはポート番号がないので、port と portrange はどのポートにも一致させる。
+//!   "tcp port 502" なら TCP の後続のフラグメントがすべて通り、組み立ててからポートで判定する。
+
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::vlan::VlanPacket;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::convert::TryFrom;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::mem;
+use std::net::IpAddr;
+
+use super::link::Frame;
+use super::skip_ipv6_extensions;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Any,
+    Source,
+    Destination,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocol {
+    Ip,
+    Ip6,
+    Arp,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmp6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Host,
+    Net,
+    Port,
+    PortRange,
+    EtherHost,
+    Vlan,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Primitive {
+    Host(Direction, IpAddr),
+    Net(Direction, IpAddr, u8),
+    Port(Direction, u16, u16),
+    EtherHost(Direction, MacAddr),
+    Vlan(Option<u16>),
+    Protocol(Protocol),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Primitive(Primitive),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+/// フィルタの評価に使うフレームの要約
+#[derive(Default)]
+struct Summary {
+    vlans: Vec<u16>,
+    link_source: Option<MacAddr>,
+    link_destination: Option<MacAddr>,
+    arp: bool,
+    source: Option<IpAddr>,
+    destination: Option<IpAddr>,
+    protocol: Option<IpNextHeaderProtocol>,
+    source_port: Option<u16>,
+    destination_port: Option<u16>,
+    /// 先頭以外のフラグメント。ポート番号はないが、組み立てられるように port には一致させる
+    later_fragment: bool,
+}
+
+impl Summary {
+    fn new(frame: &Frame) -> Summary {
+        let mut summary = Summary {
+            link_source: frame.source,
+            link_destination: frame.destination,
+            ..Default::default()
+        };
+        let mut ethertype = frame.ethertype;
+        let mut payload = frame.payload;
+        while let EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ = ethertype {
+            match VlanPacket::new(payload) {
+                Some(vlan) => {
+                    summary.vlans.push(vlan.get_vlan_identifier());
+                    ethertype = vlan.get_ethertype();
+                    payload = &payload[VlanPacket::minimum_packet_size()..];
+                }
+                None => return summary,
+            }
+        }
+        let transport = match ethertype {
+            EtherTypes::Arp => {
+                summary.arp = true;
+                None
+            }
+            EtherTypes::Ipv4 => Ipv4Packet::new(payload).map(|header| {
+                summary.source = Some(IpAddr::V4(header.get_source()));
+                summary.destination = Some(IpAddr::V4(header.get_destination()));
+                summary.protocol = Some(header.get_next_level_protocol());
+                /* 先頭以外のフラグメントにはポート番号がない */
+                if header.get_fragment_offset() == 0 {
+                    header.payload().to_vec()
+                } else {
+                    summary.later_fragment = true;
+                    Vec::new()
+                }
+            }),
+            EtherTypes::Ipv6 => Ipv6Packet::new(payload).map(|header| {
+                summary.source = Some(IpAddr::V6(header.get_source()));
+                summary.destination = Some(IpAddr::V6(header.get_destination()));
+                match skip_ipv6_extensions(header.get_next_header(), header.payload()) {
+                    /* Fragment ヘッダ (8 バイト) の次のヘッダを上位プロトコルとする */
+                    Some((IpNextHeaderProtocols::Ipv6Frag, fragment)) if fragment.len() >= 8 => {
+                        summary.protocol = Some(IpNextHeaderProtocol(fragment[0]));
+                        if u16::from_be_bytes([fragment[2], fragment[3]]) >> 3 == 0 {
+                            fragment[8..].to_vec()
+                        } else {
+                            summary.later_fragment = true;
+                            Vec::new()
+                        }
+                    }
+                    Some((protocol, payload)) => {
+                        summary.protocol = Some(protocol);
+                        payload.to_vec()
+                    }
+                    None => Vec::new(),
+                }
+            }),
+            _ => None,
+        };
+        if let (Some(protocol), Some(transport)) = (summary.protocol, transport) {
+            if (protocol == IpNextHeaderProtocols::Tcp || protocol == IpNextHeaderProtocols::Udp)
+                && transport.len() >= 4
+            {
+                summary.source_port = Some(u16::from_be_bytes([transport[0], transport[1]]));
+                summary.destination_port = Some(u16::from_be_bytes([transport[2], transport[3]]));
+            }
+        }
+        summary
+    }
+}
+
+/// 向きの指定に合わせて送信元・送信先のどちらか (または両方) が条件を満たすか
+fn either<T: Copy>(direction: Direction, source: Option<T>, destination: Option<T>, f: impl Fn(T) -> bool) -> bool {
+    let source = source.is_some_and(&f);
+    let destination = destination.is_some_and(&f);
+    match direction {
+        Direction::Any => source || destination,
+        Direction::Source => source,
+        Direction::Destination => destination,
+    }
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix as u32) };
+            u32::from(address) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix as u32) };
+            u128::from(address) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+impl Primitive {
+    fn matches(&self, summary: &Summary) -> bool {
+        match self {
+            Primitive::Host(direction, host) => {
+                either(*direction, summary.source, summary.destination, |a| a == *host)
+            }
+            Primitive::Net(direction, network, prefix) => {
+                either(*direction, summary.source, summary.destination, |a| {
+                    in_network(a, *network, *prefix)
+                })
+            }
+            Primitive::Port(direction, low, high) => {
+                summary.later_fragment
+                    || either(
+                        *direction,
+                        summary.source_port,
+                        summary.destination_port,
+                        |p| *low <= p && p <= *high,
+                    )
+            }
+            Primitive::EtherHost(direction, mac) => either(
+                *direction,
+                summary.link_source,
+                summary.link_destination,
+                |m| m == *mac,
+            ),
+            Primitive::Vlan(id) => match id {
+                Some(id) => summary.vlans.contains(id),
+                None => !summary.vlans.is_empty(),
+            },
+            Primitive::Protocol(protocol) => match protocol {
+                Protocol::Ip => matches!(summary.source, Some(IpAddr::V4(..))),
+                Protocol::Ip6 => matches!(summary.source, Some(IpAddr::V6(..))),
+                Protocol::Arp => summary.arp,
+                Protocol::Tcp => summary.protocol == Some(IpNextHeaderProtocols::Tcp),
+                Protocol::Udp => summary.protocol == Some(IpNextHeaderProtocols::Udp),
+                Protocol::Icmp => summary.protocol == Some(IpNextHeaderProtocols::Icmp),
+                Protocol::Icmp6 => summary.protocol == Some(IpNextHeaderProtocols::Icmpv6),
+            },
+        }
+    }
+}
+
+impl Expression {
+    fn matches(&self, summary: &Summary) -> bool {
+        match self {
+            Expression::Primitive(primitive) => primitive.matches(summary),
+            Expression::Not(e) => !e.matches(summary),
+            Expression::And(a, b) => a.matches(summary) && b.matches(summary),
+            Expression::Or(a, b) => a.matches(summary) || b.matches(summary),
+        }
+    }
+}
+
+/// 括弧と ! は空白で区切られていなくても 1 つの字句にする
+fn tokenize(expression: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in expression.chars() {
+        if c.is_whitespace() || c == '(' || c == ')' || (c == '!' && current.is_empty()) {
+            if !current.is_empty() {
+                tokens.push(current.clone());
+                current.clear();
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn parse_mac(text: &str) -> Option<MacAddr> {
+    let bytes: Vec<u8> = text
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if bytes.len() != 6 {
+        return None;
+    }
+    Some(MacAddr(bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]))
+}
+
+fn parse_port(text: &str) -> Result<u16, String> {
+    text.parse().map_err(|_| format!("invalid port: {}", text))
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+    /// 値だけが書かれたときに繰り返す直前の (向き, 種類)
+    last: Option<(Direction, Kind)>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|t| &t[..])
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "unexpected end of expression".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.and()?;
+        while let Some("or") | Some("||") = self.peek() {
+            self.position += 1;
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.unary()?;
+        while let Some("and") | Some("&&") = self.peek() {
+            self.position += 1;
+            expression = Expression::And(Box::new(expression), Box::new(self.unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.peek() {
+            Some("not") | Some("!") => {
+                self.position += 1;
+                Ok(Expression::Not(Box::new(self.unary()?)))
+            }
+            Some("(") => {
+                self.position += 1;
+                let expression = self.or()?;
+                match self.next()?.as_str() {
+                    ")" => Ok(expression),
+                    token => Err(format!("expected ')' but found '{}'", token)),
+                }
+            }
+            _ => self.primitive(),
+        }
+    }
+
+    fn direction(&mut self) -> Direction {
+        match self.peek() {
+            Some("src") => {
+                self.position += 1;
+                Direction::Source
+            }
+            Some("dst") => {
+                self.position += 1;
+                Direction::Destination
+            }
+            _ => Direction::Any,
+        }
+    }
+
+    fn primitive(&mut self) -> Result<Expression, String> {
+        let token = self.next()?;
+        let protocol = match token.as_str() {
+            "ip" => Some(Protocol::Ip),
+            "ip6" => Some(Protocol::Ip6),
+            "arp" => Some(Protocol::Arp),
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            "icmp" => Some(Protocol::Icmp),
+            "icmp6" => Some(Protocol::Icmp6),
+            _ => None,
+        };
+        if let Some(protocol) = protocol {
+            let protocol = Expression::Primitive(Primitive::Protocol(protocol));
+            /* "tcp port 502" のようにプロトコルの後ろに条件が続く */
+            return match self.peek() {
+                Some("src") | Some("dst") | Some("host") | Some("net") | Some("port")
+                | Some("portrange") => Ok(Expression::And(
+                    Box::new(protocol),
+                    Box::new(self.primitive()?),
+                )),
+                _ => Ok(protocol),
+            };
+        }
+        self.position -= 1;
+        let ether = if self.peek() == Some("ether") {
+            self.position += 1;
+            true
+        } else {
+            false
+        };
+        let direction = self.direction();
+        let kind = match self.peek() {
+            Some("host") if ether => Some(Kind::EtherHost),
+            Some("host") => Some(Kind::Host),
+            Some("net") => Some(Kind::Net),
+            Some("port") => Some(Kind::Port),
+            Some("portrange") => Some(Kind::PortRange),
+            Some("vlan") => Some(Kind::Vlan),
+            _ => None,
+        };
+        let (direction, kind) = match kind {
+            Some(kind) => {
+                self.position += 1;
+                (direction, kind)
+            }
+            None if direction == Direction::Any && !ether => match self.last {
+                Some(last) => last,
+                None => return Err(format!("unknown primitive: {}", self.next()?)),
+            },
+            /* "src 10.0.0.1" は host を省略したもの */
+            None if ether => (direction, Kind::EtherHost),
+            None => (direction, Kind::Host),
+        };
+        self.last = Some((direction, kind));
+        let primitive = match kind {
+            Kind::Vlan => match self.peek().map(|t| t.parse::<u16>()) {
+                Some(Ok(id)) => {
+                    self.position += 1;
+                    Primitive::Vlan(Some(id))
+                }
+                _ => Primitive::Vlan(None),
+            },
+            Kind::Host => {
+                let value = self.next()?;
+                let host = value
+                    .parse()
+                    .map_err(|_| format!("invalid host: {}", value))?;
+                Primitive::Host(direction, host)
+            }
+            Kind::Net => {
+                let value = self.next()?;
+                let (network, prefix) = match value.find('/') {
+                    Some(i) => (&value[..i], Some(&value[i + 1..])),
+                    None => (&value[..], None),
+                };
+                let network: IpAddr = network
+                    .parse()
+                    .map_err(|_| format!("invalid network: {}", value))?;
+                let max = if network.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse()
+                        .ok()
+                        .filter(|p| *p <= max)
+                        .ok_or_else(|| format!("invalid network: {}", value))?,
+                    None => max,
+                };
+                Primitive::Net(direction, network, prefix)
+            }
+            Kind::Port => {
+                let port = parse_port(&self.next()?)?;
+                Primitive::Port(direction, port, port)
+            }
+            Kind::PortRange => {
+                let value = self.next()?;
+                let mut range = value.splitn(2, '-');
+                let low = parse_port(range.next().unwrap_or(""))?;
+                let high = parse_port(range.next().unwrap_or(""))?;
+                Primitive::Port(direction, low, high)
+            }
+            Kind::EtherHost => {
+                let value = self.next()?;
+                let mac = parse_mac(&value).ok_or_else(|| format!("invalid MAC address: {}", value))?;
+                Primitive::EtherHost(direction, mac)
+            }
+        };
+        Ok(Expression::Primitive(primitive))
+    }
+}
+
+/* classic BPF の命令 (linux/filter.h) */
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+const BPF_ADD: u16 = 0x00;
+const BPF_AND: u16 = 0x50;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// カーネルが受け付ける命令数の上限 (BPF_MAXINSNS)
+const MAX_INSTRUCTIONS: usize = 4096;
+/// 補助データ SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT: カーネルが外した VLAN タグがあれば 1
+const VLAN_TAG_PRESENT: u32 = (-0x1000i32 + 48) as u32;
+/// 一致したフレームを切り詰めずに受け取る長さ
+const ACCEPT_LENGTH: u32 = 0x40000;
+
+/// Ethernet フレームでの位置
+const ETHERTYPE_OFFSET: u32 = 12;
+const IPV4_OFFSET: u32 = 14;
+const IPV6_OFFSET: u32 = 14;
+
+/// classic BPF の 1 命令 (struct sock_filter と同じ並び)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// 比べる値の読み込み方 (大きさは BPF_W, BPF_H, BPF_B)
+#[derive(Clone, Copy)]
+enum Load {
+    /// フレームの先頭からの位置
+    Absolute(u16, u32),
+    /// IPv4 ヘッダの直後からの位置
+    Transport(u16, u32),
+    /// 読んだ値と mask の論理積
+    Masked(u16, u32, u32),
+    /// フレームの長さ
+    Length,
+}
+
+/// 読み込んだ値と比べる相手
+#[derive(Clone, Copy)]
+enum Operand {
+    Constant(u32),
+    /// IPv4 のヘッダ長にこの値を足したもの
+    Ipv4Header(u32),
+}
+
+/// BPF の命令列にする前の条件。空の All は常に真、空の Any は常に偽
+enum Condition {
+    /// (読み込み, BPF_JEQ などの比較, 相手)
+    Test(Load, u16, Operand),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+fn equal(load: Load, value: u32) -> Condition {
+    Condition::Test(load, BPF_JEQ, Operand::Constant(value))
+}
+
+fn ethertype_is(ethertype: u16) -> Condition {
+    equal(Load::Absolute(BPF_H, ETHERTYPE_OFFSET), ethertype as u32)
+}
+
+fn ipv4_protocol_is(protocol: u8) -> Condition {
+    equal(Load::Absolute(BPF_B, IPV4_OFFSET + 9), protocol as u32)
+}
+
+fn ipv6_next_header_is(protocol: u8) -> Condition {
+    equal(Load::Absolute(BPF_B, IPV6_OFFSET + 6), protocol as u32)
+}
+
+/// Summary::protocol と同じく IPv4 と IPv6 のどちらでも上位プロトコルを見る
+fn protocol_is(protocol: IpNextHeaderProtocol) -> Condition {
+    Condition::Any(vec![
+        Condition::All(vec![
+            ethertype_is(EtherTypes::Ipv4.0),
+            ipv4_protocol_is(protocol.0),
+        ]),
+        Condition::All(vec![
+            ethertype_is(EtherTypes::Ipv6.0),
+            ipv6_next_header_is(protocol.0),
+        ]),
+    ])
+}
+
+fn either_condition(direction: Direction, source: Condition, destination: Condition) -> Condition {
+    match direction {
+        Direction::Any => Condition::Any(vec![source, destination]),
+        Direction::Source => source,
+        Direction::Destination => destination,
+    }
+}
+
+/// offset から始まるアドレスの先頭 prefix ビットが network と一致する
+fn address_condition(offset: u32, network: &[u8], prefix: u8) -> Condition {
+    let mut words = Vec::new();
+    for (i, chunk) in network.chunks(4).enumerate() {
+        let bits = (prefix as i32 - 32 * i as i32).clamp(0, 32) as u32;
+        if bits == 0 {
+            break;
+        }
+        let value = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        let offset = offset + 4 * i as u32;
+        words.push(if bits == 32 {
+            equal(Load::Absolute(BPF_W, offset), value)
+        } else {
+            let mask = u32::MAX << (32 - bits);
+            equal(Load::Masked(BPF_W, offset, mask), value & mask)
+        });
+    }
+    Condition::All(words)
+}
+
+fn network_condition(direction: Direction, network: IpAddr, prefix: u8) -> Condition {
+    match network {
+        IpAddr::V4(network) => Condition::All(vec![
+            ethertype_is(EtherTypes::Ipv4.0),
+            either_condition(
+                direction,
+                address_condition(IPV4_OFFSET + 12, &network.octets(), prefix),
+                address_condition(IPV4_OFFSET + 16, &network.octets(), prefix),
+            ),
+        ]),
+        IpAddr::V6(network) => Condition::All(vec![
+            ethertype_is(EtherTypes::Ipv6.0),
+            either_condition(
+                direction,
+                address_condition(IPV6_OFFSET + 8, &network.octets(), prefix),
+                address_condition(IPV6_OFFSET + 24, &network.octets(), prefix),
+            ),
+        ]),
+    }
+}
+
+fn port_range(load: Load, low: u16, high: u16) -> Condition {
+    if low == high {
+        return equal(load, low as u32);
+    }
+    Condition::All(vec![
+        Condition::Test(load, BPF_JGE, Operand::Constant(low as u32)),
+        Condition::Not(Box::new(Condition::Test(
+            load,
+            BPF_JGT,
+            Operand::Constant(high as u32),
+        ))),
+    ])
+}
+
+fn port_condition(direction: Direction, low: u16, high: u16) -> Condition {
+    let tcp = IpNextHeaderProtocols::Tcp.0;
+    let udp = IpNextHeaderProtocols::Udp.0;
+    let ipv4 = Condition::All(vec![
+        ethertype_is(EtherTypes::Ipv4.0),
+        Condition::Any(vec![ipv4_protocol_is(tcp), ipv4_protocol_is(udp)]),
+        /* 先頭以外のフラグメントにはポート番号がないので、組み立てられるように通す */
+        Condition::Any(vec![
+            Condition::Test(
+                Load::Absolute(BPF_H, IPV4_OFFSET + 6),
+                BPF_JSET,
+                Operand::Constant(0x1fff),
+            ),
+            either_condition(
+                direction,
+                port_range(Load::Transport(BPF_H, 0), low, high),
+                port_range(Load::Transport(BPF_H, 2), low, high),
+            ),
+        ]),
+    ]);
+    let ipv6 = Condition::All(vec![
+        ethertype_is(EtherTypes::Ipv6.0),
+        Condition::Any(vec![ipv6_next_header_is(tcp), ipv6_next_header_is(udp)]),
+        either_condition(
+            direction,
+            port_range(Load::Absolute(BPF_H, IPV6_OFFSET + 40), low, high),
+            port_range(Load::Absolute(BPF_H, IPV6_OFFSET + 42), low, high),
+        ),
+    ]);
+    Condition::Any(vec![ipv4, ipv6])
+}
+
+fn mac_condition(offset: u32, mac: MacAddr) -> Condition {
+    Condition::All(vec![
+        equal(
+            Load::Absolute(BPF_H, offset),
+            u16::from_be_bytes([mac.0, mac.1]) as u32,
+        ),
+        equal(
+            Load::Absolute(BPF_W, offset + 2),
+            u32::from_be_bytes([mac.2, mac.3, mac.4, mac.5]),
+        ),
+    ])
+}
+
+/// BPF で正確に判定しにくく、カーネルでは通してソフトウェアに任せるフレーム。
+/// VLAN タグ付き、ヘッダが欠けた IPv4, TCP/UDP/ICMPv6 以外 (拡張ヘッダなど) が続く IPv6
+fn deferred() -> Condition {
+    let not = |condition| Condition::Not(Box::new(condition));
+    let ipv6_protocols = [
+        IpNextHeaderProtocols::Tcp,
+        IpNextHeaderProtocols::Udp,
+        IpNextHeaderProtocols::Icmpv6,
+    ];
+    Condition::Any(vec![
+        not(equal(Load::Absolute(BPF_W, VLAN_TAG_PRESENT), 0)),
+        ethertype_is(EtherTypes::Vlan.0),
+        ethertype_is(EtherTypes::PBridge.0),
+        ethertype_is(EtherTypes::QinQ.0),
+        Condition::All(vec![
+            ethertype_is(EtherTypes::Ipv4.0),
+            Condition::Any(vec![
+                not(Condition::Test(
+                    Load::Masked(BPF_B, IPV4_OFFSET, 0x0f),
+                    BPF_JGE,
+                    Operand::Constant(5),
+                )),
+                /* ポート番号まで読めるだけの長さがあり、全長もそれを含む */
+                not(Condition::Test(
+                    Load::Length,
+                    BPF_JGE,
+                    Operand::Ipv4Header(IPV4_OFFSET + 4),
+                )),
+                not(Condition::Test(
+                    Load::Absolute(BPF_H, IPV4_OFFSET + 2),
+                    BPF_JGE,
+                    Operand::Ipv4Header(4),
+                )),
+            ]),
+        ]),
+        Condition::All(vec![
+            ethertype_is(EtherTypes::Ipv6.0),
+            Condition::Any(vec![
+                not(Condition::Any(
+                    ipv6_protocols
+                        .iter()
+                        .map(|protocol| ipv6_next_header_is(protocol.0))
+                        .collect(),
+                )),
+                not(Condition::Test(
+                    Load::Length,
+                    BPF_JGE,
+                    Operand::Constant(IPV6_OFFSET + 44),
+                )),
+                not(Condition::Test(
+                    Load::Absolute(BPF_H, IPV6_OFFSET + 4),
+                    BPF_JGE,
+                    Operand::Constant(4),
+                )),
+            ]),
+        ]),
+    ])
+}
+
+impl Primitive {
+    fn condition(&self) -> Condition {
+        match self {
+            Primitive::Host(direction, host) => {
+                let prefix = if host.is_ipv4() { 32 } else { 128 };
+                network_condition(*direction, *host, prefix)
+            }
+            Primitive::Net(direction, network, prefix) => {
+                network_condition(*direction, *network, *prefix)
+            }
+            Primitive::Port(direction, low, high) => port_condition(*direction, *low, *high),
+            Primitive::EtherHost(direction, mac) => {
+                either_condition(*direction, mac_condition(6, *mac), mac_condition(0, *mac))
+            }
+            /* タグ付きのフレームはすべて deferred() で通すので、ここに来るのはタグなし */
+            Primitive::Vlan(_) => Condition::Any(Vec::new()),
+            Primitive::Protocol(protocol) => match protocol {
+                Protocol::Ip => ethertype_is(EtherTypes::Ipv4.0),
+                Protocol::Ip6 => ethertype_is(EtherTypes::Ipv6.0),
+                Protocol::Arp => ethertype_is(EtherTypes::Arp.0),
+                Protocol::Tcp => protocol_is(IpNextHeaderProtocols::Tcp),
+                Protocol::Udp => protocol_is(IpNextHeaderProtocols::Udp),
+                Protocol::Icmp => protocol_is(IpNextHeaderProtocols::Icmp),
+                Protocol::Icmp6 => protocol_is(IpNextHeaderProtocols::Icmpv6),
+            },
+        }
+    }
+}
+
+impl Expression {
+    fn condition(&self) -> Condition {
+        match self {
+            Expression::Primitive(primitive) => primitive.condition(),
+            Expression::Not(e) => Condition::Not(Box::new(e.condition())),
+            Expression::And(a, b) => Condition::All(vec![a.condition(), b.condition()]),
+            Expression::Or(a, b) => Condition::Any(vec![a.condition(), b.condition()]),
+        }
+    }
+}
+
+/// 飛び先をラベルのままにした命令
+enum Op {
+    Instruction(u16, u32),
+    /// (比較の命令, 一致したときのラベル, しなかったときのラベル)
+    Jump(u16, u32, usize, usize),
+    Goto(usize),
+    Label(usize),
+}
+
+/// 条件を前方への分岐だけの命令列にする
+#[derive(Default)]
+struct Generator {
+    ops: Vec<Op>,
+    labels: usize,
+}
+
+impl Generator {
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn emit(&mut self, code: u16, k: u32) {
+        self.ops.push(Op::Instruction(code, k));
+    }
+
+    /// 条件が真なら if_true へ、偽なら if_false へ飛ぶ
+    fn condition(&mut self, condition: &Condition, if_true: usize, if_false: usize) {
+        match condition {
+            Condition::Test(load, jump, operand) => {
+                let source = match operand {
+                    Operand::Constant(k) => (BPF_K, *k),
+                    Operand::Ipv4Header(n) => {
+                        self.emit(BPF_LDX | BPF_B | BPF_MSH, IPV4_OFFSET);
+                        self.emit(BPF_MISC | BPF_TXA, 0);
+                        self.emit(BPF_ALU | BPF_ADD | BPF_K, *n);
+                        self.emit(BPF_MISC | BPF_TAX, 0);
+                        (BPF_X, 0)
+                    }
+                };
+                match load {
+                    Load::Absolute(size, offset) => self.emit(BPF_LD | size | BPF_ABS, *offset),
+                    Load::Transport(size, offset) => {
+                        self.emit(BPF_LDX | BPF_B | BPF_MSH, IPV4_OFFSET);
+                        self.emit(BPF_LD | size | BPF_IND, IPV4_OFFSET + offset);
+                    }
+                    Load::Masked(size, offset, mask) => {
+                        self.emit(BPF_LD | size | BPF_ABS, *offset);
+                        self.emit(BPF_ALU | BPF_AND | BPF_K, *mask);
+                    }
+                    Load::Length => self.emit(BPF_LD | BPF_W | BPF_LEN, 0),
+                }
+                self.ops
+                    .push(Op::Jump(BPF_JMP | jump | source.0, source.1, if_true, if_false));
+            }
+            Condition::All(conditions) | Condition::Any(conditions) if conditions.is_empty() => {
+                let all = matches!(condition, Condition::All(_));
+                self.ops.push(Op::Goto(if all { if_true } else { if_false }));
+            }
+            Condition::All(conditions) => {
+                for (i, c) in conditions.iter().enumerate() {
+                    if i + 1 == conditions.len() {
+                        self.condition(c, if_true, if_false);
+                    } else {
+                        let next = self.label();
+                        self.condition(c, next, if_false);
+                        self.ops.push(Op::Label(next));
+                    }
+                }
+            }
+            Condition::Any(conditions) => {
+                for (i, c) in conditions.iter().enumerate() {
+                    if i + 1 == conditions.len() {
+                        self.condition(c, if_true, if_false);
+                    } else {
+                        let next = self.label();
+                        self.condition(c, if_true, next);
+                        self.ops.push(Op::Label(next));
+                    }
+                }
+            }
+            Condition::Not(c) => self.condition(c, if_false, if_true),
+        }
+    }
+
+    /// ラベルを相対的な飛び先にする。条件分岐が 255 命令より先に飛ぶなら None
+    fn finish(self) -> Option<Vec<Instruction>> {
+        let mut positions = vec![0; self.labels];
+        let mut position = 0;
+        for op in &self.ops {
+            match op {
+                Op::Label(label) => positions[*label] = position,
+                _ => position += 1,
+            }
+        }
+        if position > MAX_INSTRUCTIONS {
+            return None;
+        }
+        let mut program = Vec::with_capacity(position);
+        for op in &self.ops {
+            let next = program.len() + 1;
+            let instruction = match *op {
+                Op::Instruction(code, k) => Instruction { code, jt: 0, jf: 0, k },
+                Op::Jump(code, k, if_true, if_false) => Instruction {
+                    code,
+                    jt: u8::try_from(positions[if_true] - next).ok()?,
+                    jf: u8::try_from(positions[if_false] - next).ok()?,
+                    k,
+                },
+                Op::Goto(label) => Instruction {
+                    code: BPF_JMP | BPF_JA,
+                    jt: 0,
+                    jf: 0,
+                    k: (positions[label] - next) as u32,
+                },
+                Op::Label(_) => continue,
+            };
+            program.push(instruction);
+        }
+        Some(program)
+    }
+}
+
+/// ライブキャプチャのソケットにフィルタを取り付ける。pnet のチャネルはソケットを公開していないので、
+/// 開いているファイルからインタフェースに bind された AF_PACKET のソケットを探す
+#[cfg(target_os = "linux")]
+pub fn attach(program: &[Instruction], interface_index: u32) -> io::Result<()> {
+    let mut attached = false;
+    for entry in fs::read_dir("/proc/self/fd")? {
+        let fd: libc::c_int = match entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        let mut length = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockname(
+                fd,
+                &mut address as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                &mut length,
+            )
+        };
+        if result != 0
+            || address.sll_family as libc::c_int != libc::AF_PACKET
+            || address.sll_ifindex as u32 != interface_index
+        {
+            continue;
+        }
+        let program = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_FILTER,
+                &program as *const libc::sock_fprog as *const libc::c_void,
+                mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        attached = true;
+    }
+    if attached {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::NotFound, "capture socket not found"))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn attach(_program: &[Instruction], _interface_index: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "not supported on this platform",
+    ))
+}
+
+pub struct Filter {
+    expression: Expression,
+}
+
+impl Filter {
+    /// フィルタ式を解析する。解析できなければ理由を返す
+    pub fn parse(expression: &str) -> Result<Filter, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expression),
+            position: 0,
+            last: None,
+        };
+        let expression = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected '{}'", token));
+        }
+        Ok(Filter { expression })
+    }
+
+    pub fn matches(&self, frame: &Frame) -> bool {
+        self.expression.matches(&Summary::new(frame))
+    }
+
+    /// Ethernet フレーム用の classic BPF に変換する。
+    /// 一致するフレームはすべて通すが、通したフレームがすべて一致するとは限らない。
+    /// 長すぎて変換できなければ None
+    pub fn compile(&self) -> Option<Vec<Instruction>> {
+        let mut generator = Generator::default();
+        let accept = generator.label();
+        let reject = generator.label();
+        let condition = Condition::Any(vec![deferred(), self.expression.condition()]);
+        generator.condition(&condition, accept, reject);
+        generator.ops.push(Op::Label(accept));
+        generator.emit(BPF_RET | BPF_K, ACCEPT_LENGTH);
+        generator.ops.push(Op::Label(reject));
+        generator.emit(BPF_RET | BPF_K, 0);
+        generator.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::{self, LinkType};
+
+    fn primitive(primitive: Primitive) -> Box<Expression> {
+        Box::new(Expression::Primitive(primitive))
+    }
+
+    fn port(port: u16) -> Box<Expression> {
+        primitive(Primitive::Port(Direction::Any, port, port))
+    }
+
+    #[test]
+    fn parse() {
+        let parsed = |expression| Filter::parse(expression).unwrap().expression;
+        assert_eq!(
+            parsed("tcp port 502"),
+            Expression::And(primitive(Primitive::Protocol(Protocol::Tcp)), port(502))
+        );
+        /* 値だけを書くと直前の種類を繰り返す */
+        assert_eq!(parsed("port 502 or 802"), Expression::Or(port(502), port(802)));
+        assert_eq!(
+            parsed("src net 10.0.0.0/8 and !(arp || vlan 10)"),
+            Expression::And(
+                primitive(Primitive::Net(
+                    Direction::Source,
+                    "10.0.0.0".parse().unwrap(),
+                    8
+                )),
+                Box::new(Expression::Not(Box::new(Expression::Or(
+                    primitive(Primitive::Protocol(Protocol::Arp)),
+                    primitive(Primitive::Vlan(Some(10))),
+                ))))
+            )
+        );
+        assert_eq!(
+            parsed("ether dst host 00:11:22:33:44:55"),
+            *primitive(Primitive::EtherHost(
+                Direction::Destination,
+                MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55)
+            ))
+        );
+        assert_eq!(
+            parsed("dst 2001:db8::1"),
+            *primitive(Primitive::Host(
+                Direction::Destination,
+                "2001:db8::1".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            parsed("portrange 500-502"),
+            *primitive(Primitive::Port(Direction::Any, 500, 502))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |expression| Filter::parse(expression).err().unwrap();
+        assert_eq!(error("port"), "unexpected end of expression");
+        assert_eq!(error("port http"), "invalid port: http");
+        assert_eq!(error("host plc1"), "invalid host: plc1");
+        assert_eq!(error("net 10.0.0.0/33"), "invalid network: 10.0.0.0/33");
+        assert_eq!(error("(tcp"), "unexpected end of expression");
+        assert_eq!(error("tcp tcp"), "unexpected 'tcp'");
+        assert_eq!(error("modbus"), "unknown primitive: modbus");
+    }
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x00, 0x66, 0x77, 0x88, 0x99, 0xaa];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn ipv4(protocol: u8, fragment_offset: u16, source: [u8; 4], destination: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 1]);
+        packet.extend_from_slice(&fragment_offset.to_be_bytes());
+        packet.extend_from_slice(&[64, protocol, 0, 0]);
+        packet.extend_from_slice(&source);
+        packet.extend_from_slice(&destination);
+        packet.extend_from_slice(payload);
+        ethernet(0x0800, &packet)
+    }
+
+    fn ipv6(next_header: u8, source: u16, destination: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, 64]);
+        for last in [source, destination].iter() {
+            packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+            packet.extend_from_slice(&[0; 10]);
+            packet.extend_from_slice(&last.to_be_bytes());
+        }
+        packet.extend_from_slice(payload);
+        ethernet(0x86dd, &packet)
+    }
+
+    fn ports(source: u16, destination: u16) -> Vec<u8> {
+        let mut header = source.to_be_bytes().to_vec();
+        header.extend_from_slice(&destination.to_be_bytes());
+        header.extend_from_slice(&[0; 16]);
+        header
+    }
+
+    /// テストのための classic BPF の実行 (カーネルが外した VLAN タグはないものとする)
+    fn run(program: &[Instruction], frame: &[u8]) -> bool {
+        let load = |offset: u32, size: u16| -> Option<u32> {
+            if offset == VLAN_TAG_PRESENT {
+                return Some(0);
+            }
+            let length = match size {
+                BPF_W => 4,
+                BPF_H => 2,
+                _ => 1,
+            };
+            let bytes = frame.get(offset as usize..offset as usize + length)?;
+            Some(bytes.iter().fold(0, |value, b| value << 8 | *b as u32))
+        };
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            let code = instruction.code;
+            match code & 0x07 {
+                BPF_LD => {
+                    let value = match code & 0xe0 {
+                        BPF_ABS => load(instruction.k, code & 0x18),
+                        BPF_IND => load(x.wrapping_add(instruction.k), code & 0x18),
+                        BPF_LEN => Some(frame.len() as u32),
+                        _ => panic!("unexpected load {:#x}", code),
+                    };
+                    /* 範囲外の読み込みはフレームを捨てる */
+                    match value {
+                        Some(value) => a = value,
+                        None => return false,
+                    }
+                }
+                BPF_LDX => match frame.get(instruction.k as usize) {
+                    Some(b) => x = 4 * (b & 0x0f) as u32,
+                    None => return false,
+                },
+                BPF_ALU => match code & 0xf0 {
+                    BPF_ADD => a = a.wrapping_add(instruction.k),
+                    BPF_AND => a &= instruction.k,
+                    _ => panic!("unexpected operation {:#x}", code),
+                },
+                BPF_JMP => {
+                    let operand = if code & BPF_X != 0 { x } else { instruction.k };
+                    let taken = match code & 0xf0 {
+                        BPF_JA => {
+                            pc += instruction.k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == operand,
+                        BPF_JGT => a > operand,
+                        BPF_JGE => a >= operand,
+                        BPF_JSET => a & operand != 0,
+                        _ => panic!("unexpected jump {:#x}", code),
+                    };
+                    pc += if taken { instruction.jt } else { instruction.jf } as usize;
+                }
+                BPF_RET => return instruction.k != 0,
+                BPF_MISC if code & BPF_TXA != 0 => a = x,
+                BPF_MISC => x = a,
+                _ => panic!("unexpected class {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn compiled_programs() {
+        let client = [192, 168, 1, 10];
+        let server = [192, 168, 1, 20];
+        let mut vlan = vec![0x00, 0x0a, 0x08, 0x00];
+        vlan.extend_from_slice(&ipv4(6, 0, client, server, &ports(40000, 502))[14..]);
+        /* (フレーム, BPF でも正確に判定できるか) */
+        let frames = vec![
+            (ipv4(6, 0, client, server, &ports(40000, 502)), true),
+            (ipv4(17, 0, server, client, &ports(502, 40000)), true),
+            (ipv4(6, 0, client, [10, 1, 2, 3], &ports(40000, 802)), true),
+            (ipv4(1, 0, client, server, &[8, 0, 0, 0, 0, 1, 0, 1]), true),
+            /* 先頭以外のフラグメントにはポート番号がない */
+            (ipv4(6, 100, client, server, &ports(40000, 502)), true),
+            (ipv4(17, 0x2000 | 100, client, server, &[0; 8]), true),
+            (ipv6(6, 1, 2, &ports(40000, 502)), true),
+            (ipv6(58, 2, 1, &[128, 0, 0, 0, 0, 1, 0, 1]), true),
+            (ethernet(0x0806, &[0; 28]), true),
+            (ethernet(0x88cc, &[0; 32]), true),
+            /* VLAN タグ付き, 拡張ヘッダ付き, ヘッダが欠けたものはソフトウェアに任せる */
+            (ethernet(0x8100, &vlan), false),
+            (ipv6(0, 1, 2, &[6, 0, 1, 4, 0, 0, 0, 0]), false),
+            (ipv4(6, 0, client, server, &[0x9c]), false),
+            (ethernet(0x0800, &[0x45, 0, 0, 20]), false),
+        ];
+        let expressions = [
+            "tcp port 502",
+            "port 502 or 802",
+            "not port 502",
+            "udp",
+            "icmp or icmp6",
+            "ip and not tcp",
+            "ip6",
+            "arp",
+            "vlan",
+            "not vlan 10",
+            "src host 192.168.1.10",
+            "dst host 192.168.1.10",
+            "net 10.0.0.0/8",
+            "host 2001:db8::1",
+            "src net 2001:db8::/120 and dst port 502",
+            "src portrange 30000-50000",
+            "ether src host 00:66:77:88:99:aa",
+            "ether dst 00:11:22:33:44:55 and not ip6",
+            "!(tcp or udp) and !arp",
+        ];
+        for expression in expressions.iter() {
+            let filter = Filter::parse(expression).unwrap();
+            let program = filter.compile().unwrap();
+            for (i, (data, exact)) in frames.iter().enumerate() {
+                let frame = link::decode(LinkType::Ethernet, data).unwrap();
+                let expected = filter.matches(&frame);
+                let accepted = run(&program, data);
+                if *exact {
+                    assert_eq!(accepted, expected, "'{}' on frame {}", expression, i);
+                } else {
+                    assert!(accepted, "'{}' drops deferred frame {}", expression, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fragments() {
+        let matches = |expression, data: &[u8]| {
+            let frame = link::decode(LinkType::Ethernet, data).unwrap();
+            Filter::parse(expression).unwrap().matches(&frame)
+        };
+        let (client, server) = ([192, 168, 1, 10], [192, 168, 1, 20]);
+        /* 先頭のフラグメント (More Fragments) はポート番号で、後続はプロトコルだけで判定する */
+        let first = ipv4(6, 0x2000, client, server, &ports(40000, 502));
+        let last = ipv4(6, 3, client, server, &[0; 8]);
+        assert!(matches("tcp port 502", &first));
+        assert!(!matches("tcp port 503", &first));
+        assert!(matches("tcp port 502", &last));
+        assert!(matches("dst port 503", &last));
+        assert!(!matches("udp port 502", &last));
+        /* IPv6 では Fragment ヘッダの次のヘッダがプロトコル */
+        let fragment = |offset: u16, payload: &[u8]| {
+            let mut header = vec![6, 0];
+            header.extend_from_slice(&(offset << 3 | 1).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0, 7]);
+            header.extend_from_slice(payload);
+            ipv6(44, 1, 2, &header)
+        };
+        assert!(matches("tcp port 502", &fragment(0, &ports(40000, 502))));
+        assert!(!matches("tcp port 503", &fragment(0, &ports(40000, 502))));
+        assert!(matches("tcp port 502", &fragment(2, &[0; 8])));
+        assert!(!matches("udp", &fragment(2, &[0; 8])));
+    }
+
+    #[test]
+    fn too_long_for_bpf() {
+        let hosts: Vec<String> = (0..200).map(|i| format!("host 10.0.0.{}", i)).collect();
+        assert!(Filter::parse(&hosts.join(" or ")).unwrap().compile().is_none());
+    }
+}
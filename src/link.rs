@@ -0,0 +1,278 @@
+//! リンク層のヘッダを外し、EtherType と中身 (IP パケットなど) に分ける。
+//! Ethernet のほか、Linux cooked capture (SLL/SLL2), ヘッダなしの IP,
+//! BSD のループバック (NULL/LOOP) に対応する。
+
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::util::MacAddr;
+
+use super::pcap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    /// BSD のループバック (アドレスファミリがホストのバイトオーダ)
+    Null,
+    /// OpenBSD のループバック (アドレスファミリがネットワークバイトオーダ)
+    Loop,
+    /// リンク層ヘッダなしの IPv4/IPv6
+    Raw,
+    /// Linux cooked capture (tcpdump -i any)
+    LinuxSll,
+    LinuxSll2,
+    /// RS-485 スニファなどのシリアルの RTU フレーム
+    Serial,
+}
+
+impl LinkType {
+    /// pcap ファイルのリンクタイプから変換する
+    pub fn from_pcap(linktype: u32) -> Option<LinkType> {
+        match linktype {
+            pcap::LINKTYPE_NULL => Some(LinkType::Null),
+            pcap::LINKTYPE_ETHERNET => Some(LinkType::Ethernet),
+            pcap::LINKTYPE_RAW | pcap::LINKTYPE_IPV4 | pcap::LINKTYPE_IPV6 => Some(LinkType::Raw),
+            pcap::LINKTYPE_LOOP => Some(LinkType::Loop),
+            pcap::LINKTYPE_LINUX_SLL => Some(LinkType::LinuxSll),
+            pcap::LINKTYPE_LINUX_SLL2 => Some(LinkType::LinuxSll2),
+            pcap::LINKTYPE_USER0..=pcap::LINKTYPE_USER15 => Some(LinkType::Serial),
+            _ => None,
+        }
+    }
+
+    /// pcap ファイルに書くときのリンクタイプ
+    pub fn to_pcap(self) -> u32 {
+        match self {
+            LinkType::Ethernet => pcap::LINKTYPE_ETHERNET,
+            LinkType::Null => pcap::LINKTYPE_NULL,
+            LinkType::Loop => pcap::LINKTYPE_LOOP,
+            LinkType::Raw => pcap::LINKTYPE_RAW,
+            LinkType::LinuxSll => pcap::LINKTYPE_LINUX_SLL,
+            LinkType::LinuxSll2 => pcap::LINKTYPE_LINUX_SLL2,
+            LinkType::Serial => pcap::LINKTYPE_USER0,
+        }
+    }
+}
+
+/// リンク層ヘッダを外したフレーム
+pub struct Frame<'a> {
+    /// リンク層のアドレス (リンクタイプによってはない)
+    pub source: Option<MacAddr>,
+    pub destination: Option<MacAddr>,
+    pub ethertype: EtherType,
+    pub payload: &'a [u8],
+}
+
+/// BSD のループバックのアドレスファミリ (AF_INET6 は OS によって値が違う)
+const AF_INET: u32 = 2;
+const AF_INET6_BSD: u32 = 24;
+const AF_INET6_FREEBSD: u32 = 28;
+const AF_INET6_DARWIN: u32 = 30;
+
+const SLL_HEADER_LENGTH: usize = 16;
+const SLL2_HEADER_LENGTH: usize = 20;
+
+fn mac_address(address: &[u8]) -> MacAddr {
+    MacAddr(
+        address[0], address[1], address[2], address[3], address[4], address[5],
+    )
+}
+
+/// IP のバージョンから EtherType を決める
+fn ip_ethertype(packet: &[u8]) -> Option<EtherType> {
+    match packet.first()? >> 4 {
+        4 => Some(EtherTypes::Ipv4),
+        6 => Some(EtherTypes::Ipv6),
+        _ => None,
+    }
+}
+
+fn family_ethertype(family: u32) -> Option<EtherType> {
+    match family {
+        AF_INET => Some(EtherTypes::Ipv4),
+        AF_INET6_BSD | AF_INET6_FREEBSD | AF_INET6_DARWIN => Some(EtherTypes::Ipv6),
+        _ => None,
+    }
+}
+
+/// SLL/SLL2 のアドレス欄 (長さ, 最大 8 バイト) から 6 バイトのアドレスを取り出す
+fn sll_address(length: u16, address: &[u8]) -> Option<MacAddr> {
+    if length == 6 {
+        Some(mac_address(address))
+    } else {
+        None
+    }
+}
+
+/// リンク層ヘッダを解析する。短すぎるなど形式が崩れていれば None
+pub fn decode(linktype: LinkType, data: &[u8]) -> Option<Frame<'_>> {
+    match linktype {
+        LinkType::Ethernet => {
+            let ethernet = EthernetPacket::new(data)?;
+            Some(Frame {
+                source: Some(ethernet.get_source()),
+                destination: Some(ethernet.get_destination()),
+                ethertype: ethernet.get_ethertype(),
+                payload: &data[EthernetPacket::minimum_packet_size()..],
+            })
+        }
+        LinkType::Null | LinkType::Loop => {
+            if data.len() < 4 {
+                return None;
+            }
+            let mut family = [0u8; 4];
+            family.copy_from_slice(&data[..4]);
+            let family = if linktype == LinkType::Loop {
+                u32::from_be_bytes(family)
+            } else {
+                /* 書き出したホストのバイトオーダなので、小さい値になる方を選ぶ */
+                let family = u32::from_le_bytes(family);
+                if family > 0xffff {
+                    family.swap_bytes()
+                } else {
+                    family
+                }
+            };
+            Some(Frame {
+                source: None,
+                destination: None,
+                ethertype: family_ethertype(family)?,
+                payload: &data[4..],
+            })
+        }
+        LinkType::Raw => Some(Frame {
+            source: None,
+            destination: None,
+            ethertype: ip_ethertype(data)?,
+            payload: data,
+        }),
+        LinkType::LinuxSll => {
+            /* packet type, ARPHRD, アドレス長, アドレス (8), protocol */
+            if data.len() < SLL_HEADER_LENGTH {
+                return None;
+            }
+            let length = u16::from_be_bytes([data[4], data[5]]);
+            Some(Frame {
+                source: sll_address(length, &data[6..14]),
+                destination: None,
+                ethertype: EtherType(u16::from_be_bytes([data[14], data[15]])),
+                payload: &data[SLL_HEADER_LENGTH..],
+            })
+        }
+        LinkType::LinuxSll2 => {
+            /* protocol, 予約, インタフェース番号, ARPHRD, packet type, アドレス長, アドレス (8) */
+            if data.len() < SLL2_HEADER_LENGTH {
+                return None;
+            }
+            Some(Frame {
+                source: sll_address(data[11] as u16, &data[12..20]),
+                destination: None,
+                ethertype: EtherType(u16::from_be_bytes([data[0], data[1]])),
+                payload: &data[SLL2_HEADER_LENGTH..],
+            })
+        }
+        LinkType::Serial => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPV4: [u8; 4] = [0x45, 0, 0, 20];
+    const IPV6: [u8; 4] = [0x60, 0, 0, 0];
+    const MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    /// (送信元, 宛先, EtherType, 中身)
+    type Fields = (Option<MacAddr>, Option<MacAddr>, EtherType, Vec<u8>);
+
+    fn frame(linktype: LinkType, data: &[u8]) -> Option<Fields> {
+        let frame = decode(linktype, data)?;
+        Some((frame.source, frame.destination, frame.ethertype, frame.payload.to_vec()))
+    }
+
+    #[test]
+    fn ethernet() {
+        let data = [&[0xff; 6][..], &[0, 0x11, 0x22, 0x33, 0x44, 0x55], &[0x08, 0x00], &IPV4].concat();
+        let broadcast = MacAddr::broadcast();
+        assert_eq!(
+            frame(LinkType::Ethernet, &data),
+            Some((Some(MAC), Some(broadcast), EtherTypes::Ipv4, IPV4.to_vec()))
+        );
+        assert_eq!(frame(LinkType::Ethernet, &data[..13]), None);
+    }
+
+    #[test]
+    fn sll() {
+        /* packet type, ARPHRD_ETHER, アドレス長 6, アドレス (8), protocol */
+        let header = [&[0, 0, 0, 1, 0, 6][..], &[0, 0x11, 0x22, 0x33, 0x44, 0x55, 0, 0], &[0x86, 0xdd]].concat();
+        let data = [&header[..], &IPV6].concat();
+        assert_eq!(
+            frame(LinkType::LinuxSll, &data),
+            Some((Some(MAC), None, EtherTypes::Ipv6, IPV6.to_vec()))
+        );
+        /* アドレス長が 6 でなければアドレスはない */
+        let mut other = data.clone();
+        other[5] = 0;
+        assert_eq!(frame(LinkType::LinuxSll, &other).unwrap().0, None);
+        assert_eq!(frame(LinkType::LinuxSll, &header).unwrap().3, Vec::<u8>::new());
+        assert_eq!(frame(LinkType::LinuxSll, &header[..15]), None);
+    }
+
+    #[test]
+    fn sll2() {
+        /* protocol, 予約, インタフェース番号, ARPHRD_ETHER, packet type, アドレス長 6, アドレス (8) */
+        let header = [
+            &[0x08, 0x00, 0, 0, 0, 0, 0, 2, 0, 1, 4, 6][..],
+            &[0, 0x11, 0x22, 0x33, 0x44, 0x55, 0, 0],
+        ]
+        .concat();
+        let data = [&header[..], &IPV4].concat();
+        assert_eq!(
+            frame(LinkType::LinuxSll2, &data),
+            Some((Some(MAC), None, EtherTypes::Ipv4, IPV4.to_vec()))
+        );
+        assert_eq!(frame(LinkType::LinuxSll2, &header[..19]), None);
+    }
+
+    #[test]
+    fn loopback() {
+        /* NULL はホストのバイトオーダなので、どちらのバイトオーダでも読む */
+        for family in [[2, 0, 0, 0], [0, 0, 0, 2]] {
+            let data = [&family[..], &IPV4].concat();
+            assert_eq!(
+                frame(LinkType::Null, &data),
+                Some((None, None, EtherTypes::Ipv4, IPV4.to_vec()))
+            );
+        }
+        for family in [24u32, 28, 30] {
+            for bytes in [family.to_le_bytes(), family.to_be_bytes()] {
+                let data = [&bytes[..], &IPV6].concat();
+                assert_eq!(frame(LinkType::Null, &data).unwrap().2, EtherTypes::Ipv6);
+            }
+        }
+        /* LOOP は常にネットワークバイトオーダ */
+        let data = [&[0, 0, 0, 24][..], &IPV6].concat();
+        assert_eq!(
+            frame(LinkType::Loop, &data),
+            Some((None, None, EtherTypes::Ipv6, IPV6.to_vec()))
+        );
+        assert_eq!(frame(LinkType::Loop, &[&[2, 0, 0, 0][..], &IPV4].concat()), None);
+        assert_eq!(frame(LinkType::Null, &[&[7, 0, 0, 0][..], &IPV4].concat()), None);
+        assert_eq!(frame(LinkType::Null, &[2, 0, 0]), None);
+        assert_eq!(frame(LinkType::Loop, &[]), None);
+    }
+
+    #[test]
+    fn raw() {
+        assert_eq!(
+            frame(LinkType::Raw, &IPV4),
+            Some((None, None, EtherTypes::Ipv4, IPV4.to_vec()))
+        );
+        assert_eq!(
+            frame(LinkType::Raw, &IPV6),
+            Some((None, None, EtherTypes::Ipv6, IPV6.to_vec()))
+        );
+        assert_eq!(frame(LinkType::Raw, &[0x55]), None);
+        assert_eq!(frame(LinkType::Raw, &[]), None);
+        assert_eq!(frame(LinkType::Serial, &IPV4), None);
+    }
+}
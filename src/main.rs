@@ -4,53 +4,348 @@ extern crate pnet_macros_support;
 use pnet::datalink::{self, NetworkInterface};
 
 use pnet::packet::arp::ArpPacket;
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ethernet::EtherTypes;
 use pnet::packet::icmp::{echo_reply, echo_request, IcmpPacket, IcmpTypes};
 use pnet::packet::icmpv6::Icmpv6Packet;
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::ipv6::Ipv6Packet;
-use pnet::packet::tcp::TcpPacket;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::ipv6::{ExtensionPacket, FragmentPacket, Ipv6Packet};
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
 use pnet::packet::udp::UdpPacket;
+use pnet::packet::vlan::VlanPacket;
 use pnet::packet::Packet;
 use pnet::util::MacAddr;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::io::{self, Write};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::mem;
 use std::net::IpAddr;
 use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+mod cli;
+mod clock;
+mod display_filter;
+mod filter;
+mod link;
+mod output;
 mod packet;
+mod pcap;
+mod reassembly;
+mod register_map;
+mod stats;
+mod tls;
+mod tree;
+mod tunnel;
 //use packet::modbus_tcp::{ModbusTCPPacket, FunctionFieldValues};
+use cli::{Command, Format, Options, TimeFormat, Verbosity};
+use clock::Clock;
+use display_filter::DisplayFilter;
+use filter::Filter;
+use link::{Frame, LinkType};
+use output::{device_name, Adu, CapturedFrame, CsvSink, Event, JsonSink, Sink, TextSink};
+use packet::conformance;
+use packet::fields::AduFields;
+use packet::modbus_ascii::{self, LineBuffer};
+use packet::modbus_rtu::{self, DirectionTracker, RtuBuffer, RtuFrame};
 use packet::modbus_tcp::*;
+use pcap::{PcapReader, PcapWriter};
+use reassembly::{FragmentKey, Reassembler};
+use register_map::RegisterMap;
+use stats::Statistics;
+use tls::keylog::KeyLog;
+use tunnel::Tunnel;
 
-fn handle_udp_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) {
+/// 各層のパケットの 1 行の説明を出力に渡す (modbus は Modbus のポートやシリアルバスのものか)
+macro_rules! report {
+    ($context:expr, $interface:expr, $modbus:expr, $($arg:tt)*) => {{
+        let modbus = $modbus;
+        let summary = format!($($arg)*);
+        $context.sink.event($context.clock.time(), &Event::Packet {
+            interface: $interface,
+            summary,
+            modbus,
+        });
+    }};
+}
+
+/// ADU 以外の Modbus の通信についての説明を出力に渡す
+macro_rules! note {
+    ($context:expr, $($arg:tt)*) => {
+        $context.sink.event($context.clock.time(), &Event::Note(format!($($arg)*)))
+    };
+}
+
+/// Modbusのポートで使われているフレーム形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    /// MBAPヘッダ付きの ModbusTCP
+    Tcp,
+    /// MBAPヘッダなしで RTU フレーム (アドレス, PDU, CRC) をそのまま流すもの
+    Rtu,
+    /// ':' で始まり CRLF で終わる ASCII フレームを流すもの
+    Ascii,
+    /// TLS で保護された ModbusTCP (Modbus/TCP Security)
+    Tls,
+}
+
+/// 送信元・送信先のアドレスとポートの組
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Flow {
+    /// VLAN ID (外側のタグから順に)
+    vlans: Vec<u16>,
+    source: IpAddr,
+    source_port: u16,
+    destination: IpAddr,
+    destination_port: u16,
+}
+
+impl Flow {
+    /// 逆向きのフロー
+    fn reversed(&self) -> Flow {
+        Flow {
+            vlans: self.vlans.clone(),
+            source: self.destination,
+            source_port: self.destination_port,
+            destination: self.source,
+            destination_port: self.source_port,
+        }
+    }
+
+    /// 両方向で同じになるフロー (アドレスとポートの小さい方を送信元にする)
+    fn connection(&self) -> Flow {
+        if (self.source, self.source_port) <= (self.destination, self.destination_port) {
+            self.clone()
+        } else {
+            self.reversed()
+        }
+    }
+}
+
+/// 応答を待つリクエストの数がこれを超えたら、古いものを捨てる
+const MAX_PENDING_REQUESTS: usize = 10000;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// パケットの解析全体で共有する設定と状態
+struct Context {
+    modbus_ports: HashMap<u16, Framing>,
+    /// ASCII フレームの組み立て途中の行 (フローごと)
+    ascii_lines: HashMap<Flow, LineBuffer>,
+    /// シリアルバスのキャプチャでリクエストか応答かを推定するための状態
+    serial_direction: DirectionTracker,
+    /// TLS の接続ごとの状態 (クライアントからサーバ向きのフローで引く)
+    tls_sessions: HashMap<Flow, tls::Session>,
+    /// 復号に使う鍵ログ (--keylog)
+    keylog: Option<KeyLog>,
+    /// 復号したデータを ADU に分けるためのバッファ (フローごと)
+    mbap_streams: HashMap<Flow, AduBuffer>,
+    /// TCP で流れる RTU フレームを分けるためのバッファ (フローごと)
+    rtu_streams: HashMap<Flow, RtuBuffer>,
+    /// IP フラグメントの組み立て
+    fragments: Reassembler,
+    /// 解析中のフレームのキャプチャ時刻 (1970-01-01 からの経過時間)
+    timestamp: Duration,
+    /// --time の最初のフレームとフローごとの直前のフレームの時刻
+    clock: Clock,
+    /// 解析中のフレームを受信したインタフェース (pcap ファイルならそのパス)
+    interface: String,
+    /// 次に解析する ADU で見つかった CRC・LRC の不一致
+    adu_errors: Vec<String>,
+    /// キャプチャフィルタ (--filter)
+    filter: Option<Filter>,
+    /// Modbus の値に対する表示フィルタ (--display-filter)
+    display_filter: Option<DisplayFilter>,
+    /// 読み書きした値にタグ名を付けるレジスタマップ (--register-map)
+    register_map: Option<RegisterMap>,
+    /// 応答を待っているリクエスト ((クライアントからサーバ向きのフロー, transaction, unit) ごと)
+    pending_requests: HashMap<(Option<Flow>, u16, u8), (Duration, AduFields)>,
+    /// Modbus のポートで開いている TCP 接続 (クライアントからサーバ向きのフロー)
+    connections: HashSet<Flow>,
+    /// 解析した結果の出力先
+    sink: Box<dyn Sink>,
+    /// フィルタに一致したフレームを書き出す pcap ファイルのパス (--write)
+    write_path: Option<String>,
+    /// write_path に入力のリンクタイプで開いた pcap ファイル
+    pcap_writer: Option<PcapWriter<BufWriter<File>>>,
+    /// 解析中のフレームが --filter と --display-filter に一致したか
+    frame_selected: bool,
+    /// シリアルバスの直前のフレームをリクエストと推定したか
+    serial_request: bool,
+}
+
+impl Context {
+    fn new() -> Context {
+        let mut modbus_ports = HashMap::new();
+        modbus_ports.insert(502, Framing::Tcp);
+        modbus_ports.insert(802, Framing::Tls);
+        let sink = Box::new(TextSink::new(
+            Verbosity::Full,
+            false,
+            false,
+            modbus_ports.clone(),
+            TimeFormat::Absolute,
+        ));
+        Context {
+            modbus_ports,
+            ascii_lines: HashMap::new(),
+            serial_direction: DirectionTracker::new(),
+            tls_sessions: HashMap::new(),
+            keylog: None,
+            mbap_streams: HashMap::new(),
+            rtu_streams: HashMap::new(),
+            fragments: Reassembler::default(),
+            timestamp: Duration::default(),
+            clock: Clock::new(),
+            interface: String::new(),
+            adu_errors: Vec::new(),
+            filter: None,
+            display_filter: None,
+            register_map: None,
+            pending_requests: HashMap::new(),
+            connections: HashSet::new(),
+            sink,
+            write_path: None,
+            pcap_writer: None,
+            frame_selected: false,
+            serial_request: false,
+        }
+    }
+
+    /// リクエストを覚えておき、応答が来たら対応するリクエストと組にして、そのリクエストを返す
+    fn pair_transaction(&mut self, flow: Option<&Flow>, fields: &mut AduFields) -> Option<AduFields> {
+        let client_flow = if fields.is_request {
+            flow.cloned()
+        } else {
+            flow.map(Flow::reversed)
+        };
+        let key = (client_flow, fields.transaction, fields.unit);
+        if fields.is_request {
+            if self.pending_requests.len() >= MAX_PENDING_REQUESTS {
+                let now = self.timestamp;
+                self.pending_requests
+                    .retain(|_, (time, _)| now.saturating_sub(*time) < REQUEST_TIMEOUT);
+            }
+            /* 時間内のものだけで上限に達していれば、いちばん古いものを捨てる */
+            if self.pending_requests.len() >= MAX_PENDING_REQUESTS
+                && !self.pending_requests.contains_key(&key)
+            {
+                let oldest = self
+                    .pending_requests
+                    .iter()
+                    .min_by_key(|(_, (time, _))| *time)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.pending_requests.remove(&oldest);
+                }
+            }
+            self.pending_requests
+                .insert(key, (self.timestamp, fields.clone()));
+        } else if let Some((time, request)) = self.pending_requests.remove(&key) {
+            if request.function == fields.function {
+                fields.pair(&request, self.timestamp.saturating_sub(time));
+                return Some(request);
+            }
+        }
+        None
+    }
+
+    fn framing(&self, port: u16) -> Option<Framing> {
+        self.modbus_ports.get(&port).cloned()
+    }
+
+    /// TCP・UDP のパケットが Modbus のポートのものか (Modbus の行と一緒に出す)
+    fn is_modbus_port(&self, source_port: u16, destination_port: u16) -> bool {
+        self.framing(source_port).is_some() || self.framing(destination_port).is_some()
+    }
+
+    /// 解析できなかったものを出力に渡す
+    fn malformed(&mut self, interface: Option<&str>, what: &str, modbus: bool) {
+        self.sink.event(self.clock.time(), &Event::Malformed {
+            interface,
+            what: what.to_string(),
+            modbus,
+        });
+    }
+}
+
+fn handle_udp_packet(
+    context: &mut Context,
+    interface_name: &str,
+    vlans: &[u16],
+    source: IpAddr,
+    destination: IpAddr,
+    packet: &[u8],
+) {
     let udp = UdpPacket::new(packet);
 
     if let Some(udp) = udp {
-        println!(
-            "[{}]: UDP Packet: {}:{} > {}:{}; length: {}",
+        let flow = Flow {
+            vlans: vlans.to_vec(),
+            source,
+            source_port: udp.get_source(),
+            destination,
+            destination_port: udp.get_destination(),
+        };
+        context.clock.enter_flow(&flow);
+        report!(
+            context,
             interface_name,
+            context.is_modbus_port(udp.get_source(), udp.get_destination()),
+            "UDP Packet: {}:{} > {}:{}; length: {}",
             source,
             udp.get_source(),
             destination,
             udp.get_destination(),
             udp.get_length()
         );
+        /* Modbus/TCP Security は TCP だけなので、UDP の TLS のポートは解析しない */
+        match (
+            context.framing(udp.get_source()).filter(|f| *f != Framing::Tls),
+            context.framing(udp.get_destination()).filter(|f| *f != Framing::Tls),
+        ) {
+            ( _ , Some(framing) ) => { /* (送信元, 送信先) Request */
+                handle_modbus_datagram(context, flow, framing, udp.payload(), true)
+            }
+            ( Some(framing) , _ ) => { /* (送信元, 送信先) Reply */
+                handle_modbus_datagram(context, flow, framing, udp.payload(), false)
+            }
+            ( _ , _ ) if udp.get_destination() == tunnel::VXLAN_PORT => {
+                handle_tunnel(
+                    context,
+                    interface_name,
+                    source,
+                    destination,
+                    tunnel::decode_vxlan(udp.payload()),
+                )
+            }
+            ( _ , _ ) => { /* Modbus以外の通信 */ }
+        }
     } else {
-        println!("[{}]: Malformed UDP Packet", interface_name);
+        context.malformed(Some(interface_name), "UDP Packet", false);
     }
 }
 
-fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) {
+fn handle_icmp_packet(
+    context: &mut Context,
+    interface_name: &str,
+    source: IpAddr,
+    destination: IpAddr,
+    packet: &[u8],
+) {
     let icmp_packet = IcmpPacket::new(packet);
     if let Some(icmp_packet) = icmp_packet {
         match icmp_packet.get_icmp_type() {
             IcmpTypes::EchoReply => {
                 let echo_reply_packet = echo_reply::EchoReplyPacket::new(packet).unwrap();
-                println!(
-                    "[{}]: ICMP echo reply {} -> {} (seq={:?}, id={:?})",
+                report!(
+                    context,
                     interface_name,
+                    false,
+                    "ICMP echo reply {} -> {} (seq={:?}, id={:?})",
                     source,
                     destination,
                     echo_reply_packet.get_sequence_number(),
@@ -59,304 +354,389 @@ fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr,
             }
             IcmpTypes::EchoRequest => {
                 let echo_request_packet = echo_request::EchoRequestPacket::new(packet).unwrap();
-                println!(
-                    "[{}]: ICMP echo request {} -> {} (seq={:?}, id={:?})",
+                report!(
+                    context,
                     interface_name,
+                    false,
+                    "ICMP echo request {} -> {} (seq={:?}, id={:?})",
                     source,
                     destination,
                     echo_request_packet.get_sequence_number(),
                     echo_request_packet.get_identifier()
                 );
             }
-            _ => println!(
-                "[{}]: ICMP packet {} -> {} (type={:?})",
+            _ => report!(
+                context,
                 interface_name,
+                false,
+                "ICMP packet {} -> {} (type={:?})",
                 source,
                 destination,
                 icmp_packet.get_icmp_type()
             ),
         }
     } else {
-        println!("[{}]: Malformed ICMP Packet", interface_name);
+        context.malformed(Some(interface_name), "ICMP Packet", false);
     }
 }
 
-fn handle_icmpv6_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) {
+fn handle_icmpv6_packet(
+    context: &mut Context,
+    interface_name: &str,
+    source: IpAddr,
+    destination: IpAddr,
+    packet: &[u8],
+) {
     let icmpv6_packet = Icmpv6Packet::new(packet);
     if let Some(icmpv6_packet) = icmpv6_packet {
-        println!(
-            "[{}]: ICMPv6 packet {} -> {} (type={:?})",
+        report!(
+            context,
             interface_name,
+            false,
+            "ICMPv6 packet {} -> {} (type={:?})",
             source,
             destination,
             icmpv6_packet.get_icmpv6_type()
         );
     } else {
-        println!("[{}]: Malformed ICMPv6 Packet", interface_name);
+        context.malformed(Some(interface_name), "ICMPv6 Packet", false);
+    }
+}
+
+fn handle_modbus_packet(context: &mut Context, flow: Option<&Flow>, packet: &[u8], is_request: bool) {
+    let errors = mem::take(&mut context.adu_errors);
+    let mut fields = match AduFields::decode(packet, is_request) {
+        Some(fields) => fields,
+        None => {
+            /* MBAPヘッダとして読めなかったもの。フレームの CRC・LRC の不一致は捨てずに出す */
+            for error in &errors {
+                context.malformed(None, error, true);
+            }
+            return;
+        }
+    };
+    let request = context.pair_transaction(flow, &mut fields);
+    if let Some(display_filter) = &context.display_filter {
+        if !display_filter.matches(&fields, flow) {
+            return;
+        }
+    }
+    context.frame_selected = true;
+    /* 書き込みの応答には値がないので、正常応答が返ったリクエストの値を示す */
+    let written = match &request {
+        Some(request) if fields.values.is_empty() && fields.exception_code.is_none() => request,
+        _ => &fields,
+    };
+    let tags = match &context.register_map {
+        Some(register_map) => register_map.annotate(&device_name(flow, is_request), written),
+        None => Vec::new(),
+    };
+    let violations = if is_request {
+        conformance::check_request(packet)
+    } else {
+        conformance::check_reply(packet, request.as_ref())
+    };
+    let adu = Adu {
+        interface: &context.interface,
+        flow,
+        fields: &fields,
+        request: request.as_ref(),
+        packet,
+        errors: &errors,
+        violations: &violations,
+        tags: &tags,
+    };
+    let event = if is_request {
+        Event::ModbusRequest(adu)
+    } else if fields.exception {
+        Event::Exception(adu)
+    } else {
+        Event::ModbusReply(adu)
+    };
+    context.sink.event(context.clock.time(), &event);
+}
+
+/// シリアルのフレームがファンクションの最小の長さに満たなければ malformed を出して false を返す
+fn check_frame_length(context: &mut Context, kind: &str, length: usize, minimum: usize, function: u8) -> bool {
+    if length < minimum {
+        let what = format!(
+            "{} Frame: {} bytes, function {} needs at least {}",
+            kind, length, function, minimum
+        );
+        context.malformed(None, &what, true);
+        return false;
+    }
+    true
+}
+
+fn handle_rtu_frame(context: &mut Context, flow: Option<&Flow>, packet: &[u8], is_request: bool) {
+    let rtu = RtuFrame::new(packet);
+    if let Some(rtu) = rtu {
+        let minimum = modbus_rtu::minimum_length(rtu.get_function(), is_request);
+        if !check_frame_length(context, "RTU", packet.len(), minimum, rtu.get_function()) {
+            return;
+        }
+        if !rtu.crc_ok() {
+            let error = format!(
+                "RTU CRC error: frame {:#06x}, computed {:#06x}",
+                rtu.get_crc(),
+                rtu.computed_crc()
+            );
+            context.adu_errors.push(error);
+        }
+        handle_modbus_packet(context, flow, &rtu.to_mbap(0), is_request);
+    } else if !packet.is_empty() {
+        context.malformed(None, "RTU Frame", true);
+    }
+}
+
+/// TCP で流れる RTU フレームをフレームごとに分けて解析する
+fn handle_rtu_stream(context: &mut Context, flow: Flow, packet: &[u8], is_request: bool) {
+    let frames = context
+        .rtu_streams
+        .entry(flow.clone())
+        .or_default()
+        .push(packet, is_request);
+    for frame in frames {
+        handle_rtu_frame(context, Some(&flow), &frame, is_request);
+    }
+}
+
+fn handle_ascii_stream(context: &mut Context, flow: Flow, packet: &[u8], is_request: bool) {
+    let lines = context
+        .ascii_lines
+        .entry(flow.clone())
+        .or_default()
+        .push(packet);
+    for line in lines {
+        match modbus_ascii::decode_line(&line) {
+            Ok(ascii) => {
+                let minimum = modbus_ascii::minimum_length(ascii.get_function(), is_request);
+                if !check_frame_length(context, "ASCII", ascii.byte_length(), minimum, ascii.get_function()) {
+                    continue;
+                }
+                if !ascii.lrc_ok() {
+                    let error = format!(
+                        "ASCII LRC error: frame {:#04x}, computed {:#04x}",
+                        ascii.get_lrc(),
+                        ascii.computed_lrc()
+                    );
+                    context.adu_errors.push(error);
+                }
+                handle_modbus_packet(context, Some(&flow), &ascii.to_mbap(0), is_request);
+            }
+            Err(e) => context.malformed(None, &format!("ASCII Frame: {}", e), true),
+        }
     }
 }
 
-fn handle_tcp_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) {
+fn handle_tls_stream(context: &mut Context, flow: Flow, packet: &[u8], is_request: bool) {
+    let key = if is_request { flow.clone() } else { flow.reversed() };
+    let events = context
+        .tls_sessions
+        .entry(key)
+        .or_default()
+        .push(is_request, packet, context.keylog.as_mut());
+    for event in events {
+        match event {
+            tls::Event::ClientHello {
+                version,
+                server_name,
+            } => note!(
+                context,
+                "TLS ClientHello, version: {}, server name: {}",
+                tls::version_name(version),
+                server_name.as_deref().unwrap_or("-")
+            ),
+            tls::Event::ServerHello {
+                version,
+                cipher_suite,
+            } => note!(
+                context,
+                "TLS ServerHello, version: {}, cipher suite: {}",
+                tls::version_name(version),
+                tls::cipher_suite_name(cipher_suite)
+            ),
+            tls::Event::HelloRetryRequest { cipher_suite } => note!(
+                context,
+                "TLS HelloRetryRequest, cipher suite: {}",
+                tls::cipher_suite_name(cipher_suite)
+            ),
+            tls::Event::UnsupportedCipherSuite(cipher_suite) => note!(
+                context,
+                "TLS warning: unsupported cipher suite {}, records will not be decrypted",
+                tls::cipher_suite_name(cipher_suite)
+            ),
+            tls::Event::Certificate(certificate) => note!(
+                context,
+                "TLS Certificate, subject: {}, issuer: {}, role: {}",
+                certificate.subject,
+                certificate.issuer,
+                certificate.role.as_deref().unwrap_or("-")
+            ),
+            tls::Event::Alert { level, description } => note!(
+                context,
+                "TLS Alert, level: {}, description: {}",
+                level, description
+            ),
+            tls::Event::ApplicationData(data) => {
+                let adus = context
+                    .mbap_streams
+                    .entry(flow.clone())
+                    .or_default()
+                    .push(&data);
+                for adu in adus {
+                    handle_modbus_packet(context, Some(&flow), &adu, is_request);
+                }
+            }
+            tls::Event::Encrypted {
+                content_type,
+                length,
+            } => note!(
+                context,
+                "TLS encrypted record, content type: {}, length: {}",
+                content_type, length
+            ),
+            tls::Event::Malformed(e) => context.malformed(None, &format!("TLS Record: {}", e), true),
+        }
+    }
+}
+
+fn handle_modbus_framing(
+    context: &mut Context,
+    flow: Flow,
+    framing: Framing,
+    packet: &[u8],
+    is_request: bool,
+) {
+    match framing {
+        Framing::Tcp => handle_modbus_packet(context, Some(&flow), packet, is_request),
+        Framing::Rtu => handle_rtu_stream(context, flow, packet, is_request),
+        Framing::Ascii => handle_ascii_stream(context, flow, packet, is_request),
+        Framing::Tls => handle_tls_stream(context, flow, packet, is_request),
+    }
+}
+
+/// Modbus/UDP は 1 データグラムに 1 ADU (RTU なら 1 フレーム) なので、長さが合わないものは解析しない
+fn handle_modbus_datagram(
+    context: &mut Context,
+    flow: Flow,
+    framing: Framing,
+    packet: &[u8],
+    is_request: bool,
+) {
+    match framing {
+        Framing::Tcp => match check_adu_length(packet, is_request) {
+            Ok(()) => handle_modbus_packet(context, Some(&flow), packet, is_request),
+            Err(e) => context.malformed(None, &format!("Modbus/UDP datagram: {}", e), true),
+        },
+        Framing::Rtu => handle_rtu_frame(context, Some(&flow), packet, is_request),
+        Framing::Ascii => handle_ascii_stream(context, flow, packet, is_request),
+        Framing::Tls => {}
+    }
+}
+
+fn handle_tcp_packet(
+    context: &mut Context,
+    interface_name: &str,
+    vlans: &[u16],
+    source: IpAddr,
+    destination: IpAddr,
+    packet: &[u8],
+) {
     let tcp = TcpPacket::new(packet);
     if let Some(tcp) = tcp {
-        println!(
-            "[{}]: TCP Packet: {}:{} > {}:{}; length: {}",
+        let flow = Flow {
+            vlans: vlans.to_vec(),
+            source,
+            source_port: tcp.get_source(),
+            destination,
+            destination_port: tcp.get_destination(),
+        };
+        context.clock.enter_flow(&flow);
+        report!(
+            context,
             interface_name,
+            context.is_modbus_port(tcp.get_source(), tcp.get_destination()),
+            "TCP Packet: {}:{} > {}:{}; length: {}",
             source,
             tcp.get_source(),
             destination,
             tcp.get_destination(),
             packet.len()
         );
-        let modbus_tcp = ModbusTCPPacket::new(tcp.payload());
-        if let Some(modbus_tcp) = modbus_tcp{
-            match (tcp.get_source(), tcp.get_destination()) {
-                ( _ , 502 ) => { /* (送信元, 送信先) Request */
-                    match modbus_tcp.get_function() {
-                        FunctionFieldValues::ReadCoilStatus => {
-                            let m_packet = read_coil_status::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read coil status({}) Request, Reference Number: {}, Bit Count: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_bit_count()
-                            );
-                        }
-                        FunctionFieldValues::ReadInputStatus => {
-                            let m_packet = read_input_status::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read input status({}) Request, Reference Number: {}, Bit Count: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_bit_count()
-                            );
-                        }
-                        FunctionFieldValues::ReadHoldingRegister => {
-                            let m_packet = read_holding_register::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read holding register({}) Request, Reference Number: {}, Bit Count: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_bit_count()
-                            );
-                        }
-                        FunctionFieldValues::ReadInputRegister => {
-                            let m_packet = read_input_register::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read input register({}) Request, Reference Number: {}, Bit Count: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_bit_count()
-                            );
-                        }
-                        FunctionFieldValues::ForceSingleCoil => {
-                            let m_packet = force_single_coil::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    force single coil({}) Request, Reference Number: {}, Data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::PresetSingleRegister => {
-                            let m_packet = preset_single_register::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    preset singe register({}) Request, Reference Number: {}, Data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::Diagnostics => {
-                            let m_packet = diagnostics::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    diagnostics({}) Request, sub code: {}, Data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_sub_code(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::FetchCommunicationEventCounter  => {
-                            let m_packet = fetch_communication_event_counter::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    fetch conmmunication event counter({}) Request,",
-                                m_packet.get_function(),
-                            );
-                        }
-                        FunctionFieldValues::FetchCommunicationEventCounterLog  => {
-                            let m_packet = fetch_communication_event_counter_log::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    fetch conmmunication event counter log({}) Request,",
-                                m_packet.get_function(),
-                            );
-                        }
-                        FunctionFieldValues::ForceMultipleCoils => {
-                            let m_packet = force_multiple_coils::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    force multiple coils({}) Request, Reference Number: {}, Register Count: {}, Byte Count: {}, data: {:?}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_register_count(),
-                                m_packet.get_byte_count(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::PresetMultipleRegisters => {
-                            let m_packet = preset_multiple_registers::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    preset multiple registers({}) Request, Reference Number: {}, Register Count: {}, Byte Count: {}, data: {:?}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_register_count(),
-                                m_packet.get_byte_count(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::ReportSlaveID  => {
-                            let m_packet = report_slave_id::request::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    freport_slave_id({}) Request,",
-                                m_packet.get_function(),
-                            );
-                        }
-                        _ => {
-                            println!(
-                                "unknown function number for {:?} request",
-                                modbus_tcp.get_function()
-                            );
-                        }
-                    }
+        match (
+            context.framing(tcp.get_source()),
+            context.framing(tcp.get_destination()),
+        ) {
+            ( _ , Some(framing) ) => { /* (送信元, 送信先) Request */
+                let flags = tcp.get_flags();
+                if flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN {
+                    open_connection(context, interface_name, &flow);
                 }
-                ( 502 , _ ) => { /* (送信元, 送信先) Reply */
-                    match modbus_tcp.get_function() {
-                        FunctionFieldValues::ReadCoilStatus => {
-                            let m_packet = read_coil_status::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read coil status({}) Reply, Byte Count: {}, Data: {:?}",
-                                m_packet.get_function(),
-                                m_packet.get_byte_count(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::ReadInputStatus => {
-                            let m_packet = read_input_status::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read input status({}) Reply, Byte Count: {}, Data: {:?}",
-                                m_packet.get_function(),
-                                m_packet.get_byte_count(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::ReadHoldingRegister => {
-                            let m_packet = read_holding_register::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read holding register({}) Reply, Byte Count: {}, Data: {:?}",
-                                m_packet.get_function(),
-                                m_packet.get_byte_count(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::ReadInputRegister => {
-                            let m_packet = read_input_register::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    read input register({}) Reply, Byte Count: {}, Data: {:?}",
-                                m_packet.get_function(),
-                                m_packet.get_byte_count(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::ForceSingleCoil => {
-                            let m_packet = force_single_coil::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    force single coil({}) Reply, Reference Number: {}, Data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::PresetSingleRegister => {
-                            let m_packet = preset_single_register::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    preset singe register({}) Reply, Reference Number: {}, Data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::Diagnostics => {
-                            let m_packet = diagnostics::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    diagnostics({}) Reply, sub code: {}, Data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_sub_code(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::FetchCommunicationEventCounter  => {
-                            let m_packet = fetch_communication_event_counter::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    fetch conmmunication event counter({}) Reply, status: {}, event counter: {}",
-                                m_packet.get_function(),
-                                m_packet.get_status(),
-                                m_packet.get_event_counter()
-                            );
-                        }
-                        FunctionFieldValues::FetchCommunicationEventCounterLog  => {
-                            let m_packet = fetch_communication_event_counter_log::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    fetch conmmunication event counter log({}) Reply, byte count: {}, status: {}, event counter: {}, message counter: {}, event: {:?}",
-                                m_packet.get_function(),
-                                m_packet.get_byte_count(),
-                                m_packet.get_status(),
-                                m_packet.get_event_counter(),
-                                m_packet.get_message_counter(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::ForceMultipleCoils => {
-                            let m_packet = force_multiple_coils::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    force multiple coils({}) Reply, Reference Number: {}, data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::PresetMultipleRegisters => {
-                            let m_packet = preset_multiple_registers::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    preset multiple registers({}) Reply, Reference Number: {}, data: {}",
-                                m_packet.get_function(),
-                                m_packet.get_reference_number(),
-                                m_packet.get_data()
-                            );
-                        }
-                        FunctionFieldValues::ReportSlaveID  => {
-                            let m_packet = report_slave_id::reply::ModbusPacket::new(tcp.payload()).unwrap();
-                            println!(
-                                "    freport_slave_id({}) Reply, payload: {:?}",
-                                m_packet.get_function(),
-                                m_packet.payload()
-                            );
-                        }
-                        _ => {
-                            println!(
-                                "unknown function number for {:?} reply",
-                                modbus_tcp.get_function()
-                            );
-                        }
-                    }
+                let closing = (flags & (TcpFlags::FIN | TcpFlags::RST) != 0).then(|| flow.clone());
+                handle_modbus_framing(context, flow, framing, tcp.payload(), true);
+                if let Some(flow) = closing {
+                    close_connection(context, interface_name, &flow, true, flags);
                 }
-                ( _ , _ ) => { /* ModbusTCP以外の通信 */ }
             }
-        } else { /* ModbusTCPの形式に当てはまらなかったパケット */ }
+            ( Some(framing) , _ ) => { /* (送信元, 送信先) Reply */
+                let flags = tcp.get_flags();
+                let closing = (flags & (TcpFlags::FIN | TcpFlags::RST) != 0).then(|| flow.clone());
+                handle_modbus_framing(context, flow, framing, tcp.payload(), false);
+                if let Some(flow) = closing {
+                    close_connection(context, interface_name, &flow, false, flags);
+                }
+            }
+            ( _ , _ ) => { /* ModbusTCP以外の通信 */ }
+        }
     } else {
-        println!("[{}]: Malformed TCP Packet", interface_name);
+        context.malformed(Some(interface_name), "TCP Packet", false);
+    }
+}
+
+/// クライアントの SYN で Modbus のポートへの接続が始まったことを出力に渡す
+fn open_connection(context: &mut Context, interface_name: &str, flow: &Flow) {
+    if context.connections.insert(flow.clone()) {
+        context.sink.event(context.clock.time(), &Event::FlowOpened {
+            interface: interface_name,
+            flow,
+        });
+    }
+}
+
+/// FIN・RST で接続が終わったことを出力に渡し、送ってこなくなった向きの組み立て途中のデータを捨てる。
+/// RST では両方向と TLS の状態も捨てる
+fn close_connection(
+    context: &mut Context,
+    interface_name: &str,
+    flow: &Flow,
+    is_request: bool,
+    flags: u16,
+) {
+    let client_flow = if is_request { flow.clone() } else { flow.reversed() };
+    if context.connections.remove(&client_flow) {
+        context.sink.event(context.clock.time(), &Event::FlowClosed {
+            interface: interface_name,
+            flow: &client_flow,
+        });
+    }
+    context.ascii_lines.remove(flow);
+    context.mbap_streams.remove(flow);
+    context.rtu_streams.remove(flow);
+    if flags & TcpFlags::RST != 0 {
+        let reversed = flow.reversed();
+        context.ascii_lines.remove(&reversed);
+        context.mbap_streams.remove(&reversed);
+        context.rtu_streams.remove(&reversed);
+        context.tls_sessions.remove(&client_flow);
     }
 }
 
 fn handle_transport_protocol(
+    context: &mut Context,
     interface_name: &str,
+    vlans: &[u16],
     source: IpAddr,
     destination: IpAddr,
     protocol: IpNextHeaderProtocol,
@@ -364,20 +744,29 @@ fn handle_transport_protocol(
 ) {
     match protocol {
         IpNextHeaderProtocols::Udp => {
-            handle_udp_packet(interface_name, source, destination, packet)
+            handle_udp_packet(context, interface_name, vlans, source, destination, packet)
         }
         IpNextHeaderProtocols::Tcp => {
-            handle_tcp_packet(interface_name, source, destination, packet)
+            handle_tcp_packet(context, interface_name, vlans, source, destination, packet)
         }
         IpNextHeaderProtocols::Icmp => {
-            handle_icmp_packet(interface_name, source, destination, packet)
+            handle_icmp_packet(context, interface_name, source, destination, packet)
         }
         IpNextHeaderProtocols::Icmpv6 => {
-            handle_icmpv6_packet(interface_name, source, destination, packet)
+            handle_icmpv6_packet(context, interface_name, source, destination, packet)
         }
-        _ => println!(
-            "[{}]: Unknown {} packet: {} > {}; protocol: {:?} length: {}",
+        IpNextHeaderProtocols::Gre => handle_tunnel(
+            context,
+            interface_name,
+            source,
+            destination,
+            tunnel::decode_gre(packet),
+        ),
+        _ => report!(
+            context,
             interface_name,
+            false,
+            "Unknown {} packet: {} > {}; protocol: {:?} length: {}",
             match source {
                 IpAddr::V4(..) => "IPv4",
                 _ => "IPv6",
@@ -390,140 +779,764 @@ fn handle_transport_protocol(
     }
 }
 
-fn handle_ipv4_packet(interface_name: &str, ethernet: &EthernetPacket) {
-    let header = Ipv4Packet::new(ethernet.payload());
+/// フラグメントを組み立て、データグラムがそろったらトランスポート層に渡す
+fn handle_fragment(
+    context: &mut Context,
+    interface_name: &str,
+    key: FragmentKey,
+    protocol: IpNextHeaderProtocol,
+    offset: usize,
+    more: bool,
+    packet: &[u8],
+) {
+    report!(
+        context,
+        interface_name,
+        false,
+        "{} fragment: {} > {}; id: {} offset: {} length: {}{}",
+        match key.source {
+            IpAddr::V4(..) => "IPv4",
+            _ => "IPv6",
+        },
+        key.source,
+        key.destination,
+        key.identification,
+        offset,
+        packet.len(),
+        if more { " (more)" } else { "" }
+    );
+    let (vlans, source, destination) = (key.vlans.clone(), key.source, key.destination);
+    let datagram = context
+        .fragments
+        .push(context.timestamp, key, offset, more, packet);
+    if let Some(datagram) = datagram {
+        /* IPv6 では組み立てたデータの先頭にも拡張ヘッダが続くことがある */
+        let upper = match source {
+            IpAddr::V4(..) => Some((protocol, &datagram[..])),
+            IpAddr::V6(..) => skip_ipv6_extensions(protocol, &datagram),
+        };
+        if let Some((protocol, payload)) = upper {
+            handle_transport_protocol(
+                context,
+                interface_name,
+                &vlans,
+                source,
+                destination,
+                protocol,
+                payload,
+            );
+        } else {
+            context.malformed(Some(interface_name), "IPv6 Extension Header", false);
+        }
+    }
+}
+
+fn handle_ipv4_packet(context: &mut Context, interface_name: &str, vlans: &[u16], packet: &[u8]) {
+    /* IHL が 5 未満 (20 バイト未満) のヘッダは読めない */
+    let header = Ipv4Packet::new(packet).filter(|header| header.get_header_length() >= 5);
     if let Some(header) = header {
-        handle_transport_protocol(
-            interface_name,
-            IpAddr::V4(header.get_source()),
-            IpAddr::V4(header.get_destination()),
-            header.get_next_level_protocol(),
-            header.payload(),
-        );
+        let offset = header.get_fragment_offset() as usize * 8;
+        let more = header.get_flags() & Ipv4Flags::MoreFragments != 0;
+        if offset == 0 && !more {
+            handle_transport_protocol(
+                context,
+                interface_name,
+                vlans,
+                IpAddr::V4(header.get_source()),
+                IpAddr::V4(header.get_destination()),
+                header.get_next_level_protocol(),
+                header.payload(),
+            );
+        } else {
+            let key = FragmentKey {
+                vlans: vlans.to_vec(),
+                source: IpAddr::V4(header.get_source()),
+                destination: IpAddr::V4(header.get_destination()),
+                protocol: header.get_next_level_protocol().0,
+                identification: header.get_identification() as u32,
+            };
+            handle_fragment(
+                context,
+                interface_name,
+                key,
+                header.get_next_level_protocol(),
+                offset,
+                more,
+                header.payload(),
+            );
+        }
     } else {
-        println!("[{}]: Malformed IPv4 Packet", interface_name);
+        context.malformed(Some(interface_name), "IPv4 Packet", false);
     }
 }
 
-fn handle_ipv6_packet(interface_name: &str, ethernet: &EthernetPacket) {
-    let header = Ipv6Packet::new(ethernet.payload());
+/// Hop-by-Hop, Routing, Destination Options の拡張ヘッダを飛ばし、
+/// (次のプロトコル, その先頭からのデータ) を返す。Fragment ヘッダではそこで止まる。
+fn skip_ipv6_extensions(
+    next_header: IpNextHeaderProtocol,
+    packet: &[u8],
+) -> Option<(IpNextHeaderProtocol, &[u8])> {
+    let mut next_header = next_header;
+    let mut packet = packet;
+    while let IpNextHeaderProtocols::Hopopt
+    | IpNextHeaderProtocols::Ipv6Route
+    | IpNextHeaderProtocols::Ipv6Opts = next_header
+    {
+        let extension = ExtensionPacket::new(packet)?;
+        /* 長さは先頭の 8 バイトを除いた 8 バイト単位 */
+        let length = (extension.get_hdr_ext_len() as usize + 1) * 8;
+        if packet.len() < length {
+            return None;
+        }
+        next_header = extension.get_next_header();
+        packet = &packet[length..];
+    }
+    Some((next_header, packet))
+}
+
+fn handle_ipv6_packet(context: &mut Context, interface_name: &str, vlans: &[u16], packet: &[u8]) {
+    let header = Ipv6Packet::new(packet);
     if let Some(header) = header {
-        handle_transport_protocol(
+        match skip_ipv6_extensions(header.get_next_header(), header.payload()) {
+            Some((IpNextHeaderProtocols::Ipv6Frag, payload)) => {
+                handle_ipv6_fragment(context, interface_name, vlans, &header, payload)
+            }
+            Some((protocol, payload)) => handle_transport_protocol(
+                context,
+                interface_name,
+                vlans,
+                IpAddr::V6(header.get_source()),
+                IpAddr::V6(header.get_destination()),
+                protocol,
+                payload,
+            ),
+            None => context.malformed(Some(interface_name), "IPv6 Extension Header", false),
+        }
+    } else {
+        context.malformed(Some(interface_name), "IPv6 Packet", false);
+    }
+}
+
+fn handle_ipv6_fragment(
+    context: &mut Context,
+    interface_name: &str,
+    vlans: &[u16],
+    header: &Ipv6Packet,
+    packet: &[u8],
+) {
+    if let Some(fragment) = FragmentPacket::new(packet) {
+        let key = FragmentKey {
+            vlans: vlans.to_vec(),
+            source: IpAddr::V6(header.get_source()),
+            destination: IpAddr::V6(header.get_destination()),
+            protocol: fragment.get_next_header().0,
+            identification: fragment.get_id(),
+        };
+        handle_fragment(
+            context,
             interface_name,
-            IpAddr::V6(header.get_source()),
-            IpAddr::V6(header.get_destination()),
-            header.get_next_header(),
-            header.payload(),
+            key,
+            fragment.get_next_header(),
+            fragment.get_fragment_offset() as usize,
+            !fragment.is_last_fragment(),
+            &packet[FragmentPacket::minimum_packet_size()..],
         );
     } else {
-        println!("[{}]: Malformed IPv6 Packet", interface_name);
+        context.malformed(Some(interface_name), "IPv6 Fragment Header", false);
+    }
+}
+
+/// リンク層のアドレス (ないリンクタイプでは "-")
+fn link_address(address: Option<MacAddr>) -> String {
+    match address {
+        Some(address) => address.to_string(),
+        None => "-".to_string(),
     }
 }
 
-fn handle_arp_packet(interface_name: &str, ethernet: &EthernetPacket) {
-    let header = ArpPacket::new(ethernet.payload());
+fn handle_arp_packet(context: &mut Context, interface_name: &str, frame: &Frame, packet: &[u8]) {
+    let header = ArpPacket::new(packet);
     if let Some(header) = header {
-        println!(
-            "[{}]: ARP packet: {}({}) > {}({}); operation: {:?}",
+        report!(
+            context,
             interface_name,
-            ethernet.get_source(),
+            false,
+            "ARP packet: {}({}) > {}({}); operation: {:?}",
+            link_address(frame.source),
             header.get_sender_proto_addr(),
-            ethernet.get_destination(),
+            link_address(frame.destination),
             header.get_target_proto_addr(),
             header.get_operation()
         );
     } else {
-        println!("[{}]: Malformed ARP Packet", interface_name);
+        context.malformed(Some(interface_name), "ARP Packet", false);
     }
 }
 
-fn handle_ethernet_frame(interface: &NetworkInterface, ethernet: &EthernetPacket) {
-    let interface_name = &interface.name[..];
-    match ethernet.get_ethertype() {
-        EtherTypes::Ipv4 => handle_ipv4_packet(interface_name, ethernet),
-        EtherTypes::Ipv6 => handle_ipv6_packet(interface_name, ethernet),
-        EtherTypes::Arp => handle_arp_packet(interface_name, ethernet),
-        _ => println!(
-            "[{}]: Unknown packet: {} > {}; ethertype: {:?} length: {}",
+fn handle_link_frame(context: &mut Context, interface_name: &str, frame: &Frame) {
+    /* 802.1Q (QinQ の場合は 802.1ad も) のタグを外側から順に外す */
+    let mut vlans = Vec::new();
+    let mut ethertype = frame.ethertype;
+    let mut payload = frame.payload;
+    while let EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ = ethertype {
+        if let Some(vlan) = VlanPacket::new(payload) {
+            vlans.push(vlan.get_vlan_identifier());
+            ethertype = vlan.get_ethertype();
+            payload = &payload[VlanPacket::minimum_packet_size()..];
+        } else {
+            context.malformed(Some(interface_name), "VLAN Tag", false);
+            return;
+        }
+    }
+    let tagged_name;
+    let interface_name = if vlans.is_empty() {
+        interface_name
+    } else {
+        let ids: Vec<String> = vlans.iter().map(|id| id.to_string()).collect();
+        tagged_name = format!("{} vlan {}", interface_name, ids.join("."));
+        &tagged_name
+    };
+    match ethertype {
+        EtherTypes::Ipv4 => handle_ipv4_packet(context, interface_name, &vlans, payload),
+        EtherTypes::Ipv6 => handle_ipv6_packet(context, interface_name, &vlans, payload),
+        EtherTypes::Arp => handle_arp_packet(context, interface_name, frame, payload),
+        _ => report!(
+            context,
             interface_name,
-            ethernet.get_source(),
-            ethernet.get_destination(),
-            ethernet.get_ethertype(),
-            ethernet.packet().len()
+            false,
+            "Unknown packet: {} > {}; ethertype: {:?} length: {}",
+            link_address(frame.source),
+            link_address(frame.destination),
+            ethertype,
+            payload.len()
         ),
     }
 }
 
-fn main() {
-    use pnet::datalink::Channel::Ethernet;
+/// トンネルの中のフレームを、外側の送信元・送信先を付けたインタフェース名で解析し直す
+fn handle_tunnel(
+    context: &mut Context,
+    interface_name: &str,
+    source: IpAddr,
+    destination: IpAddr,
+    decapsulated: Option<(Tunnel, Frame)>,
+) {
+    if let Some((tunnel, frame)) = decapsulated {
+        report!(
+            context,
+            interface_name,
+            false,
+            "{} tunnel: {} > {}; length: {}",
+            tunnel,
+            source,
+            destination,
+            frame.payload.len()
+        );
+        let inner_name = format!("{} {} {}>{}", interface_name, tunnel, source, destination);
+        handle_link_frame(context, &inner_name, &frame);
+    } else {
+        context.malformed(Some(interface_name), "Tunnel Packet", false);
+    }
+}
 
-    let iface_name = match env::args().nth(1) {
-        Some(n) => n,
-        None => {
-            writeln!(io::stderr(), "USAGE: packetdump <NETWORK INTERFACE>").unwrap();
-            process::exit(1);
+fn handle_serial_frame(context: &mut Context, interface_name: &str, packet: &[u8]) {
+    let rtu = RtuFrame::new(packet);
+    if let Some(rtu) = rtu {
+        let is_request = context.serial_direction.is_request(&rtu);
+        context.serial_request = is_request;
+        report!(
+            context,
+            interface_name,
+            true,
+            "RTU frame: address {}; {}; length: {}",
+            rtu.get_address(),
+            if is_request { "Request" } else { "Reply" },
+            packet.len()
+        );
+        handle_rtu_frame(context, None, packet, is_request);
+    } else {
+        context.malformed(Some(interface_name), "RTU frame", true);
+    }
+}
+
+/// リンクタイプに合わせてリンク層ヘッダを外し、フレームを解析する
+fn handle_captured_frame(
+    context: &mut Context,
+    interface_name: &str,
+    linktype: LinkType,
+    packet: &[u8],
+) {
+    if context.interface != interface_name {
+        context.interface = interface_name.to_string();
+    }
+    context.clock.start_frame(context.timestamp);
+    /* 表示フィルタがあれば、一致した ADU を含むフレームだけを選ぶ */
+    let selected = context.display_filter.is_none();
+    context.frame_selected = false;
+    if linktype == LinkType::Serial {
+        context.frame_selected = selected;
+        handle_serial_frame(context, interface_name, packet);
+    } else if let Some(frame) = link::decode(linktype, packet) {
+        let matched = match &context.filter {
+            Some(filter) => filter.matches(&frame),
+            None => true,
+        };
+        if matched {
+            context.frame_selected = selected;
+            handle_link_frame(context, interface_name, &frame);
+        }
+    } else {
+        context.malformed(Some(interface_name), &format!("{:?} frame", linktype), false);
+    }
+    context.clock.finish_frame();
+    context.sink.event(context.clock.time(), &Event::FrameSeen(CapturedFrame {
+        interface: interface_name,
+        linktype,
+        data: packet,
+        selected: context.frame_selected,
+        serial_request: context.serial_request,
+    }));
+}
+
+/// フィルタに一致したフレームを --write のファイルに書く
+fn write_frame(context: &mut Context, linktype: u32, original_length: u32, packet: &[u8]) {
+    if !context.frame_selected {
+        return;
+    }
+    if let Some(writer) = &mut context.pcap_writer {
+        match writer.write_record(linktype, context.timestamp, original_length, packet) {
+            Ok(true) => {}
+            /* 知らせるのは最初の 1 つだけで、数は close_pcap_writer で示す */
+            Ok(false) if writer.dropped() == 1 => eprintln!(
+                "packetdump: frames of link type {} are not written to {}, which has link type {}",
+                linktype,
+                context.write_path.as_deref().unwrap_or_default(),
+                writer.linktype()
+            ),
+            Ok(false) => {}
+            Err(e) => fail(&format!("unable to write packet: {}", e)),
+        }
+    }
+}
+
+/// --write のファイルを作り、入力のリンクタイプでファイルヘッダを書く
+fn open_pcap_writer(context: &mut Context, linktype: u32) {
+    if let Some(path) = &context.write_path {
+        let file = File::create(path)
+            .unwrap_or_else(|e| fail(&format!("unable to create {}: {}", path, e)));
+        let writer = PcapWriter::new(BufWriter::new(file), linktype)
+            .unwrap_or_else(|e| fail(&format!("unable to write {}: {}", path, e)));
+        context.pcap_writer = Some(writer);
+    }
+}
+
+/// --write のファイルを書き切り、リンクタイプが違って書かなかったフレームがあればその数を示す
+fn close_pcap_writer(context: &mut Context) {
+    if let Some(writer) = &mut context.pcap_writer {
+        writer
+            .flush()
+            .unwrap_or_else(|e| fail(&format!("unable to write packet: {}", e)));
+        if writer.dropped() > 0 {
+            eprintln!(
+                "packetdump: {} frames were not written to {}",
+                writer.dropped(),
+                context.write_path.as_deref().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// pcap ファイルを読み、リンクタイプに合わせて各レコードを解析する
+fn read_capture(context: &mut Context, path: &str) {
+    let file = File::open(path)
+        .unwrap_or_else(|e| fail(&format!("unable to open {}: {}", path, e)));
+    let mut reader = PcapReader::new(BufReader::new(file))
+        .unwrap_or_else(|e| fail(&format!("unable to read {}: {}", path, e)));
+    let linktype = LinkType::from_pcap(reader.linktype())
+        .unwrap_or_else(|| fail(&format!("unhandled link type: {}", reader.linktype())));
+    open_pcap_writer(context, reader.linktype());
+    loop {
+        match reader.next_record() {
+            Ok(Some(record)) => {
+                context.timestamp = record.timestamp;
+                handle_captured_frame(context, path, linktype, &record.data);
+                write_frame(context, reader.linktype(), record.original_length, &record.data);
+            }
+            Ok(None) => break,
+            Err(e) => fail(&format!("unable to read {}: {}", path, e)),
+        }
+    }
+    close_pcap_writer(context);
+}
+
+/// ライブキャプチャで受け取るフレームのリンクタイプと、その前に付いているバイト数
+fn live_link_type(interface: &NetworkInterface) -> (LinkType, usize) {
+    if cfg!(any(target_os = "macos", target_os = "ios"))
+        && interface.is_up()
+        && !interface.is_broadcast()
+        && interface.is_loopback()
+    {
+        // The pnet code for BPF loopback adds a zero'd out Ethernet header
+        (LinkType::Raw, 14)
+    } else if interface.is_point_to_point() && !interface.is_loopback() && interface.mac.is_none() {
+        // Maybe is TUN interface
+        (LinkType::Raw, 0)
+    } else {
+        (LinkType::Ethernet, 0)
+    }
+}
+
+/// 引数や入力ファイルの誤りを表示して終了する
+fn fail(message: &str) -> ! {
+    eprintln!("packetdump: {}", message);
+    process::exit(1);
+}
+
+/// オプションから解析の設定を作る
+fn configure(options: &Options) -> Context {
+    let mut context = Context::new();
+    for (port, framing) in &options.modbus_ports {
+        context.modbus_ports.insert(*port, *framing);
+    }
+    if let Some(path) = &options.keylog {
+        let keylog = KeyLog::open(path)
+            .unwrap_or_else(|e| fail(&format!("unable to read {}: {}", path, e)));
+        context.keylog = Some(keylog);
+    }
+    if let Some(expression) = &options.filter {
+        let filter = Filter::parse(expression)
+            .unwrap_or_else(|e| fail(&format!("invalid filter: {}", e)));
+        context.filter = Some(filter);
+    }
+    if let Some(expression) = &options.display_filter {
+        let display_filter = DisplayFilter::parse(expression)
+            .unwrap_or_else(|e| fail(&format!("invalid display filter: {}", e)));
+        context.display_filter = Some(display_filter);
+    }
+    if let Some(path) = &options.register_map {
+        let register_map = RegisterMap::open(path)
+            .unwrap_or_else(|e| fail(&format!("unable to read {}: {}", path, e)));
+        context.register_map = Some(register_map);
+    }
+    if (options.tree || options.hex_dump) && options.format != Format::Text {
+        fail("-v and -x are only for --format text");
+    }
+    context.write_path = options.write.clone();
+    context.sink = match (&options.command, options.format) {
+        (Command::Stats { .. }, _) => Box::new(Statistics::new()),
+        (_, Format::JsonLines) => Box::new(JsonSink),
+        (_, Format::Csv) => Box::new(CsvSink::new(options.time)),
+        (_, Format::Text) => {
+            let verbosity = match (&options.command, options.verbosity) {
+                (_, Some(verbosity)) => verbosity,
+                (Command::Export { .. }, None) => Verbosity::Modbus,
+                (_, None) => Verbosity::Full,
+            };
+            Box::new(TextSink::new(
+                verbosity,
+                options.tree,
+                options.hex_dump,
+                context.modbus_ports.clone(),
+                options.time,
+            ))
         }
     };
-    let interface_names_match = |iface: &NetworkInterface| iface.name == iface_name;
-
-    // Find the network interface with the provided name
-    let interfaces = datalink::interfaces();
-    let interface = interfaces
-        .into_iter()
-        .filter(interface_names_match)
-        .next()
-        .unwrap_or_else(|| panic!("No such network interface: {}", iface_name));
-
-    // Create a channel to receive on
-    let (_, mut rx) = match datalink::channel(&interface, Default::default()) {
-        Ok(Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => panic!("packetdump: unhandled channel type:"),
-        Err(e) => panic!("packetdump: unable to create channel: {}", e),
+    context
+}
+
+/// インタフェースのフラグ (ifconfig の表記に合わせる)
+fn interface_flags(interface: &NetworkInterface) -> String {
+    let flags = [
+        (interface.is_up(), "UP"),
+        (interface.is_broadcast(), "BROADCAST"),
+        (interface.is_loopback(), "LOOPBACK"),
+        (interface.is_point_to_point(), "POINTOPOINT"),
+        (interface.is_multicast(), "MULTICAST"),
+    ];
+    let names: Vec<&str> = flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect();
+    names.join(",")
+}
+
+fn list_interfaces() {
+    for interface in datalink::interfaces() {
+        if interface.description.is_empty() {
+            println!("{}: flags=<{}>", interface.name, interface_flags(&interface));
+        } else {
+            println!(
+                "{} ({}): flags=<{}>",
+                interface.name,
+                interface.description,
+                interface_flags(&interface)
+            );
+        }
+        if let Some(mac) = interface.mac {
+            println!("    ether {}", mac);
+        }
+        for ip in &interface.ips {
+            let family = match ip.ip() {
+                IpAddr::V4(..) => "inet",
+                _ => "inet6",
+            };
+            println!("    {} {}", family, ip);
+        }
+    }
+}
+
+/// 受信スレッドから解析するスレッドに渡すフレーム (インタフェースの番号, 受信時刻, データ)
+type Received = (usize, Duration, Vec<u8>);
+
+/// インタフェースごとに受信スレッドを立て、受け取った順に 1 つの流れとして解析する
+fn capture_live(context: &mut Context, names: &[String]) -> ! {
+    use pnet::datalink::Channel::Ethernet;
+
+    let available = datalink::interfaces();
+    let interfaces: Vec<NetworkInterface> = if names.iter().any(|name| name == "any") {
+        available.into_iter().filter(|iface| iface.is_up()).collect()
+    } else {
+        names
+            .iter()
+            .map(|name| {
+                available
+                    .iter()
+                    .find(|iface| &iface.name == name)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        fail(&format!(
+                            "no such network interface: {} (see packetdump list-interfaces)",
+                            name
+                        ))
+                    })
+            })
+            .collect()
     };
+    if interfaces.is_empty() {
+        fail("no network interface is up");
+    }
 
-    loop {
-        let mut buf: [u8; 1600] = [0u8; 1600];
-        let mut fake_ethernet_frame = MutableEthernetPacket::new(&mut buf[..]).unwrap();
-        match rx.next() {
-            Ok(packet) => {
-                let payload_offset;
-                if cfg!(any(target_os = "macos", target_os = "ios"))
-                    && interface.is_up()
-                    && !interface.is_broadcast()
-                    && ((!interface.is_loopback() && interface.is_point_to_point())
-                        || interface.is_loopback())
-                {
-                    if interface.is_loopback() {
-                        // The pnet code for BPF loopback adds a zero'd out Ethernet header
-                        payload_offset = 14;
-                    } else {
-                        // Maybe is TUN interface
-                        payload_offset = 0;
-                    }
-                    if packet.len() > payload_offset {
-                        let version = Ipv4Packet::new(&packet[payload_offset..])
-                            .unwrap()
-                            .get_version();
-                        if version == 4 {
-                            fake_ethernet_frame.set_destination(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake_ethernet_frame.set_source(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake_ethernet_frame.set_ethertype(EtherTypes::Ipv4);
-                            fake_ethernet_frame.set_payload(&packet[payload_offset..]);
-                            handle_ethernet_frame(&interface, &fake_ethernet_frame.to_immutable());
-                            continue;
-                        } else if version == 6 {
-                            fake_ethernet_frame.set_destination(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake_ethernet_frame.set_source(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake_ethernet_frame.set_ethertype(EtherTypes::Ipv6);
-                            fake_ethernet_frame.set_payload(&packet[payload_offset..]);
-                            handle_ethernet_frame(&interface, &fake_ethernet_frame.to_immutable());
-                            continue;
-                        }
+    let (sender, receiver) = mpsc::channel::<Received>();
+    let mut linktypes = Vec::new();
+    for (index, interface) in interfaces.iter().enumerate() {
+        // Create a channel to receive on
+        let mut rx = match datalink::channel(interface, Default::default()) {
+            Ok(Ethernet(_, rx)) => rx,
+            Ok(_) => fail(&format!("unhandled channel type: {}", interface.name)),
+            Err(e) => fail(&format!("unable to capture on {}: {}", interface.name, e)),
+        };
+        let (linktype, payload_offset) = live_link_type(interface);
+        linktypes.push(linktype);
+        /* カーネルで捨てられなかったフレームも handle_captured_frame でもう一度評価する */
+        if let (Some(filter), LinkType::Ethernet, 0) = (&context.filter, linktype, payload_offset) {
+            let attached = match filter.compile() {
+                Some(program) => filter::attach(&program, interface.index)
+                    .map_err(|e| e.to_string()),
+                None => Err("too long for BPF".to_string()),
+            };
+            if let Err(e) = attached {
+                eprintln!(
+                    "packetdump: capture filter is not attached to {}, filtering in software: {}",
+                    interface.name, e
+                );
+            }
+        }
+        let sender = sender.clone();
+        let name = interface.name.clone();
+        thread::spawn(move || loop {
+            match rx.next() {
+                Ok(packet) => {
+                    let timestamp = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    if packet.len() >= payload_offset
+                        && sender
+                            .send((index, timestamp, packet[payload_offset..].to_vec()))
+                            .is_err()
+                    {
+                        break;
                     }
                 }
-                handle_ethernet_frame(&interface, &EthernetPacket::new(packet).unwrap());
+                Err(e) => fail(&format!("unable to receive packet on {}: {}", name, e)),
             }
-            Err(e) => panic!("packetdump: unable to receive packet: {}", e),
+        });
+    }
+    drop(sender);
+    /* インタフェースごとにリンクタイプが違えば、最初のインタフェースのものだけを書く */
+    open_pcap_writer(context, linktypes[0].to_pcap());
+
+    for (index, timestamp, packet) in receiver {
+        context.timestamp = timestamp;
+        handle_captured_frame(context, &interfaces[index].name, linktypes[index], &packet);
+        write_frame(context, linktypes[index].to_pcap(), packet.len() as u32, &packet);
+        /* 中断されても書いたところまで読めるようにする */
+        if let Some(writer) = &mut context.pcap_writer {
+            writer
+                .flush()
+                .unwrap_or_else(|e| fail(&format!("unable to write packet: {}", e)));
+        }
+    }
+    close_pcap_writer(context);
+    context.sink.finish();
+    fail("capture stopped on every interface");
+}
+
+fn main() {
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("packetdump: {} (see packetdump help)", e);
+            process::exit(2);
+        }
+    };
+    let mut context = configure(&options);
+    match &options.command {
+        Command::Live { interfaces } => capture_live(&mut context, interfaces),
+        Command::Read { path } | Command::Export { path } | Command::Stats { path } => {
+            read_capture(&mut context, path);
+            context.sink.finish();
         }
+        Command::ListInterfaces => list_interfaces(),
+        Command::Help => println!("{}", cli::USAGE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use output::Time;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Malformed と ADU のイベントを文字列にして残す
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Sink for Recorder {
+        fn event(&mut self, _: &Time, event: &Event) {
+            let line = match event {
+                Event::Malformed { what, .. } => format!("malformed: {}", what),
+                Event::ModbusRequest(adu) | Event::ModbusReply(adu) | Event::Exception(adu) => {
+                    format!("adu {}: {}", adu.fields.transaction, adu.errors.join(", "))
+                }
+                _ => return,
+            };
+            self.0.borrow_mut().push(line);
+        }
+    }
+
+    fn recording_context() -> (Context, Rc<RefCell<Vec<String>>>) {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut context = Context::new();
+        context.sink = Box::new(Recorder(events.clone()));
+        (context, events)
     }
-}
\ No newline at end of file
+
+    fn flow() -> Flow {
+        Flow {
+            vlans: Vec::new(),
+            source: "10.0.0.1".parse().unwrap(),
+            source_port: 50000,
+            destination: "10.0.0.2".parse().unwrap(),
+            destination_port: 502,
+        }
+    }
+
+    #[test]
+    fn pending_requests() {
+        let mut context = Context::new();
+        let flow = flow();
+        let request = |transaction| AduFields {
+            transaction,
+            unit: 1,
+            function: 3,
+            is_request: true,
+            ..AduFields::default()
+        };
+        for transaction in 0..MAX_PENDING_REQUESTS as u16 {
+            context.timestamp = Duration::from_millis(transaction as u64);
+            context.pair_transaction(Some(&flow), &mut request(transaction));
+        }
+        assert_eq!(context.pending_requests.len(), MAX_PENDING_REQUESTS);
+        /* 同じキーのリクエストは置き換えるだけで捨てない */
+        context.pair_transaction(Some(&flow), &mut request(0));
+        assert_eq!(context.pending_requests.len(), MAX_PENDING_REQUESTS);
+        /* 時間内のものだけで上限に達していれば、いちばん古いものを捨てる */
+        context.pair_transaction(Some(&flow), &mut request(MAX_PENDING_REQUESTS as u16));
+        assert_eq!(context.pending_requests.len(), MAX_PENDING_REQUESTS);
+        let reply = |transaction| AduFields {
+            is_request: false,
+            ..request(transaction)
+        };
+        assert!(context.pair_transaction(Some(&flow.reversed()), &mut reply(1)).is_none());
+        assert!(context.pair_transaction(Some(&flow.reversed()), &mut reply(0)).is_some());
+        assert!(context.pair_transaction(Some(&flow.reversed()), &mut reply(2)).is_some());
+        /* 時間切れのもの (3 から 100 の 98 個) があればそちらだけを捨てる */
+        context.timestamp = REQUEST_TIMEOUT + Duration::from_millis(100);
+        context.pair_transaction(Some(&flow), &mut request(1));
+        context.pair_transaction(Some(&flow), &mut request(2));
+        assert_eq!(context.pending_requests.len(), MAX_PENDING_REQUESTS);
+        context.pair_transaction(Some(&flow), &mut request(MAX_PENDING_REQUESTS as u16 + 1));
+        assert_eq!(context.pending_requests.len(), MAX_PENDING_REQUESTS - 98 + 1);
+    }
+
+    #[test]
+    fn adu_errors() {
+        let (mut context, events) = recording_context();
+        let flow = flow();
+        context.adu_errors.push("RTU CRC error".to_string());
+        handle_modbus_packet(&mut context, Some(&flow), &to_mbap(7, 1, &[3, 0, 0, 0, 1]), true);
+        /* MBAP ヘッダとして読めなくてもフレームの誤りは出し、次の ADU に持ち越さない */
+        context.adu_errors.push("ASCII LRC error".to_string());
+        handle_modbus_packet(&mut context, Some(&flow), &[0, 8, 0], true);
+        handle_modbus_packet(&mut context, Some(&flow), &to_mbap(9, 1, &[3, 0, 0, 0, 1]), true);
+        assert_eq!(
+            *events.borrow(),
+            vec!["adu 7: RTU CRC error", "malformed: ASCII LRC error", "adu 9: "]
+        );
+        assert!(context.adu_errors.is_empty());
+    }
+
+    /// 次のヘッダと 8 バイト単位の長さで、中身を 0 で埋めた拡張ヘッダ
+    fn extension(next_header: u8, length: u8) -> Vec<u8> {
+        let mut header = vec![0u8; (length as usize + 1) * 8];
+        header[0] = next_header;
+        header[1] = length;
+        header
+    }
+
+    #[test]
+    fn ipv6_extensions() {
+        let udp = [0x01, 0xf6, 0x01, 0xf6];
+        /* Hop-by-Hop, Routing, Destination Options の順に飛ばす */
+        let packet = [extension(43, 0), extension(60, 1), extension(17, 0), udp.to_vec()].concat();
+        assert_eq!(
+            skip_ipv6_extensions(IpNextHeaderProtocols::Hopopt, &packet),
+            Some((IpNextHeaderProtocols::Udp, &udp[..]))
+        );
+        /* 拡張ヘッダがなければそのまま */
+        assert_eq!(
+            skip_ipv6_extensions(IpNextHeaderProtocols::Tcp, &udp),
+            Some((IpNextHeaderProtocols::Tcp, &udp[..]))
+        );
+        /* Fragment ヘッダではそこで止まる */
+        let fragment = [17, 0, 0, 1, 0, 0, 0, 1];
+        let packet = [extension(44, 0), fragment.to_vec()].concat();
+        assert_eq!(
+            skip_ipv6_extensions(IpNextHeaderProtocols::Ipv6Opts, &packet),
+            Some((IpNextHeaderProtocols::Ipv6Frag, &fragment[..]))
+        );
+        /* 組み立てた後のデータの先頭の Destination Options */
+        let packet = [extension(6, 2), udp.to_vec()].concat();
+        assert_eq!(
+            skip_ipv6_extensions(IpNextHeaderProtocols::Ipv6Opts, &packet),
+            Some((IpNextHeaderProtocols::Tcp, &udp[..]))
+        );
+        /* 途中で切れたヘッダ */
+        let packet = [extension(43, 0), extension(17, 1)].concat();
+        assert_eq!(skip_ipv6_extensions(IpNextHeaderProtocols::Hopopt, &packet[..23]), None);
+        assert_eq!(skip_ipv6_extensions(IpNextHeaderProtocols::Hopopt, &packet[..9]), None);
+        assert_eq!(skip_ipv6_extensions(IpNextHeaderProtocols::Hopopt, &packet[..7]), None);
+        assert_eq!(skip_ipv6_extensions(IpNextHeaderProtocols::Ipv6Route, &[]), None);
+    }
+}
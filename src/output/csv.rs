@@ -0,0 +1,160 @@
+//! --format csv の出力。リクエストと組にできた応答から、読み書きされたコイル・レジスタの値を
+//! 1 つずつ 1 行にする。読み出しは応答の値を、書き込みは正常応答が返ったリクエストの値を使う。
+//! timestamp の列は --time に合わせて UTC の日時か秒にする。
+
+use super::{device_name, Event, Sink, Time};
+use crate::cli::TimeFormat;
+use crate::packet::fields::AduFields;
+use crate::packet::modbus_tcp::*;
+
+const HEADER: &str = "timestamp,device,unit,table,address,value,operation,function";
+
+/// 応答と、組になったリクエストから行を作る。リクエストや例外応答、形の崩れた応答では行はない
+fn rows(
+    timestamp: &str,
+    device: &str,
+    reply: &AduFields,
+    request: Option<&AduFields>,
+) -> Vec<String> {
+    let (request, table, address) = match (request, reply.table(), reply.address) {
+        (Some(request), Some(table), Some(address))
+            if !reply.is_request && reply.exception_code.is_none() && reply.malformed.is_none() =>
+        {
+            (request, table, address)
+        }
+        _ => return Vec::new(),
+    };
+    let (operation, values) = match FunctionField(reply.function) {
+        FunctionFieldValues::ReadCoilStatus
+        | FunctionFieldValues::ReadInputStatus
+        | FunctionFieldValues::ReadHoldingRegister
+        | FunctionFieldValues::ReadInputRegister => ("read", &reply.values),
+        _ => ("write", &request.values),
+    };
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            format!(
+                "{},{},{},{},{},{},{},{}",
+                timestamp,
+                device,
+                reply.unit,
+                table.name(),
+                address as usize + i,
+                value,
+                operation,
+                reply.function
+            )
+        })
+        .collect()
+}
+
+/// 応答ごとに値の行を書く
+pub struct CsvSink {
+    /// timestamp の列の表し方
+    time: TimeFormat,
+}
+
+impl CsvSink {
+    /// 見出しの行を書いて作る
+    pub fn new(time: TimeFormat) -> CsvSink {
+        println!("{}", HEADER);
+        CsvSink { time }
+    }
+}
+
+impl Sink for CsvSink {
+    fn event(&mut self, time: &Time, event: &Event) {
+        if let Event::ModbusReply(adu) = event {
+            let device = device_name(adu.flow, adu.fields.is_request);
+            let timestamp = time.display(self.time);
+            for row in rows(&timestamp, &device, adu.fields, adu.request) {
+                println!("{}", row);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn decode(pdu: &[u8], is_request: bool) -> AduFields {
+        AduFields::decode(&to_mbap(1, 1, pdu), is_request).unwrap()
+    }
+
+    /// 応答に番号のない読み出しは、組にしたリクエストから補ってから行にする
+    fn pair(request: &[u8], reply: &[u8]) -> Vec<String> {
+        let request = decode(request, true);
+        let mut reply = decode(reply, false);
+        reply.pair(&request, Duration::from_millis(1));
+        rows("1.5", "10.0.0.2", &reply, Some(&request))
+    }
+
+    #[test]
+    fn reads() {
+        assert_eq!(
+            pair(&[3, 0, 10, 0, 2], &[3, 4, 0, 1, 0x12, 0x34]),
+            vec![
+                "1.5,10.0.0.2,1,holding_register,10,1,read,3",
+                "1.5,10.0.0.2,1,holding_register,11,4660,read,3",
+            ]
+        );
+        assert_eq!(
+            pair(&[4, 0, 0, 0, 1], &[4, 2, 0xff, 0xff]),
+            vec!["1.5,10.0.0.2,1,input_register,0,65535,read,4"]
+        );
+        assert_eq!(
+            pair(&[1, 0, 20, 0, 3], &[1, 1, 0b101]),
+            vec![
+                "1.5,10.0.0.2,1,coil,20,1,read,1",
+                "1.5,10.0.0.2,1,coil,21,0,read,1",
+                "1.5,10.0.0.2,1,coil,22,1,read,1",
+            ]
+        );
+        assert_eq!(
+            pair(&[2, 0, 7, 0, 1], &[2, 1, 0]),
+            vec!["1.5,10.0.0.2,1,discrete_input,7,0,read,2"]
+        );
+    }
+
+    #[test]
+    fn writes() {
+        /* 書き込みの応答は値を持たないので、リクエストの値を使う */
+        assert_eq!(
+            pair(&[16, 0, 5, 0, 2, 4, 0, 7, 0, 8], &[16, 0, 5, 0, 2]),
+            vec![
+                "1.5,10.0.0.2,1,holding_register,5,7,write,16",
+                "1.5,10.0.0.2,1,holding_register,6,8,write,16",
+            ]
+        );
+        assert_eq!(
+            pair(&[6, 0, 1, 0, 9], &[6, 0, 1, 0, 9]),
+            vec!["1.5,10.0.0.2,1,holding_register,1,9,write,6"]
+        );
+        assert_eq!(
+            pair(&[15, 0, 30, 0, 2, 1, 0b10], &[15, 0, 30, 0, 2]),
+            vec![
+                "1.5,10.0.0.2,1,coil,30,0,write,15",
+                "1.5,10.0.0.2,1,coil,31,1,write,15",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_rows() {
+        let request = decode(&[3, 0, 10, 0, 2], true);
+        let mut reply = decode(&[3, 4, 0, 1, 0, 2], false);
+        /* 番号がわからない応答 */
+        assert!(rows("0", "-", &reply, Some(&request)).is_empty());
+        reply.pair(&request, Duration::from_millis(1));
+        assert!(rows("0", "-", &reply, None).is_empty());
+        assert!(rows("0", "-", &request, Some(&request)).is_empty());
+        assert!(pair(&[3, 0, 10, 0, 2], &[0x83, 2]).is_empty());
+        assert!(pair(&[3, 0, 10, 0, 2], &[3, 4, 0, 1]).is_empty());
+        /* 表のないファンクション */
+        assert!(pair(&[8, 0, 0, 0xa5, 0x37], &[8, 0, 0, 0xa5, 0x37]).is_empty());
+    }
+}
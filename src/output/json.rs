@@ -0,0 +1,239 @@
+//! --format jsonl の出力。デコードした ADU ごとに 1 行の JSON オブジェクトを書く。
+//! キーは次のとおりで、値がないものは null (配列は空) にする。キーを消したり型を変えたりしない。
+//!
+//! | キー            | 型              | 内容                                                   |
+//! |-----------------|-----------------|--------------------------------------------------------|
+//! | timestamp       | number          | キャプチャ時刻 (1970-01-01 からの秒, マイクロ秒まで)   |
+//! | relative        | number          | 最初のフレームからの秒                                 |
+//! | delta           | number / null   | 同じフローの直前のフレームからの秒                     |
+//! | interface       | string          | 受信したインタフェース (pcap ファイルならそのパス)     |
+//! | vlans           | array of number | VLAN ID (外側のタグから順に)                           |
+//! | src, dst        | string / null   | "ip:port" (シリアルバスでは null)                      |
+//! | direction       | string          | "request" か "reply"                                   |
+//! | transaction     | number          | MBAP の transaction ID (シリアルでは 0)                |
+//! | unit            | number          | unit ID (シリアルではスレーブアドレス)                 |
+//! | function        | number          | ファンクションコード (例外応答でも最上位ビットなし)    |
+//! | function_name   | string / null   | "read_holding_register" など                           |
+//! | address         | number / null   | PDU 上の 0 始まりの先頭番号                            |
+//! | reference       | number / null   | 40001 形式の番号                                       |
+//! | quantity        | number / null   | 個数                                                   |
+//! | values          | array of number | 読み書きしたコイル (0/1) またはレジスタの値            |
+//! | tags            | array of object | --register-map で名前を付けた値 (下記)                 |
+//! | exception_code  | number / null   | 例外応答の例外コード                                   |
+//! | byte_count      | number / null   | PDU の byte count                                      |
+//! | data            | string / null   | byte count に続くデータの 16 進                        |
+//! | data_value      | number / null   | Force Single Coil・Preset Single Register・            |
+//! |                 |                 | Diagnostics の 16 ビットのデータ (コイルは 0xff00 など)|
+//! | sub_code        | number / null   | Diagnostics のサブファンクション                       |
+//! | status          | number / null   | Fetch Communication Event Counter (Log) の応答の status|
+//! | event_counter   | number / null   | 同じ応答の event counter                               |
+//! | message_counter | number / null   | Fetch Communication Event Counter Log の応答の         |
+//! |                 |                 | message counter                                        |
+//! | latency_ms      | number / null   | 対応するリクエストからの応答時間                       |
+//! | pdu             | string          | ファンクションコードからの PDU の 16 進                |
+//! | errors          | array of string | CRC・LRC の不一致, "malformed: ...", 仕様違反          |
+//!
+//! "malformed: ..." は PDU がファンクションの形に足りないもので、読めなかったフィールドは null (配列は空) になる。
+//!
+//! tags の要素は {"name": string, "value": number / null, "units": string, "description": string} で、
+//! 書き込みの応答では正常応答が返ったリクエストの値を示す。value はスケールを適用した値 (f32 の NaN などは null)。
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+use super::{seconds, Event, Sink, Time};
+use crate::packet::fields::AduFields;
+use crate::packet::modbus_tcp::{FunctionField, MBAP_HEADER_LENGTH};
+use crate::register_map::{number, TagValue};
+use crate::Flow;
+
+/// JSON の文字列リテラルにする
+fn string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".to_string(),
+    }
+}
+
+fn array<T: ToString>(values: &[T]) -> String {
+    let items: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// ADU 1 つ分の行
+fn adu_line(
+    time: &Time,
+    interface: &str,
+    flow: Option<&Flow>,
+    fields: &AduFields,
+    pdu: &[u8],
+    tags: &[TagValue],
+    errors: &[String],
+) -> String {
+    let tags: Vec<String> = tags
+        .iter()
+        .map(|tag| {
+            format!(
+                "{{\"name\":{},\"value\":{},\"units\":{},\"description\":{}}}",
+                string(&tag.name),
+                optional(Some(tag.value).filter(|v| v.is_finite()).map(number)),
+                string(&tag.units),
+                string(&tag.description)
+            )
+        })
+        .collect();
+    let endpoint = |ip, port| Some(string(&SocketAddr::new(ip, port).to_string()));
+    let errors: Vec<String> = errors.iter().map(|e| string(e)).collect();
+    format!(
+        concat!(
+            "{{\"timestamp\":{},\"relative\":{},\"delta\":{},",
+            "\"interface\":{},\"vlans\":{},\"src\":{},\"dst\":{},",
+            "\"direction\":{},\"transaction\":{},\"unit\":{},\"function\":{},\"function_name\":{},",
+            "\"address\":{},\"reference\":{},\"quantity\":{},\"values\":{},\"tags\":[{}],",
+            "\"exception_code\":{},\"byte_count\":{},\"data\":{},\"data_value\":{},\"sub_code\":{},",
+            "\"status\":{},\"event_counter\":{},\"message_counter\":{},",
+            "\"latency_ms\":{},\"pdu\":{},\"errors\":[{}]}}"
+        ),
+        seconds(time.absolute),
+        seconds(time.relative),
+        optional(time.delta.map(seconds)),
+        string(interface),
+        array(flow.map_or(&[][..], |flow| &flow.vlans[..])),
+        optional(flow.and_then(|flow| endpoint(flow.source, flow.source_port))),
+        optional(flow.and_then(|flow| endpoint(flow.destination, flow.destination_port))),
+        string(if fields.is_request { "request" } else { "reply" }),
+        fields.transaction,
+        fields.unit,
+        fields.function,
+        optional(FunctionField(fields.function).name().map(string)),
+        optional(fields.address),
+        optional(fields.reference()),
+        optional(fields.quantity),
+        array(&fields.values),
+        tags.join(","),
+        optional(fields.exception_code),
+        optional(fields.byte_count),
+        optional(Some(&fields.data).filter(|data| !data.is_empty()).map(|data| string(&hex(data)))),
+        optional(fields.data_value),
+        optional(fields.sub_code),
+        optional(fields.status),
+        optional(fields.event_counter),
+        optional(fields.message_counter),
+        optional(fields.latency.map(|latency| latency.as_secs_f64() * 1000.0)),
+        string(&hex(pdu)),
+        errors.join(",")
+    )
+}
+
+/// ADU ごとに 1 行を書く。errors には CRC・LRC の不一致に、PDU の形の誤りと仕様違反を加える
+pub struct JsonSink;
+
+impl Sink for JsonSink {
+    fn event(&mut self, time: &Time, event: &Event) {
+        let adu = match event {
+            Event::ModbusRequest(adu) | Event::ModbusReply(adu) | Event::Exception(adu) => adu,
+            _ => return,
+        };
+        let mut errors = adu.errors.to_vec();
+        errors.extend(adu.fields.malformed.iter().map(|m| format!("malformed: {}", m)));
+        errors.extend(adu.violations.iter().map(|v| format!("spec violation: {}", v)));
+        println!(
+            "{}",
+            adu_line(
+                time,
+                adu.interface,
+                adu.flow,
+                adu.fields,
+                &adu.packet[MBAP_HEADER_LENGTH..],
+                adu.tags,
+                &errors
+            )
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::modbus_tcp::to_mbap;
+    use std::time::Duration;
+
+    fn time() -> Time {
+        Time {
+            absolute: Duration::new(1_700_000_000, 250_000_000),
+            relative: Duration::from_millis(1500),
+            delta: None,
+        }
+    }
+
+    fn flow() -> Flow {
+        Flow {
+            vlans: vec![10],
+            source: "10.0.0.1".parse().unwrap(),
+            source_port: 40000,
+            destination: "10.0.0.2".parse().unwrap(),
+            destination_port: 502,
+        }
+    }
+
+    fn line(pdu: &[u8], is_request: bool, errors: &[String]) -> String {
+        let fields = AduFields::decode(&to_mbap(7, 1, pdu), is_request).unwrap();
+        adu_line(&time(), "eth0", Some(&flow()), &fields, pdu, &[], errors)
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            line(&[8, 0, 0, 0xa5, 0x37], true, &[]),
+            concat!(
+                "{\"timestamp\":1700000000.250000,\"relative\":1.500000,\"delta\":null,",
+                "\"interface\":\"eth0\",\"vlans\":[10],\"src\":\"10.0.0.1:40000\",\"dst\":\"10.0.0.2:502\",",
+                "\"direction\":\"request\",\"transaction\":7,\"unit\":1,\"function\":8,\"function_name\":\"diagnostics\",",
+                "\"address\":null,\"reference\":null,\"quantity\":null,\"values\":[],\"tags\":[],",
+                "\"exception_code\":null,\"byte_count\":null,\"data\":null,\"data_value\":42295,\"sub_code\":0,",
+                "\"status\":null,\"event_counter\":null,\"message_counter\":null,",
+                "\"latency_ms\":null,\"pdu\":\"080000a537\",\"errors\":[]}"
+            )
+        );
+        /* イベントログの応答はカウンタとイベントを持つ */
+        let log = line(&[12, 8, 0xff, 0xff, 0, 3, 0, 9, 0x20, 0x00], false, &[]);
+        assert!(log.contains(concat!(
+            "\"byte_count\":8,\"data\":\"2000\",\"data_value\":null,\"sub_code\":null,",
+            "\"status\":65535,\"event_counter\":3,\"message_counter\":9,"
+        )));
+    }
+
+    #[test]
+    fn escaped_errors() {
+        let errors = vec![
+            "RTU CRC error: \"a\\b\"".to_string(),
+            "line\nbreak\ttab\u{1}".to_string(),
+        ];
+        assert!(line(&[3, 0, 0, 0, 1], true, &errors).ends_with(concat!(
+            "\"errors\":[\"RTU CRC error: \\\"a\\\\b\\\"\",",
+            "\"line\\nbreak\\ttab\\u0001\"]}"
+        )));
+    }
+}
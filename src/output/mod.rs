@@ -0,0 +1,159 @@
+//! 解析した結果を出力する。main.rs の解析は見つけたものを Event として Sink に渡すだけで、
+//! 何をどの形式で書くかは Sink が決める。出力形式を増やすときは Sink を実装して configure で選ぶ。
+
+use std::time::Duration;
+
+use super::cli::TimeFormat;
+use super::link::LinkType;
+use super::packet::conformance::Violation;
+use super::packet::fields::AduFields;
+use super::register_map::TagValue;
+use super::Flow;
+
+pub mod csv;
+pub mod json;
+pub mod text;
+
+pub use self::csv::CsvSink;
+pub use self::json::JsonSink;
+pub use self::text::TextSink;
+
+/// フレームのキャプチャ時刻
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Time {
+    /// 1970-01-01 からの経過時間
+    pub absolute: Duration,
+    /// 最初のフレームからの経過時間
+    pub relative: Duration,
+    /// 同じフローの直前のフレームからの経過時間 (フローの最初のフレームでは None)
+    pub delta: Option<Duration>,
+}
+
+impl Time {
+    /// --time の表し方にする (none では UTC の日時)
+    pub fn display(&self, format: TimeFormat) -> String {
+        match format {
+            TimeFormat::Relative => seconds(self.relative),
+            TimeFormat::Delta => seconds(self.delta.unwrap_or_default()),
+            TimeFormat::Absolute | TimeFormat::Hidden => utc(self.absolute),
+        }
+    }
+}
+
+/// キャプチャしたフレーム 1 つ
+pub struct CapturedFrame<'a> {
+    /// 受信したインタフェース (pcap ファイルならそのパス)
+    pub interface: &'a str,
+    pub linktype: LinkType,
+    pub data: &'a [u8],
+    /// --filter と --display-filter に一致したか
+    pub selected: bool,
+    /// シリアルバスのフレームをリクエストと推定したか
+    pub serial_request: bool,
+}
+
+/// デコードした ADU 1 つ
+pub struct Adu<'a> {
+    pub interface: &'a str,
+    /// シリアルバスでは None
+    pub flow: Option<&'a Flow>,
+    pub fields: &'a AduFields,
+    /// 応答と組になったリクエスト
+    pub request: Option<&'a AduFields>,
+    /// MBAP ヘッダからの ADU (RTU・ASCII は MBAP ヘッダを付けたもの)
+    pub packet: &'a [u8],
+    /// CRC・LRC の不一致
+    pub errors: &'a [String],
+    /// 仕様の制限への違反
+    pub violations: &'a [Violation],
+    /// レジスタマップで名前を付けた値 (書き込みの応答ではリクエストの値)
+    pub tags: &'a [TagValue],
+}
+
+pub enum Event<'a> {
+    /// フレーム 1 つの解析が終わった
+    FrameSeen(CapturedFrame<'a>),
+    /// Modbus 以外も含む各層のパケット。modbus は Modbus のポートやシリアルバスのものか
+    Packet {
+        interface: &'a str,
+        summary: String,
+        modbus: bool,
+    },
+    /// ADU 以外の Modbus の通信についての説明 (TLS のハンドシェイクなど)
+    Note(String),
+    ModbusRequest(Adu<'a>),
+    ModbusReply(Adu<'a>),
+    /// 例外応答
+    Exception(Adu<'a>),
+    /// 解析できなかったもの。interface がなければ直前のパケットの中身
+    Malformed {
+        interface: Option<&'a str>,
+        what: String,
+        modbus: bool,
+    },
+    /// Modbus のポートへの TCP 接続 (flow はクライアントからサーバ向き)
+    FlowOpened {
+        interface: &'a str,
+        flow: &'a Flow,
+    },
+    FlowClosed {
+        interface: &'a str,
+        flow: &'a Flow,
+    },
+}
+
+/// 出力先
+pub trait Sink {
+    /// time は event を見つけたフレームのキャプチャ時刻
+    fn event(&mut self, time: &Time, event: &Event);
+
+    /// 入力が終わったときに呼ばれる (集計を出力するものなど)
+    fn finish(&mut self) {}
+}
+
+/// サーバ側 (リクエストの送信先、応答の送信元) のアドレス。シリアルバスでは "serial"
+pub fn device_name(flow: Option<&Flow>, is_request: bool) -> String {
+    match flow {
+        Some(flow) if is_request => flow.destination.to_string(),
+        Some(flow) => flow.source.to_string(),
+        None => "serial".to_string(),
+    }
+}
+
+/// 1970-01-01 からの日数を (年, 月, 日) にする
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    /* 3 月始まりの月 */
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// UTC の "2024-01-02 03:04:05.678901" (表計算ソフトがそのまま日時として読める形)
+fn utc(timestamp: Duration) -> String {
+    let seconds = timestamp.as_secs();
+    let (year, month, day) = civil_from_days(seconds / 86400);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        timestamp.subsec_micros()
+    )
+}
+
+/// 秒をマイクロ秒まで
+fn seconds(duration: Duration) -> String {
+    format!("{}.{:06}", duration.as_secs(), duration.subsec_micros())
+}
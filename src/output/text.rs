@@ -0,0 +1,352 @@
+//! --format text の出力。--verbosity の詳しさに合わせて 1 行の要約を書き、
+//! -v・-x ではフレームの各層の階層表示と 16 進ダンプを書く。
+//! インタフェース名で始まる行には、その前に --time の表し方でキャプチャ時刻を付ける。
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, IsTerminal};
+
+use super::{Adu, CapturedFrame, Event, Sink, Time};
+use crate::cli::{TimeFormat, Verbosity};
+use crate::packet::conformance::Violation;
+use crate::packet::fields::AduFields;
+use crate::packet::modbus_tcp::*;
+use crate::tree;
+use crate::{Flow, Framing};
+
+pub struct TextSink {
+    /// 表示する詳しさ
+    verbosity: Verbosity,
+    /// -v の階層表示をするときはその詳しさ (ふだんの行は出さない)
+    tree: Option<Verbosity>,
+    /// -x の 16 進ダンプ
+    hex_dump: bool,
+    /// 階層表示のためにフレームを読み直すときの Modbus のポート
+    modbus_ports: HashMap<u16, Framing>,
+    /// 行の先頭に付ける時刻の表し方
+    time: TimeFormat,
+}
+
+impl TextSink {
+    pub fn new(
+        verbosity: Verbosity,
+        tree: bool,
+        hex_dump: bool,
+        modbus_ports: HashMap<u16, Framing>,
+        time: TimeFormat,
+    ) -> TextSink {
+        let (verbosity, tree) = if tree && verbosity > Verbosity::Silent {
+            (Verbosity::Silent, Some(verbosity))
+        } else {
+            (verbosity, None)
+        };
+        TextSink {
+            verbosity,
+            tree,
+            hex_dump,
+            modbus_ports,
+            time,
+        }
+    }
+
+    /// 行の先頭に付ける時刻 (--time none では空)
+    fn stamp(&self, time: &Time) -> String {
+        match self.time {
+            TimeFormat::Hidden => String::new(),
+            format => format!("{} ", time.display(format)),
+        }
+    }
+
+    fn shows(&self, modbus: bool) -> bool {
+        self.verbosity >= if modbus { Verbosity::Modbus } else { Verbosity::Full }
+    }
+
+    fn print_adu(&self, time: &Time, adu: &Adu) {
+        if let (Some(request), false) = (adu.request, adu.fields.is_request) {
+            if self.verbosity == Verbosity::Summary {
+                println!("{}{}", self.stamp(time), transaction_summary(adu, request));
+            }
+        }
+        if self.shows(true) {
+            for error in adu.errors {
+                println!("    {}", error);
+            }
+            print_modbus_packet(adu.fields, adu.violations);
+            /* 書き込みの応答のタグはリクエストで示している */
+            if !adu.fields.values.is_empty() {
+                for tag in adu.tags {
+                    if tag.description.is_empty() {
+                        println!("    {}", tag);
+                    } else {
+                        println!("    {} ({})", tag, tag.description);
+                    }
+                }
+            }
+        }
+    }
+
+    /// -v の階層表示と -x の 16 進ダンプ
+    fn print_frame_details(&self, time: &Time, frame: &CapturedFrame) {
+        let dissection = tree::dissect(
+            frame.linktype,
+            frame.data,
+            &self.modbus_ports,
+            frame.serial_request,
+        );
+        if self.tree.unwrap_or(self.verbosity) < Verbosity::Full && dissection.modbus.is_none() {
+            return;
+        }
+        if self.tree.is_some() {
+            println!(
+                "{}[{}]: Frame; length: {}",
+                self.stamp(time),
+                frame.interface,
+                frame.data.len()
+            );
+            tree::print_tree(&dissection);
+        }
+        if self.hex_dump {
+            tree::print_hex_dump(frame.data, dissection.modbus, io::stdout().is_terminal());
+        }
+    }
+}
+
+impl Sink for TextSink {
+    fn event(&mut self, time: &Time, event: &Event) {
+        match event {
+            Event::FrameSeen(frame) => {
+                if (self.tree.is_some() || self.hex_dump) && frame.selected {
+                    self.print_frame_details(time, frame);
+                }
+            }
+            Event::Packet {
+                interface,
+                summary,
+                modbus,
+            } => {
+                if self.shows(*modbus) {
+                    println!("{}[{}]: {}", self.stamp(time), interface, summary);
+                }
+            }
+            Event::Note(note) => {
+                if self.shows(true) {
+                    println!("    {}", note);
+                }
+            }
+            Event::ModbusRequest(adu) | Event::ModbusReply(adu) | Event::Exception(adu) => {
+                self.print_adu(time, adu)
+            }
+            Event::Malformed {
+                interface,
+                what,
+                modbus,
+            } => {
+                if self.shows(*modbus) {
+                    match interface {
+                        Some(interface) => {
+                            println!("{}[{}]: Malformed {}", self.stamp(time), interface, what)
+                        }
+                        None => println!("    Malformed {}", what),
+                    }
+                }
+            }
+            Event::FlowOpened { interface, flow } => {
+                if self.shows(true) {
+                    println!(
+                        "{}[{}]: Modbus connection opened: {}",
+                        self.stamp(time),
+                        interface,
+                        endpoints(flow)
+                    );
+                }
+            }
+            Event::FlowClosed { interface, flow } => {
+                if self.shows(true) {
+                    println!(
+                        "{}[{}]: Modbus connection closed: {}",
+                        self.stamp(time),
+                        interface,
+                        endpoints(flow)
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn endpoints(flow: &Flow) -> String {
+    format!(
+        "{}:{} > {}:{}",
+        flow.source, flow.source_port, flow.destination, flow.destination_port
+    )
+}
+
+/// --verbosity summary で表示する、リクエストと応答の組の 1 行
+fn transaction_summary(reply: &Adu, request: &AduFields) -> String {
+    /* 応答のフローなので逆向きにしてクライアントから書く */
+    let endpoints = match reply.flow {
+        Some(flow) => endpoints(&flow.reversed()),
+        None => "serial".to_string(),
+    };
+    let (interface, tags) = (reply.interface, reply.tags);
+    let reply = reply.fields;
+    let mut line = format!(
+        "[{}]: {}; unit: {}; {}({})",
+        interface,
+        endpoints,
+        reply.unit,
+        FunctionField(reply.function).name().unwrap_or("unknown"),
+        reply.function
+    );
+    if let Some(reference) = reply.reference() {
+        line.push_str(&format!(", reference: {}", reference));
+    }
+    if let Some(quantity) = reply.quantity {
+        line.push_str(&format!(", quantity: {}", quantity));
+    }
+    /* 書き込みの応答には値がないのでリクエストの値を示す */
+    let values = if reply.values.is_empty() {
+        &request.values
+    } else {
+        &reply.values
+    };
+    match reply.exception_code {
+        Some(code) => line.push_str(&format!("; exception: {}", code)),
+        None if !values.is_empty() => line.push_str(&format!("; values: {:?}", values)),
+        None => {}
+    }
+    if !tags.is_empty() {
+        let tags: Vec<String> = tags.iter().map(|tag| tag.to_string()).collect();
+        line.push_str(&format!("; {}", tags.join(", ")));
+    }
+    if let Some(latency) = reply.latency {
+        line.push_str(&format!("; {:.3} ms", latency.as_secs_f64() * 1000.0));
+    }
+    line
+}
+
+/// 行に書くファンクションの名前。解析できないファンクションは None
+fn label(function: FunctionField) -> Option<&'static str> {
+    match function {
+        FunctionFieldValues::ReadCoilStatus => Some("read coil status"),
+        FunctionFieldValues::ReadInputStatus => Some("read input status"),
+        FunctionFieldValues::ReadHoldingRegister => Some("read holding register"),
+        FunctionFieldValues::ReadInputRegister => Some("read input register"),
+        FunctionFieldValues::ForceSingleCoil => Some("force single coil"),
+        FunctionFieldValues::PresetSingleRegister => Some("preset singe register"),
+        FunctionFieldValues::Diagnostics => Some("diagnostics"),
+        FunctionFieldValues::FetchCommunicationEventCounter => Some("fetch conmmunication event counter"),
+        FunctionFieldValues::FetchCommunicationEventCounterLog => Some("fetch conmmunication event counter log"),
+        FunctionFieldValues::ForceMultipleCoils => Some("force multiple coils"),
+        FunctionFieldValues::PresetMultipleRegisters => Some("preset multiple registers"),
+        FunctionFieldValues::ReportSlaveID => Some("freport_slave_id"),
+        _ => None,
+    }
+}
+
+/// 読めなかった値は "-"
+fn show<T: fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// ファンクションの名前と Request・Reply に続ける、ファンクションごとのフィールド
+fn details(fields: &AduFields) -> String {
+    let reference = show(fields.address);
+    if fields.exception {
+        return format!(
+            ", function: {}, exception code: {}",
+            fields.function,
+            show(fields.exception_code)
+        );
+    }
+    match (FunctionField(fields.function), fields.is_request) {
+        (FunctionFieldValues::ReadCoilStatus, true)
+        | (FunctionFieldValues::ReadInputStatus, true)
+        | (FunctionFieldValues::ReadHoldingRegister, true)
+        | (FunctionFieldValues::ReadInputRegister, true) => format!(
+            ", Reference Number: {}, Bit Count: {}",
+            reference,
+            show(fields.quantity)
+        ),
+        (FunctionFieldValues::ReadCoilStatus, false)
+        | (FunctionFieldValues::ReadInputStatus, false)
+        | (FunctionFieldValues::ReadHoldingRegister, false)
+        | (FunctionFieldValues::ReadInputRegister, false) => format!(
+            ", Byte Count: {}, Data: {:?}",
+            show(fields.byte_count),
+            fields.data
+        ),
+        (FunctionFieldValues::ForceSingleCoil, _) => format!(
+            ", Reference Number: {}, Data: {}",
+            reference,
+            show(fields.data_value.map(CoilValue))
+        ),
+        (FunctionFieldValues::PresetSingleRegister, _) => format!(
+            ", Reference Number: {}, Data: {}",
+            reference,
+            show(fields.data_value)
+        ),
+        (FunctionFieldValues::Diagnostics, _) => format!(
+            ", sub code: {}, Data: {}",
+            show(fields.sub_code),
+            show(fields.data_value)
+        ),
+        (FunctionFieldValues::FetchCommunicationEventCounter, false) => format!(
+            ", status: {}, event counter: {}",
+            show(fields.status),
+            show(fields.event_counter)
+        ),
+        (FunctionFieldValues::FetchCommunicationEventCounterLog, false) => format!(
+            ", byte count: {}, status: {}, event counter: {}, message counter: {}, event: {:?}",
+            show(fields.byte_count),
+            show(fields.status),
+            show(fields.event_counter),
+            show(fields.message_counter),
+            fields.data
+        ),
+        (FunctionFieldValues::ForceMultipleCoils, true)
+        | (FunctionFieldValues::PresetMultipleRegisters, true) => format!(
+            ", Reference Number: {}, Register Count: {}, Byte Count: {}, data: {:?}",
+            reference,
+            show(fields.quantity),
+            show(fields.byte_count),
+            fields.data
+        ),
+        (FunctionFieldValues::ForceMultipleCoils, false)
+        | (FunctionFieldValues::PresetMultipleRegisters, false) => format!(
+            ", Reference Number: {}, data: {}",
+            reference,
+            show(fields.quantity)
+        ),
+        /* byte count を含めて示す */
+        (FunctionFieldValues::ReportSlaveID, false) => {
+            let payload: Vec<u8> = fields.byte_count.into_iter().chain(fields.data.iter().copied()).collect();
+            format!(", payload: {:?}", payload)
+        }
+        _ => ",".to_string(),
+    }
+}
+
+/// デコードした ADU の 1 行と仕様違反。ファンクションの形に合わなかったものは Malformed の行にする
+fn print_modbus_packet(fields: &AduFields, violations: &[Violation]) {
+    let direction = if fields.is_request { "Request" } else { "Reply" };
+    let function = FunctionField(fields.function);
+    let (name, code) = match label(function) {
+        _ if fields.exception => ("exception", fields.function | EXCEPTION_BIT),
+        Some(name) => (name, fields.function),
+        None => {
+            println!("unknown function number for {:?} {}", function, direction.to_lowercase());
+            return;
+        }
+    };
+    match &fields.malformed {
+        Some(reason) => println!("    Malformed {}({}) {}: {}", name, code, direction, reason),
+        None => println!("    {}({}) {}{}", name, code, direction, details(fields)),
+    }
+    for violation in violations {
+        println!("    spec violation: {}", violation);
+    }
+}
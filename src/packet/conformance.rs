@@ -0,0 +1,288 @@
+//! デコードできた ModbusTCP パケットが仕様の制限を守っているかを調べる。
+//! デコードの成否とは別の判定として、違反内容の一覧を返す。
+
+use std::fmt;
+
+use super::fields::AduFields;
+use super::modbus_tcp::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// 個数がファンクションコードごとの上限・下限を外れている
+    QuantityOutOfRange {
+        function: FunctionField,
+        quantity: Quantity,
+        min: u16,
+        max: u16,
+    },
+    /// Force Single Coil の値が 0xFF00 / 0x0000 以外
+    InvalidCoilValue(CoilValue),
+    /// byte count が個数から求まるバイト数と一致しない
+    ByteCountMismatch { expected: usize, actual: u8 },
+    /// レジスタ読み出し応答の byte count が奇数
+    OddRegisterByteCount(u8),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::QuantityOutOfRange {
+                function,
+                quantity,
+                min,
+                max,
+            } => write!(
+                f,
+                "quantity {} out of range {}..={} for function {}",
+                quantity, min, max, function.0
+            ),
+            Violation::InvalidCoilValue(value) => {
+                write!(f, "coil value {} is neither 0xff00 nor 0x0000", value)
+            }
+            Violation::ByteCountMismatch { expected, actual } => {
+                write!(f, "byte count {} does not match expected {}", actual, expected)
+            }
+            Violation::OddRegisterByteCount(byte_count) => {
+                write!(f, "register byte count {} is odd", byte_count)
+            }
+        }
+    }
+}
+
+fn check_quantity(function: FunctionField, quantity: Quantity, violations: &mut Vec<Violation>) {
+    if !quantity.is_valid_for(function) {
+        let (min, max) = Quantity::range(function).unwrap();
+        violations.push(Violation::QuantityOutOfRange {
+            function,
+            quantity,
+            min,
+            max,
+        });
+    }
+}
+
+fn check_byte_count(expected: usize, actual: u8, violations: &mut Vec<Violation>) {
+    if expected != actual as usize {
+        violations.push(Violation::ByteCountMismatch { expected, actual });
+    }
+}
+
+fn check_coil_value(value: CoilValue, violations: &mut Vec<Violation>) {
+    if !value.is_valid() {
+        violations.push(Violation::InvalidCoilValue(value));
+    }
+}
+
+/// リクエストを調べる。デコードできないパケットは違反なしとして扱う。
+pub fn check_request(packet: &[u8]) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let function = match ModbusTCPPacket::new(packet) {
+        Some(modbus) => modbus.get_function(),
+        None => return violations,
+    };
+    match function {
+        FunctionFieldValues::ReadCoilStatus => {
+            if let Some(m_packet) = read_coil_status::request::ModbusPacket::new(packet) {
+                check_quantity(function, m_packet.get_bit_count(), &mut violations);
+            }
+        }
+        FunctionFieldValues::ReadInputStatus => {
+            if let Some(m_packet) = read_input_status::request::ModbusPacket::new(packet) {
+                check_quantity(function, m_packet.get_bit_count(), &mut violations);
+            }
+        }
+        FunctionFieldValues::ReadHoldingRegister => {
+            if let Some(m_packet) = read_holding_register::request::ModbusPacket::new(packet) {
+                check_quantity(function, m_packet.get_bit_count(), &mut violations);
+            }
+        }
+        FunctionFieldValues::ReadInputRegister => {
+            if let Some(m_packet) = read_input_register::request::ModbusPacket::new(packet) {
+                check_quantity(function, m_packet.get_bit_count(), &mut violations);
+            }
+        }
+        FunctionFieldValues::ForceSingleCoil => {
+            if let Some(m_packet) = force_single_coil::request::ModbusPacket::new(packet) {
+                check_coil_value(m_packet.get_data(), &mut violations);
+            }
+        }
+        FunctionFieldValues::ForceMultipleCoils => {
+            if let Some(m_packet) = force_multiple_coils::request::ModbusPacket::new(packet) {
+                let quantity = m_packet.get_register_count();
+                check_quantity(function, quantity, &mut violations);
+                check_byte_count(
+                    (quantity.0 as usize).div_ceil(8),
+                    m_packet.get_byte_count(),
+                    &mut violations,
+                );
+            }
+        }
+        FunctionFieldValues::PresetMultipleRegisters => {
+            if let Some(m_packet) = preset_multiple_registers::request::ModbusPacket::new(packet) {
+                let quantity = m_packet.get_register_count();
+                check_quantity(function, quantity, &mut violations);
+                check_byte_count(
+                    quantity.0 as usize * 2,
+                    m_packet.get_byte_count(),
+                    &mut violations,
+                );
+            }
+        }
+        _ => {}
+    }
+    violations
+}
+
+/// 読み出し応答の byte count を、対応するリクエストの個数から求まるバイト数と突き合わせる。
+/// リクエストがなければ、レジスタの読み出しで byte count が偶数かだけを見る。
+fn check_read_byte_count(
+    byte_count: u8,
+    registers: bool,
+    request: Option<&AduFields>,
+    violations: &mut Vec<Violation>,
+) {
+    match request.and_then(|request| request.quantity) {
+        Some(quantity) if registers => {
+            check_byte_count(quantity as usize * 2, byte_count, violations)
+        }
+        Some(quantity) => check_byte_count((quantity as usize).div_ceil(8), byte_count, violations),
+        None if registers && !byte_count.is_multiple_of(2) => {
+            violations.push(Violation::OddRegisterByteCount(byte_count))
+        }
+        None => {}
+    }
+}
+
+/// 応答を調べる。request は応答と組になったリクエスト。
+pub fn check_reply(packet: &[u8], request: Option<&AduFields>) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let function = match ModbusTCPPacket::new(packet) {
+        Some(modbus) => modbus.get_function(),
+        None => return violations,
+    };
+    match function {
+        FunctionFieldValues::ReadCoilStatus => {
+            if let Some(m_packet) = read_coil_status::reply::ModbusPacket::new(packet) {
+                check_read_byte_count(m_packet.get_byte_count(), false, request, &mut violations);
+            }
+        }
+        FunctionFieldValues::ReadInputStatus => {
+            if let Some(m_packet) = read_input_status::reply::ModbusPacket::new(packet) {
+                check_read_byte_count(m_packet.get_byte_count(), false, request, &mut violations);
+            }
+        }
+        FunctionFieldValues::ReadHoldingRegister => {
+            if let Some(m_packet) = read_holding_register::reply::ModbusPacket::new(packet) {
+                check_read_byte_count(m_packet.get_byte_count(), true, request, &mut violations);
+            }
+        }
+        FunctionFieldValues::ReadInputRegister => {
+            if let Some(m_packet) = read_input_register::reply::ModbusPacket::new(packet) {
+                check_read_byte_count(m_packet.get_byte_count(), true, request, &mut violations);
+            }
+        }
+        FunctionFieldValues::ForceSingleCoil => {
+            if let Some(m_packet) = force_single_coil::reply::ModbusPacket::new(packet) {
+                check_coil_value(m_packet.get_data(), &mut violations);
+            }
+        }
+        FunctionFieldValues::ForceMultipleCoils => {
+            if let Some(m_packet) = force_multiple_coils::reply::ModbusPacket::new(packet) {
+                check_quantity(function, m_packet.get_data(), &mut violations);
+            }
+        }
+        FunctionFieldValues::PresetMultipleRegisters => {
+            if let Some(m_packet) = preset_multiple_registers::reply::ModbusPacket::new(packet) {
+                check_quantity(function, m_packet.get_data(), &mut violations);
+            }
+        }
+        _ => {}
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(bytes: &[u8]) -> AduFields {
+        AduFields::decode(bytes, true).unwrap()
+    }
+
+    #[test]
+    fn request_limits() {
+        let bytes = read_holding_register::request::Builder::new(Address(0), Quantity(126))
+            .to_bytes()
+            .unwrap();
+        assert_eq!(
+            check_request(&bytes),
+            vec![Violation::QuantityOutOfRange {
+                function: FunctionFieldValues::ReadHoldingRegister,
+                quantity: Quantity(126),
+                min: 1,
+                max: 125,
+            }]
+        );
+        let bytes = force_single_coil::request::Builder::new(Address(0), CoilValue(0x1234))
+            .to_bytes()
+            .unwrap();
+        assert_eq!(
+            check_request(&bytes),
+            vec![Violation::InvalidCoilValue(CoilValue(0x1234))]
+        );
+        let bytes = force_multiple_coils::request::Builder::new(Address(0), vec![true; 9])
+            .to_bytes()
+            .unwrap();
+        assert!(check_request(&bytes).is_empty());
+    }
+
+    #[test]
+    fn read_reply_byte_count_against_request() {
+        let read = request(
+            &read_holding_register::request::Builder::new(Address(0), Quantity(3))
+                .to_bytes()
+                .unwrap(),
+        );
+        let reply = read_holding_register::reply::Builder::new(vec![1, 2])
+            .to_bytes()
+            .unwrap();
+        assert_eq!(
+            check_reply(&reply, Some(&read)),
+            vec![Violation::ByteCountMismatch {
+                expected: 6,
+                actual: 4
+            }]
+        );
+        /* リクエストがなければ個数と突き合わせられない */
+        assert!(check_reply(&reply, None).is_empty());
+
+        let read = request(
+            &read_coil_status::request::Builder::new(Address(0), Quantity(9))
+                .to_bytes()
+                .unwrap(),
+        );
+        let reply = read_coil_status::reply::Builder::new(vec![true; 9])
+            .to_bytes()
+            .unwrap();
+        assert!(check_reply(&reply, Some(&read)).is_empty());
+        let reply = read_coil_status::reply::Builder::new(vec![true; 17])
+            .to_bytes()
+            .unwrap();
+        assert_eq!(
+            check_reply(&reply, Some(&read)),
+            vec![Violation::ByteCountMismatch {
+                expected: 2,
+                actual: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn odd_register_byte_count_without_request() {
+        let reply = [0, 0, 0, 0, 0, 4, 1, 3, 1, 0xff];
+        assert_eq!(
+            check_reply(&reply, None),
+            vec![Violation::OddRegisterByteCount(1)]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! デコードした ModbusTCP の ADU から、フィルタや出力で使う MBAP ヘッダと PDU の値を取り出す。
+//! 読み出し応答には先頭番号と個数がないので、対応するリクエストから補う (pair)。
+
+use pnet::packet::Packet;
+use std::time::Duration;
+
+use super::modbus_tcp::*;
+
+/// コイル・レジスタの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Table {
+    Coil,
+    DiscreteInput,
+    InputRegister,
+    HoldingRegister,
+}
+
+impl Table {
+    pub fn of(function: u8) -> Option<Table> {
+        match FunctionField(function) {
+            FunctionFieldValues::ReadCoilStatus
+            | FunctionFieldValues::ForceSingleCoil
+            | FunctionFieldValues::ForceMultipleCoils => Some(Table::Coil),
+            FunctionFieldValues::ReadInputStatus => Some(Table::DiscreteInput),
+            FunctionFieldValues::ReadInputRegister => Some(Table::InputRegister),
+            FunctionFieldValues::ReadHoldingRegister
+            | FunctionFieldValues::PresetSingleRegister
+            | FunctionFieldValues::PresetMultipleRegisters => Some(Table::HoldingRegister),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Table::Coil => "coil",
+            Table::DiscreteInput => "discrete_input",
+            Table::InputRegister => "input_register",
+            Table::HoldingRegister => "holding_register",
+        }
+    }
+
+    /// Modicon 形式の番号 (40001 など) の先頭
+    pub fn reference_base(&self) -> u32 {
+        match self {
+            Table::Coil => 1,
+            Table::DiscreteInput => 10001,
+            Table::InputRegister => 30001,
+            Table::HoldingRegister => 40001,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AduFields {
+    pub transaction: u16,
+    pub unit: u8,
+    /// 例外応答では最上位ビットを落としたファンクションコード
+    pub function: u8,
+    pub is_request: bool,
+    /// 例外応答か (例外コードが欠けていても、ファンクションコードの最上位ビットで決まる)
+    pub exception: bool,
+    pub exception_code: Option<u8>,
+    pub address: Option<u16>,
+    pub quantity: Option<u16>,
+    /// 読み書きしたコイル (0/1) またはレジスタの値
+    pub values: Vec<u16>,
+    /// リクエストから応答までの時間 (応答で、対応するリクエストがあったときだけ)
+    pub latency: Option<Duration>,
+    /// byte count のあるファンクションでは、その値
+    pub byte_count: Option<u8>,
+    /// byte count に続くデータ (読み出し応答のコイル・レジスタ、イベントログのイベントなど)
+    pub data: Vec<u8>,
+    /// Force Single Coil・Preset Single Register・Diagnostics の 16 ビットのデータ
+    pub data_value: Option<u16>,
+    /// Diagnostics のサブファンクション
+    pub sub_code: Option<u16>,
+    /// Fetch Communication Event Counter (Log) の応答の status と各カウンタ
+    pub status: Option<u16>,
+    pub event_counter: Option<u16>,
+    pub message_counter: Option<u16>,
+    /// PDU が短い、byte count の分のデータがないなど、ファンクションの形に合わなかった理由
+    pub malformed: Option<String>,
+}
+
+/// LSB から詰められたコイルを count 個取り出す
+fn unpack_coils(data: &[u8], count: usize) -> Vec<u16> {
+    (0..count.min(data.len() * 8))
+        .map(|i| ((data[i / 8] >> (i % 8)) & 1) as u16)
+        .collect()
+}
+
+fn unpack_registers(data: &[u8]) -> Vec<u16> {
+    data.chunks(2)
+        .filter(|c| c.len() == 2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect()
+}
+
+impl AduFields {
+    /// ADU を解析する。MBAP ヘッダとして読めなければ None。
+    /// ファンクションの形に合わない PDU は、読めたところまでの値と malformed を返す
+    pub fn decode(packet: &[u8], is_request: bool) -> Option<AduFields> {
+        let modbus = ModbusTCPPacket::new(packet)?;
+        let function = modbus.get_function();
+        let is_exception = !is_request && function.0 & EXCEPTION_BIT != 0;
+        let mut fields = AduFields {
+            transaction: modbus.get_transaction(),
+            unit: modbus.get_unit().0,
+            function: if is_exception { function.0 & !EXCEPTION_BIT } else { function.0 },
+            is_request,
+            exception: is_exception,
+            ..AduFields::default()
+        };
+        if let Err(e) = check_pdu_length(function.0, packet.len() - MBAP_HEADER_LENGTH, is_request) {
+            fields.malformed = Some(e);
+            return Some(fields);
+        }
+        if is_exception {
+            fields.exception_code = exception::reply::ModbusPacket::new(packet)
+                .map(|m_packet| m_packet.get_exception_code());
+            return Some(fields);
+        }
+        match (function, is_request) {
+            (FunctionFieldValues::ReadCoilStatus, true) => {
+                if let Some(m_packet) = read_coil_status::request::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(m_packet.get_bit_count().0);
+                }
+            }
+            (FunctionFieldValues::ReadInputStatus, true) => {
+                if let Some(m_packet) = read_input_status::request::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(m_packet.get_bit_count().0);
+                }
+            }
+            (FunctionFieldValues::ReadHoldingRegister, true) => {
+                if let Some(m_packet) = read_holding_register::request::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(m_packet.get_bit_count().0);
+                }
+            }
+            (FunctionFieldValues::ReadInputRegister, true) => {
+                if let Some(m_packet) = read_input_register::request::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(m_packet.get_bit_count().0);
+                }
+            }
+            (FunctionFieldValues::ReadCoilStatus, false) => {
+                if let Some(m_packet) = read_coil_status::reply::ModbusPacket::new(packet) {
+                    fields.set_data(m_packet.get_byte_count(), 0, m_packet.get_data());
+                    fields.values = unpack_coils(&fields.data, fields.data.len() * 8);
+                }
+            }
+            (FunctionFieldValues::ReadInputStatus, false) => {
+                if let Some(m_packet) = read_input_status::reply::ModbusPacket::new(packet) {
+                    fields.set_data(m_packet.get_byte_count(), 0, m_packet.get_data());
+                    fields.values = unpack_coils(&fields.data, fields.data.len() * 8);
+                }
+            }
+            (FunctionFieldValues::ReadHoldingRegister, false) => {
+                if let Some(m_packet) = read_holding_register::reply::ModbusPacket::new(packet) {
+                    fields.set_data(m_packet.get_byte_count(), 0, m_packet.get_data());
+                    fields.values = unpack_registers(&fields.data);
+                }
+            }
+            (FunctionFieldValues::ReadInputRegister, false) => {
+                if let Some(m_packet) = read_input_register::reply::ModbusPacket::new(packet) {
+                    fields.set_data(m_packet.get_byte_count(), 0, m_packet.get_data());
+                    fields.values = unpack_registers(&fields.data);
+                }
+            }
+            (FunctionFieldValues::ForceSingleCoil, true) => {
+                if let Some(m_packet) = force_single_coil::request::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(1);
+                    fields.data_value = Some(m_packet.get_data().0);
+                    fields.values = m_packet.get_data().as_bool().map(|v| v as u16).into_iter().collect();
+                }
+            }
+            (FunctionFieldValues::ForceSingleCoil, false) => {
+                if let Some(m_packet) = force_single_coil::reply::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(1);
+                    fields.data_value = Some(m_packet.get_data().0);
+                    fields.values = m_packet.get_data().as_bool().map(|v| v as u16).into_iter().collect();
+                }
+            }
+            (FunctionFieldValues::PresetSingleRegister, true) => {
+                if let Some(m_packet) = preset_single_register::request::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(1);
+                    fields.data_value = Some(m_packet.get_data());
+                    fields.values = vec![m_packet.get_data()];
+                }
+            }
+            (FunctionFieldValues::PresetSingleRegister, false) => {
+                if let Some(m_packet) = preset_single_register::reply::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(1);
+                    fields.data_value = Some(m_packet.get_data());
+                    fields.values = vec![m_packet.get_data()];
+                }
+            }
+            (FunctionFieldValues::Diagnostics, true) => {
+                if let Some(m_packet) = diagnostics::request::ModbusPacket::new(packet) {
+                    fields.sub_code = Some(m_packet.get_sub_code());
+                    fields.data_value = Some(m_packet.get_data());
+                }
+            }
+            (FunctionFieldValues::Diagnostics, false) => {
+                if let Some(m_packet) = diagnostics::reply::ModbusPacket::new(packet) {
+                    fields.sub_code = Some(m_packet.get_sub_code());
+                    fields.data_value = Some(m_packet.get_data());
+                }
+            }
+            (FunctionFieldValues::FetchCommunicationEventCounter, false) => {
+                if let Some(m_packet) = fetch_communication_event_counter::reply::ModbusPacket::new(packet) {
+                    fields.status = Some(m_packet.get_status());
+                    fields.event_counter = Some(m_packet.get_event_counter());
+                }
+            }
+            (FunctionFieldValues::FetchCommunicationEventCounterLog, false) => {
+                if let Some(m_packet) = fetch_communication_event_counter_log::reply::ModbusPacket::new(packet) {
+                    fields.status = Some(m_packet.get_status());
+                    fields.event_counter = Some(m_packet.get_event_counter());
+                    fields.message_counter = Some(m_packet.get_message_counter());
+                    /* byte count は status と 2 つのカウンタの 6 バイトを含む */
+                    fields.set_data(m_packet.get_byte_count(), 6, m_packet.get_data());
+                }
+            }
+            (FunctionFieldValues::ForceMultipleCoils, true) => {
+                if let Some(m_packet) = force_multiple_coils::request::ModbusPacket::new(packet) {
+                    let quantity = m_packet.get_register_count().0;
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(quantity);
+                    fields.set_data(m_packet.get_byte_count(), 0, m_packet.get_data());
+                    fields.values = unpack_coils(&fields.data, quantity as usize);
+                }
+            }
+            (FunctionFieldValues::ForceMultipleCoils, false) => {
+                if let Some(m_packet) = force_multiple_coils::reply::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(m_packet.get_data().0);
+                }
+            }
+            (FunctionFieldValues::PresetMultipleRegisters, true) => {
+                if let Some(m_packet) = preset_multiple_registers::request::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(m_packet.get_register_count().0);
+                    fields.set_data(m_packet.get_byte_count(), 0, m_packet.get_data());
+                    fields.values = unpack_registers(&fields.data);
+                }
+            }
+            (FunctionFieldValues::PresetMultipleRegisters, false) => {
+                if let Some(m_packet) = preset_multiple_registers::reply::ModbusPacket::new(packet) {
+                    fields.address = Some(m_packet.get_reference_number().0);
+                    fields.quantity = Some(m_packet.get_data().0);
+                }
+            }
+            (FunctionFieldValues::ReportSlaveID, false) => {
+                if let Some(m_packet) = report_slave_id::reply::ModbusPacket::new(packet) {
+                    /* 最小の長さを調べてあるので byte count はある */
+                    let payload = m_packet.payload();
+                    let byte_count = payload[0];
+                    let end = payload.len().min(1 + byte_count as usize);
+                    fields.set_data(byte_count, 0, payload[1..end].to_vec());
+                }
+            }
+            _ => {}
+        }
+        Some(fields)
+    }
+
+    /// byte count と、それに続くデータを入れる。header は byte count に含まれるデータの前のバイト数。
+    /// byte count の分だけ続いていなければ malformed にする
+    fn set_data(&mut self, byte_count: u8, header: usize, data: Vec<u8>) {
+        if header + data.len() < byte_count as usize {
+            self.malformed = Some(format!(
+                "byte count {} but {} bytes follow",
+                byte_count,
+                header + data.len()
+            ));
+        }
+        self.byte_count = Some(byte_count);
+        self.data = data;
+    }
+
+    /// 対応するリクエストから、応答にない先頭番号と個数を補い、応答時間を求める
+    pub fn pair(&mut self, request: &AduFields, latency: Duration) {
+        if self.address.is_none() {
+            self.address = request.address;
+        }
+        if self.quantity.is_none() {
+            self.quantity = request.quantity;
+        }
+        /* コイルの読み出し応答は 8 個単位で詰められているので個数で切る */
+        if let (Some(Table::Coil), Some(quantity)) | (Some(Table::DiscreteInput), Some(quantity)) =
+            (Table::of(self.function), self.quantity)
+        {
+            if self.exception_code.is_none() && self.values.len() > quantity as usize {
+                self.values.truncate(quantity as usize);
+            }
+        }
+        self.latency = Some(latency);
+    }
+
+    pub fn table(&self) -> Option<Table> {
+        Table::of(self.function)
+    }
+
+    /// Modicon 形式の先頭番号 (保持レジスタの 0 番なら 40001)
+    pub fn reference(&self) -> Option<u32> {
+        Some(self.table()?.reference_base() + self.address? as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(pdu: &[u8], is_request: bool) -> AduFields {
+        AduFields::decode(&to_mbap(1, 1, pdu), is_request).unwrap()
+    }
+
+    #[test]
+    fn well_formed() {
+        let reply = decode(&[3, 4, 0, 1, 0, 2], false);
+        assert_eq!((reply.byte_count, reply.values.clone()), (Some(4), vec![1, 2]));
+        assert_eq!(reply.malformed, None);
+        let exception = decode(&[0x83, 2], false);
+        assert_eq!((exception.function, exception.exception_code), (3, Some(2)));
+        /* リクエストのファンクションコードは最上位ビットを落とさない */
+        assert_eq!(decode(&[0x83, 2], true).function, 0x83);
+        let log = decode(&[12, 8, 0, 0, 0, 1, 0, 2, 0x20, 0x00], false);
+        assert_eq!((log.message_counter, log.data.clone()), (Some(2), vec![0x20, 0x00]));
+        assert_eq!(log.malformed, None);
+    }
+
+    #[test]
+    fn truncated() {
+        let request = decode(&[3, 0], true);
+        assert_eq!(request.malformed.as_deref(), Some("PDU of 2 bytes, function 3 needs at least 5"));
+        assert_eq!((request.address, request.quantity), (None, None));
+        let exception = decode(&[0x83], false);
+        assert_eq!((exception.function, exception.exception_code), (3, None));
+        assert!(exception.exception);
+        assert!(exception.malformed.is_some());
+        let reply = decode(&[3, 4, 0, 1], false);
+        assert_eq!(reply.malformed.as_deref(), Some("byte count 4 but 2 bytes follow"));
+        assert_eq!(reply.values, vec![1]);
+        /* byte count が status とカウンタの 6 バイトより小さくても読める */
+        let log = decode(&[12, 2, 0, 0, 0, 1, 0, 2], false);
+        assert_eq!((log.byte_count, log.malformed), (Some(2), None));
+        let slave_id = decode(&[17, 3, 1], false);
+        assert_eq!(slave_id.malformed.as_deref(), Some("byte count 3 but 1 bytes follow"));
+    }
+}
@@ -1 +1,5 @@
+pub mod conformance;
+pub mod fields;
+pub mod modbus_ascii;
+pub mod modbus_rtu;
 pub mod modbus_tcp;
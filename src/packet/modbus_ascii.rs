@@ -0,0 +1,219 @@
+//! Modbus ASCII フレーム (':' で始まり、16進文字列, LRC, CRLF で終わる行) を扱う。
+//! +-----+-----------+-----------+---------------+-----------+-------+
+//! | ':' | Address 2 | Function 2|  Data 2 x N   |   LRC 2   | CR LF |
+//! +-----+-----------+-----------+---------------+-----------+-------+
+//!
+//! TCP ではフレームがセグメントをまたぐので、LineBuffer で行を組み立ててから decode_line に渡す。
+
+use std::fmt;
+
+use super::modbus_tcp::{minimum_pdu_length, to_mbap};
+
+/// ':' と CRLF を除いた最大の長さ (アドレス, PDU 253 バイト, LRC を16進で表したもの)
+pub const MAX_LINE: usize = 2 * (1 + 253 + 1);
+
+/// アドレスと PDU のバイトの和の2の補数
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// ファンクションごとのフレームの最小のバイト数 (16進から戻した、アドレスと LRC を含む長さ)
+pub fn minimum_length(function: u8, is_request: bool) -> usize {
+    2 + minimum_pdu_length(function, is_request)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsciiError {
+    /// 行が ':' で始まっていない
+    MissingStart,
+    /// 16進として読めない文字がある
+    InvalidHex,
+    /// 16進の文字数が奇数
+    OddLength,
+    /// アドレス, ファンクションコード, LRC に満たない
+    TooShort,
+    /// CRLF が来ないまま MAX_LINE を超えた
+    TooLong,
+}
+
+impl fmt::Display for AsciiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsciiError::MissingStart => write!(f, "line does not start with ':'"),
+            AsciiError::InvalidHex => write!(f, "invalid hex character"),
+            AsciiError::OddLength => write!(f, "odd number of hex characters"),
+            AsciiError::TooShort => write!(f, "too short"),
+            AsciiError::TooLong => write!(f, "too long"),
+        }
+    }
+}
+
+pub struct AsciiFrame {
+    bytes: Vec<u8>,
+}
+
+impl AsciiFrame {
+    pub fn get_address(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn get_function(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    /// 16進から戻したバイト数 (アドレス, PDU, LRC)
+    pub fn byte_length(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// ファンクションコードから LRC の手前まで
+    pub fn pdu(&self) -> &[u8] {
+        &self.bytes[1..self.bytes.len() - 1]
+    }
+
+    /// フレームに書かれている LRC
+    pub fn get_lrc(&self) -> u8 {
+        self.bytes[self.bytes.len() - 1]
+    }
+
+    pub fn computed_lrc(&self) -> u8 {
+        lrc(&self.bytes[..self.bytes.len() - 1])
+    }
+
+    pub fn lrc_ok(&self) -> bool {
+        self.get_lrc() == self.computed_lrc()
+    }
+
+    /// スレーブアドレスを unit にした MBAP ヘッダを付けて、ModbusTCP の形に直す
+    pub fn to_mbap(&self, transaction: u16) -> Vec<u8> {
+        to_mbap(transaction, self.get_address(), self.pdu())
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
+/// CRLF を除いた1行を16進から戻す。LRC の不一致はエラーにせず AsciiFrame::lrc_ok で調べる。
+pub fn decode_line(line: &[u8]) -> Result<AsciiFrame, AsciiError> {
+    if line.first() != Some(&b':') {
+        return Err(AsciiError::MissingStart);
+    }
+    let hex = &line[1..];
+    if hex.len() > MAX_LINE {
+        return Err(AsciiError::TooLong);
+    }
+    if !hex.len().is_multiple_of(2) {
+        return Err(AsciiError::OddLength);
+    }
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.chunks(2) {
+        match (hex_value(pair[0]), hex_value(pair[1])) {
+            (Some(hi), Some(lo)) => bytes.push(hi << 4 | lo),
+            _ => return Err(AsciiError::InvalidHex),
+        }
+    }
+    if bytes.len() < 3 {
+        return Err(AsciiError::TooShort);
+    }
+    Ok(AsciiFrame { bytes })
+}
+
+/// TCP のストリームから CRLF 区切りの行を組み立てる
+#[derive(Default)]
+pub struct LineBuffer {
+    buffer: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// 受け取ったバイト列を溜め、CRLF で終わった行を CRLF を除いて返す。
+    /// CRLF が来ないまま長くなりすぎた行はそこで打ち切って返す。
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        for byte in data {
+            if *byte == b'\n' && self.buffer.last() == Some(&b'\r') {
+                self.buffer.pop();
+                lines.push(self.buffer.split_off(0));
+            } else {
+                self.buffer.push(*byte);
+                if self.buffer.len() > MAX_LINE + 2 {
+                    lines.push(self.buffer.split_off(0));
+                }
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lrcs() {
+        assert_eq!(lrc(&[0x11, 0x03, 0x00, 0x6b, 0x00, 0x03]), 0x7e);
+        assert_eq!(lrc(&[]), 0);
+        assert_eq!(lrc(&[0x80, 0x80]), 0);
+        assert_eq!(lrc(&[0xff]), 0x01);
+    }
+
+    #[test]
+    fn lines() {
+        let frame = decode_line(b":1103006B00037E").unwrap();
+        assert_eq!((frame.get_address(), frame.get_function()), (0x11, 3));
+        assert_eq!(frame.pdu(), &[0x03, 0x00, 0x6b, 0x00, 0x03]);
+        assert_eq!(frame.byte_length(), 7);
+        assert!(frame.lrc_ok());
+        /* 小文字も読み、LRC の不一致はエラーにしない */
+        let frame = decode_line(b":1103006b000300").unwrap();
+        assert_eq!((frame.get_lrc(), frame.computed_lrc()), (0x00, 0x7e));
+        assert!(!frame.lrc_ok());
+        assert_eq!(frame.to_mbap(7), to_mbap(7, 0x11, &[0x03, 0x00, 0x6b, 0x00, 0x03]));
+
+        assert_eq!(decode_line(b"1103006B00037E").err(), Some(AsciiError::MissingStart));
+        assert_eq!(decode_line(b"").err(), Some(AsciiError::MissingStart));
+        assert_eq!(decode_line(b":1103006B00037").err(), Some(AsciiError::OddLength));
+        assert_eq!(decode_line(b":1103006G00037E").err(), Some(AsciiError::InvalidHex));
+        assert_eq!(decode_line(b":1103 06B00037E").err(), Some(AsciiError::InvalidHex));
+        /* CRLF が残っていれば16進として読めない */
+        assert_eq!(decode_line(b":1103006B00037E\r\n").err(), Some(AsciiError::InvalidHex));
+        assert_eq!(decode_line(b":1103").err(), Some(AsciiError::TooShort));
+        let long = [b":".to_vec(), vec![b'0'; MAX_LINE + 2]].concat();
+        assert_eq!(decode_line(&long).err(), Some(AsciiError::TooLong));
+    }
+
+    #[test]
+    fn minimum_lengths() {
+        assert_eq!(minimum_length(3, true), 7);
+        assert_eq!(minimum_length(3, false), 4);
+        assert_eq!(minimum_length(0x83, false), 4);
+    }
+
+    #[test]
+    fn line_buffer() {
+        let mut buffer = LineBuffer::default();
+        /* セグメントをまたぐ行。CR と LF が別のセグメントに分かれても区切る */
+        assert!(buffer.push(b":1103").is_empty());
+        assert!(buffer.push(b"006B00037E\r").is_empty());
+        assert_eq!(buffer.push(b"\n:01"), vec![b":1103006B00037E".to_vec()]);
+        /* 1 つのセグメントに複数の行 */
+        assert_eq!(
+            buffer.push(b"0300\r\n:0203\r\n\r\n"),
+            vec![b":010300".to_vec(), b":0203".to_vec(), Vec::new()]
+        );
+        /* LF だけでは区切らない */
+        assert!(buffer.push(b":01\n02").is_empty());
+        assert_eq!(buffer.push(b"\r\n"), vec![b":01\n02".to_vec()]);
+        /* CRLF が来ないまま長くなりすぎた行は打ち切る */
+        let lines = buffer.push(&vec![b'0'; MAX_LINE + 3]);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].len(), MAX_LINE + 3);
+    }
+}
@@ -0,0 +1,241 @@
+//! Modbus RTU フレーム (スレーブアドレス, PDU, CRC-16) を扱う。
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |    Address    |   Function    |   Data ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!     ... Data    |    CRC (Lo)   |    CRC (Hi)   |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!
+//! MBAP ヘッダを付け直すことで、modbus_tcp の各パケットのレイアウトで PDU を解析できる。
+
+use super::modbus_tcp::{minimum_pdu_length, to_mbap, EXCEPTION_BIT};
+
+/// CRC-16/MODBUS (初期値 0xFFFF, 多項式 0xA001 の反転形)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub struct RtuFrame<'a> {
+    frame: &'a [u8],
+}
+
+impl<'a> RtuFrame<'a> {
+    /// アドレス, ファンクションコード, CRC の 4 バイトに満たない場合は None
+    pub fn new(frame: &'a [u8]) -> Option<RtuFrame<'a>> {
+        if frame.len() >= 4 {
+            Some(RtuFrame { frame })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_address(&self) -> u8 {
+        self.frame[0]
+    }
+
+    pub fn get_function(&self) -> u8 {
+        self.frame[1]
+    }
+
+    /// ファンクションコードから CRC の手前まで
+    pub fn pdu(&self) -> &'a [u8] {
+        &self.frame[1..self.frame.len() - 2]
+    }
+
+    /// フレームに書かれている CRC (下位バイトが先)
+    pub fn get_crc(&self) -> u16 {
+        let len = self.frame.len();
+        u16::from_le_bytes([self.frame[len - 2], self.frame[len - 1]])
+    }
+
+    pub fn computed_crc(&self) -> u16 {
+        crc16(&self.frame[..self.frame.len() - 2])
+    }
+
+    pub fn crc_ok(&self) -> bool {
+        self.get_crc() == self.computed_crc()
+    }
+
+    /// スレーブアドレスを unit にした MBAP ヘッダを付けて、ModbusTCP の形に直す
+    pub fn to_mbap(&self, transaction: u16) -> Vec<u8> {
+        to_mbap(transaction, self.get_address(), self.pdu())
+    }
+}
+
+/// アドレスと PDU から CRC 付きの RTU フレームを作る
+#[cfg(test)]
+fn encode(address: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(3 + pdu.len());
+    frame.push(address);
+    frame.extend_from_slice(pdu);
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// ファンクションごとのフレームの最小の長さ (アドレスと CRC を含む)
+pub fn minimum_length(function: u8, is_request: bool) -> usize {
+    3 + minimum_pdu_length(function, is_request)
+}
+
+/// リクエストとしてありうるフレーム長 (アドレスと CRC を含む)。形だけで決まらなければ None
+fn request_length(frame: &[u8]) -> Option<usize> {
+    match frame[1] {
+        1..=6 | 8 => Some(8),
+        11 | 12 | 17 => Some(4),
+        15 | 16 if frame.len() > 6 => Some(9 + frame[6] as usize),
+        _ => None,
+    }
+}
+
+/// 応答としてありうるフレーム長 (アドレスと CRC を含む)。形だけで決まらなければ None
+fn reply_length(frame: &[u8]) -> Option<usize> {
+    match frame[1] {
+        function if function & EXCEPTION_BIT != 0 => Some(5),
+        1..=4 | 12 | 17 => Some(5 + frame[2] as usize),
+        5 | 6 | 8 | 11 | 15 | 16 => Some(8),
+        _ => None,
+    }
+}
+
+/// RTU フレームの最大長 (アドレス 1 バイト, PDU 253 バイト, CRC 2 バイト)
+const MAX_FRAME_LENGTH: usize = 256;
+
+/// TCP で流れる RTU フレームを、ファンクションコードから求まる長さで 1 フレームずつに分ける。
+/// セグメントの切れ目がフレームの切れ目と一致しなくてもよい。
+#[derive(Default)]
+pub struct RtuBuffer {
+    buffer: Vec<u8>,
+}
+
+impl RtuBuffer {
+    /// データを追加し、そろったフレームを返す。長さが形から決まらないファンクションでは
+    /// 届いているデータ全体を 1 フレームとみなす。ありえない長さなら 1 バイトずらして同期を取り直す。
+    pub fn push(&mut self, data: &[u8], is_request: bool) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        while self.buffer.len() >= 2 {
+            /* byte count が届くまでは長さが決まらない */
+            let header = match (is_request, self.buffer[1]) {
+                (true, 15) | (true, 16) => 7,
+                (false, 1..=4) | (false, 12) | (false, 17) => 3,
+                _ => 2,
+            };
+            if self.buffer.len() < header {
+                break;
+            }
+            let length = if is_request {
+                request_length(&self.buffer)
+            } else {
+                reply_length(&self.buffer)
+            };
+            let length = length.unwrap_or(self.buffer.len());
+            if length > MAX_FRAME_LENGTH {
+                self.buffer.remove(0);
+                continue;
+            }
+            if self.buffer.len() < length {
+                break;
+            }
+            frames.push(self.buffer.drain(..length).collect());
+        }
+        frames
+    }
+}
+
+/// シリアルバスにはポートがないので、フレームの順番と形からリクエストか応答かを推定する。
+/// マスタのリクエストの直後に、同じスレーブから同じファンクションコード
+/// (例外ならその最上位ビットを立てたもの) で応答の形をしたフレームが来れば応答とみなす。
+#[derive(Default)]
+pub struct DirectionTracker {
+    /// 応答を待っているリクエストのアドレスとファンクションコード
+    pending: Option<(u8, u8)>,
+}
+
+impl DirectionTracker {
+    pub fn new() -> DirectionTracker {
+        DirectionTracker { pending: None }
+    }
+
+    /// リクエストなら true
+    pub fn is_request(&mut self, rtu: &RtuFrame) -> bool {
+        let frame = rtu.frame;
+        let fits_request = request_length(frame).is_none_or(|len| len == frame.len());
+        let fits_reply = reply_length(frame).is_none_or(|len| len == frame.len());
+        let answers_pending = match self.pending {
+            Some((address, function)) => {
+                address == frame[0]
+                    && (function == frame[1] || function | EXCEPTION_BIT == frame[1])
+            }
+            None => false,
+        };
+        let is_request = if frame[1] & EXCEPTION_BIT != 0 || (answers_pending && fits_reply) {
+            false
+        } else {
+            fits_request || !fits_reply
+        };
+        self.pending = if is_request {
+            Some((frame[0], frame[1]))
+        } else {
+            None
+        };
+        is_request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        let frame = encode(1, &[3, 0, 0, 0, 10]);
+        assert_eq!(frame[6..], [0xc5, 0xcd]);
+        assert!(RtuFrame::new(&frame).unwrap().crc_ok());
+    }
+
+    #[test]
+    fn split_and_coalesced_segments() {
+        let first = encode(1, &[3, 0, 0, 0, 2]);
+        let second = encode(1, &[16, 0, 10, 0, 1, 2, 0x01, 0xf4]);
+        let stream = [first.clone(), second.clone()].concat();
+        let mut buffer = RtuBuffer::default();
+        /* 2 フレームが 1 セグメントにまとまっている */
+        assert_eq!(buffer.push(&stream, true), vec![first.clone(), second.clone()]);
+        /* 1 フレームが byte count の手前で切れている */
+        assert!(buffer.push(&stream[..3], true).is_empty());
+        assert_eq!(buffer.push(&stream[3..10], true), vec![first]);
+        assert!(buffer.push(&stream[10..14], true).is_empty());
+        assert_eq!(buffer.push(&stream[14..], true), vec![second]);
+    }
+
+    #[test]
+    fn replies_by_byte_count() {
+        let read = encode(1, &[3, 4, 0, 1, 0, 2]);
+        let exception = encode(1, &[0x83, 2]);
+        let write = encode(1, &[6, 0, 10, 0x01, 0xf4]);
+        let mut buffer = RtuBuffer::default();
+        let stream = [read.clone(), exception.clone(), write.clone()].concat();
+        assert!(buffer.push(&stream[..2], false).is_empty());
+        assert_eq!(buffer.push(&stream[2..], false), vec![read, exception, write]);
+    }
+
+    #[test]
+    fn minimum_lengths() {
+        assert_eq!(minimum_length(3, true), 8);
+        assert_eq!(minimum_length(3, false), 5);
+        assert_eq!(minimum_length(0x83, false), 5);
+        assert_eq!(minimum_length(16, true), 9);
+        assert_eq!(minimum_length(11, true), 4);
+    }
+}
@@ -1,13 +1,18 @@
 use pnet_macros::packet;
 use pnet_macros_support::types::*;
 use pnet_macros_support::packet::PrimitiveValues;
+use std::convert::TryFrom;
+use std::fmt;
 
+/// MBAP ヘッダとファンクションコードだけを読む。各フィールドの構造体は使わない
 #[packet]
+#[allow(dead_code)]
 pub struct ModbusTCP {
     pub transaction: u16be,
     pub protocol: u16be,
     pub length: u16be,
-    pub unit: u8,
+    #[construct_with(u8)]
+    pub unit: UnitId,
     #[construct_with(u8)]
     pub function: FunctionField,
     #[payload]
@@ -21,6 +26,29 @@ impl FunctionField {
     pub fn new(field_val: u8) -> FunctionField {
         FunctionField(field_val)
     }
+
+    /// 出力に使うファンクションの名前 (解析できないファンクションは None)
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            FunctionFieldValues::ReadCoilStatus => Some("read_coil_status"),
+            FunctionFieldValues::ReadInputStatus => Some("read_input_status"),
+            FunctionFieldValues::ReadHoldingRegister => Some("read_holding_register"),
+            FunctionFieldValues::ReadInputRegister => Some("read_input_register"),
+            FunctionFieldValues::ForceSingleCoil => Some("force_single_coil"),
+            FunctionFieldValues::PresetSingleRegister => Some("preset_single_register"),
+            FunctionFieldValues::Diagnostics => Some("diagnostics"),
+            FunctionFieldValues::FetchCommunicationEventCounter => {
+                Some("fetch_communication_event_counter")
+            }
+            FunctionFieldValues::FetchCommunicationEventCounterLog => {
+                Some("fetch_communication_event_counter_log")
+            }
+            FunctionFieldValues::ForceMultipleCoils => Some("force_multiple_coils"),
+            FunctionFieldValues::PresetMultipleRegisters => Some("preset_multiple_registers"),
+            FunctionFieldValues::ReportSlaveID => Some("report_slave_id"),
+            _ => None,
+        }
+    }
 }
 
 impl PrimitiveValues for FunctionField {
@@ -49,6 +77,344 @@ pub mod FunctionFieldValues {
     pub const ReportSlaveID: FunctionField = FunctionField(17);
 }
 
+/// ユニット識別子 (シリアル側のスレーブアドレス)
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default, Hash)]
+pub struct UnitId(pub u8);
+
+impl UnitId {
+    pub fn new(val: u8) -> UnitId {
+        UnitId(val)
+    }
+}
+
+impl PrimitiveValues for UnitId {
+    type T = (u8,);
+    fn to_primitive_values(&self) -> (u8,) {
+        (self.0,)
+    }
+}
+
+impl fmt::Display for UnitId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// 先頭のコイル・レジスタ番号 (Reference Number)
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct Address(pub u16);
+
+impl Address {
+    pub fn new(val: u16) -> Address {
+        Address(val)
+    }
+}
+
+impl PrimitiveValues for Address {
+    type T = (u16,);
+    fn to_primitive_values(&self) -> (u16,) {
+        (self.0,)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// 読み書きするコイル・レジスタの個数
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct Quantity(pub u16);
+
+impl Quantity {
+    pub fn new(val: u16) -> Quantity {
+        Quantity(val)
+    }
+
+    /// ファンクションコードごとに仕様で許されている個数の範囲を返す
+    pub fn range(function: FunctionField) -> Option<(u16, u16)> {
+        match function {
+            FunctionFieldValues::ReadCoilStatus | FunctionFieldValues::ReadInputStatus => {
+                Some((1, 2000))
+            }
+            FunctionFieldValues::ReadHoldingRegister | FunctionFieldValues::ReadInputRegister => {
+                Some((1, 125))
+            }
+            FunctionFieldValues::ForceMultipleCoils => Some((1, 1968)),
+            FunctionFieldValues::PresetMultipleRegisters => Some((1, 123)),
+            _ => None,
+        }
+    }
+
+    /// 個数の制限がないファンクションコードでは常に true
+    pub fn is_valid_for(&self, function: FunctionField) -> bool {
+        match Quantity::range(function) {
+            Some((min, max)) => (min..=max).contains(&self.0),
+            None => true,
+        }
+    }
+}
+
+impl PrimitiveValues for Quantity {
+    type T = (u16,);
+    fn to_primitive_values(&self) -> (u16,) {
+        (self.0,)
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Force Single Coil の値。仕様上は 0xFF00 (ON) か 0x0000 (OFF) のみ
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct CoilValue(pub u16);
+
+impl CoilValue {
+    pub const ON: CoilValue = CoilValue(0xFF00);
+    pub const OFF: CoilValue = CoilValue(0x0000);
+
+    pub fn new(val: u16) -> CoilValue {
+        CoilValue(val)
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn from_bool(on: bool) -> CoilValue {
+        if on {
+            CoilValue::ON
+        } else {
+            CoilValue::OFF
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        *self == CoilValue::ON || *self == CoilValue::OFF
+    }
+
+    /// 仕様外の値では None
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            CoilValue::ON => Some(true),
+            CoilValue::OFF => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl PrimitiveValues for CoilValue {
+    type T = (u16,);
+    fn to_primitive_values(&self) -> (u16,) {
+        (self.0,)
+    }
+}
+
+impl fmt::Display for CoilValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.as_bool() {
+            Some(true) => write!(f, "ON"),
+            Some(false) => write!(f, "OFF"),
+            None => write!(f, "{:#06x}", self.0),
+        }
+    }
+}
+
+/// 例外応答では function の最上位ビットが立つ
+pub const EXCEPTION_BIT: u8 = 0x80;
+
+/// transaction, protocol, length, unit の 7 バイト。その後ろが PDU
+pub const MBAP_HEADER_LENGTH: usize = 7;
+
+/// MBAPヘッダの length は unit 以降のバイト数なので、PDU の長さに unit の 1 バイトを足す
+fn mbap_length(pdu_len: usize) -> u16 {
+    (1 + pdu_len) as u16
+}
+
+/// ファンクションごとの PDU (ファンクションコードから) の最小の長さ。
+/// これより短い PDU は決まった位置のフィールドが欠けている
+pub fn minimum_pdu_length(function: u8, is_request: bool) -> usize {
+    match (is_request, function) {
+        (false, function) if function & EXCEPTION_BIT != 0 => 2,
+        (true, 1..=6) | (true, 8) => 5,
+        (true, 15) | (true, 16) => 6,
+        (false, 1..=4) | (false, 17) => 2,
+        (false, 12) => 8,
+        (false, 5) | (false, 6) | (false, 8) | (false, 11) | (false, 15) | (false, 16) => 5,
+        _ => 1,
+    }
+}
+
+/// データ全体が ADU 1 つ分として、MBAP ヘッダの length と PDU の長さが成り立つか調べる
+pub fn check_adu_length(adu: &[u8], is_request: bool) -> Result<(), String> {
+    if adu.len() <= MBAP_HEADER_LENGTH {
+        return Err(format!("{} bytes is too short for an MBAP header and function code", adu.len()));
+    }
+    let length = u16::from_be_bytes([adu[4], adu[5]]) as usize;
+    if length != adu.len() - 6 {
+        return Err(format!("MBAP length {} does not match {} bytes", length, adu.len() - 6));
+    }
+    check_pdu_length(adu[MBAP_HEADER_LENGTH], adu.len() - MBAP_HEADER_LENGTH, is_request)
+}
+
+/// PDU の長さがファンクションの最小の長さに足りているか調べる
+pub fn check_pdu_length(function: u8, pdu_length: usize, is_request: bool) -> Result<(), String> {
+    let minimum = minimum_pdu_length(function, is_request);
+    if pdu_length < minimum {
+        return Err(format!(
+            "PDU of {} bytes, function {} needs at least {}",
+            pdu_length, function, minimum
+        ));
+    }
+    Ok(())
+}
+
+/// unit と PDU に MBAP ヘッダを付けて ModbusTCP の形にする
+pub fn to_mbap(transaction: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut adu = Vec::with_capacity(7 + pdu.len());
+    adu.extend_from_slice(&transaction.to_be_bytes());
+    adu.extend_from_slice(&0u16.to_be_bytes());
+    adu.extend_from_slice(&mbap_length(pdu.len()).to_be_bytes());
+    adu.push(unit);
+    adu.extend_from_slice(pdu);
+    adu
+}
+
+/// コイルの並びを 8 個ずつ LSB から 1 バイトに詰める
+#[cfg_attr(not(test), allow(dead_code))]
+fn pack_coils(coils: &[bool]) -> Vec<u8> {
+    let mut data = vec![0u8; coils.len().div_ceil(8)];
+    for (i, coil) in coils.iter().enumerate() {
+        if *coil {
+            data[i / 8] |= 1 << (i % 8);
+        }
+    }
+    data
+}
+
+/// レジスタ値をビッグエンディアンで並べる
+#[cfg_attr(not(test), allow(dead_code))]
+fn pack_registers(registers: &[u16]) -> Vec<u8> {
+    registers.iter().flat_map(|r| r.to_be_bytes().to_vec()).collect()
+}
+
+/// byte count に入らない長さなら Err
+#[cfg_attr(not(test), allow(dead_code))]
+fn byte_count(length: usize) -> Result<u8, String> {
+    u8::try_from(length).map_err(|_| format!("{} bytes do not fit in a byte count", length))
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+fn quantity(count: usize) -> Result<Quantity, String> {
+    u16::try_from(count)
+        .map(Quantity)
+        .map_err(|_| format!("{} values do not fit in a quantity", count))
+}
+
+/// 各ファンクションの request・reply に、ADU を組み立てる Builder を作る。
+/// new の引数がそのまま Builder のフィールドになり、|b| の後に Modbus 構造体の
+/// function 以降のフィールドを b から書く (byte count などは ? で Err を返せる)。
+/// transaction, protocol, length, unit は Builder が埋め、length は PDU の長さから求める。
+/// このバイナリではテストのトラフィックを作るのにだけ使う。
+macro_rules! builder {
+    (
+        $(#[$meta:meta])*
+        Builder($($field:ident: $field_type:ty),*) |$builder:tt| Modbus {
+            $($name:ident: $value:expr),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        #[allow(dead_code)]
+        pub struct Builder {
+            transaction: u16,
+            unit: UnitId,
+            $($field: $field_type),*
+        }
+
+        #[allow(dead_code)]
+        impl Builder {
+            pub fn new($($field: $field_type),*) -> Builder {
+                Builder {
+                    transaction: 0,
+                    unit: UnitId(0),
+                    $($field),*
+                }
+            }
+
+            pub fn transaction(mut self, transaction: u16) -> Builder {
+                self.transaction = transaction;
+                self
+            }
+
+            pub fn unit(mut self, unit: UnitId) -> Builder {
+                self.unit = unit;
+                self
+            }
+
+            /// length と byte count を計算したうえで Modbus 構造体を返す。
+            /// PDU が仕様の 253 バイトを超えるなら Err
+            pub fn build(&self) -> Result<Modbus, String> {
+                let $builder = self;
+                let mut modbus = Modbus {
+                    transaction: self.transaction,
+                    protocol: 0,
+                    length: 0,
+                    unit: self.unit,
+                    $($name: $value),*
+                };
+                let pdu_length = ModbusPacket::packet_size(&modbus)
+                    - $crate::packet::modbus_tcp::MBAP_HEADER_LENGTH;
+                if pdu_length >= $crate::packet::modbus_tcp::MAX_MBAP_LENGTH as usize {
+                    return Err(format!("PDU of {} bytes exceeds 253 bytes", pdu_length));
+                }
+                modbus.length = $crate::packet::modbus_tcp::mbap_length(pdu_length);
+                Ok(modbus)
+            }
+
+            pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+                let modbus = self.build()?;
+                let mut buf = vec![0u8; ModbusPacket::packet_size(&modbus)];
+                MutableModbusPacket::new(&mut buf[..]).unwrap().populate(&modbus);
+                Ok(buf)
+            }
+        }
+    };
+}
+
+/// MBAPヘッダの length の上限 (unit + PDU の最大 253 バイト)
+const MAX_MBAP_LENGTH: u16 = 254;
+
+/// 区切りのないバイト列 (TLS で復号したデータなど) を MBAP ヘッダの length で ADU に分ける
+#[derive(Default)]
+pub struct AduBuffer {
+    buffer: Vec<u8>,
+}
+
+impl AduBuffer {
+    /// データを追加し、そろった ADU を返す。
+    /// length がありえない値なら 1 バイトずらして同期を取り直す。
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut adus = Vec::new();
+        while self.buffer.len() >= 7 {
+            let protocol = u16::from_be_bytes([self.buffer[2], self.buffer[3]]);
+            let length = u16::from_be_bytes([self.buffer[4], self.buffer[5]]);
+            if protocol != 0 || !(2..=MAX_MBAP_LENGTH).contains(&length) {
+                self.buffer.remove(0);
+                continue;
+            }
+            let total = 6 + length as usize;
+            if self.buffer.len() < total {
+                break;
+            }
+            adus.push(self.buffer.drain(..total).collect());
+        }
+        adus
+    }
+}
+
 pub mod read_coil_status {
     pub mod request {
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -59,54 +425,35 @@ pub mod read_coil_status {
         //! |        Reference Number       |           Bit Count           |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-    
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-    
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct BitCount(pub u16);
-    
-        impl BitCount {
-            pub fn new(val: u16) -> BitCount {
-                BitCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for BitCount {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub bit_count: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub bit_count: Quantity,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Read Coil Status リクエストを組み立てる。
+            Builder(reference_number: Address, bit_count: Quantity) |b| Modbus {
+                function: FunctionFieldValues::ReadCoilStatus.0,
+                reference_number: b.reference_number,
+                bit_count: b.bit_count,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -118,32 +465,17 @@ pub mod read_coil_status {
         //! |   Byte Count  |   Data ...
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ByteCount(pub u8);
-
-        impl ByteCount {
-            pub fn new(val: u8) -> ByteCount {
-                ByteCount(val)
-            }
-        }
-
-        impl PrimitiveValues for ByteCount {
-            type T = (u8,);
-            fn to_primitive_values(&self) -> (u8,) {
-                (self.0,)
-            }
-        }
+        use super::super::{byte_count, pack_coils, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             pub byte_count: u8,
             #[length_fn = "data_length_b"]
@@ -158,6 +490,16 @@ pub mod read_coil_status {
 
             byte_count as usize
         }
+
+        builder! {
+            /// Read Coil Status の応答を組み立てる。コイルは 8 個ずつ LSB から詰める。
+            Builder(coils: Vec<bool>) |b| Modbus {
+                function: FunctionFieldValues::ReadCoilStatus.0,
+                byte_count: byte_count(b.coils.len().div_ceil(8))?,
+                data: pack_coils(&b.coils),
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -171,54 +513,35 @@ pub mod read_input_status {
         //! |        Reference Number       |           Bit Count           |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct BitCount(pub u16);
-
-        impl BitCount {
-            pub fn new(val: u16) -> BitCount {
-                BitCount(val)
-            }
-        }
-
-        impl PrimitiveValues for BitCount {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub bit_count: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub bit_count: Quantity,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Read Input Status リクエストを組み立てる。
+            Builder(reference_number: Address, bit_count: Quantity) |b| Modbus {
+                function: FunctionFieldValues::ReadInputStatus.0,
+                reference_number: b.reference_number,
+                bit_count: b.bit_count,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -230,32 +553,17 @@ pub mod read_input_status {
         //! |   Byte Count  |   Data ...
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ByteCount(pub u8);
-    
-        impl ByteCount {
-            pub fn new(val: u8) -> ByteCount {
-                ByteCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for ByteCount {
-            type T = (u8,);
-            fn to_primitive_values(&self) -> (u8,) {
-                (self.0,)
-            }
-        }
+        use super::super::{byte_count, pack_coils, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             pub byte_count: u8,
             #[length_fn = "data_length_b"]
@@ -270,6 +578,16 @@ pub mod read_input_status {
     
             byte_count as usize
         }
+
+        builder! {
+            /// Read Input Status の応答を組み立てる。入力は 8 個ずつ LSB から詰める。
+            Builder(coils: Vec<bool>) |b| Modbus {
+                function: FunctionFieldValues::ReadInputStatus.0,
+                byte_count: byte_count(b.coils.len().div_ceil(8))?,
+                data: pack_coils(&b.coils),
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -283,54 +601,35 @@ pub mod read_holding_register {
         //! |        Reference Number       |           Bit Count           |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct BitCount(pub u16);
-
-        impl BitCount {
-            pub fn new(val: u16) -> BitCount {
-                BitCount(val)
-            }
-        }
-
-        impl PrimitiveValues for BitCount {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub bit_count: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub bit_count: Quantity,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Read Holding Register リクエストを組み立てる。
+            Builder(reference_number: Address, bit_count: Quantity) |b| Modbus {
+                function: FunctionFieldValues::ReadHoldingRegister.0,
+                reference_number: b.reference_number,
+                bit_count: b.bit_count,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -342,32 +641,17 @@ pub mod read_holding_register {
         //! |   Byte Count  |   Data ...
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ByteCount(pub u8);
-    
-        impl ByteCount {
-            pub fn new(val: u8) -> ByteCount {
-                ByteCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for ByteCount {
-            type T = (u8,);
-            fn to_primitive_values(&self) -> (u8,) {
-                (self.0,)
-            }
-        }
+        use super::super::{byte_count, pack_registers, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             pub byte_count: u8,
             #[length_fn = "data_length_b"]
@@ -382,6 +666,16 @@ pub mod read_holding_register {
     
             byte_count as usize
         }
+
+        builder! {
+            /// Read Holding Register の応答を組み立てる。
+            Builder(registers: Vec<u16>) |b| Modbus {
+                function: FunctionFieldValues::ReadHoldingRegister.0,
+                byte_count: byte_count(2 * b.registers.len())?,
+                data: pack_registers(&b.registers),
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -395,54 +689,35 @@ pub mod read_input_register {
         //! |        Reference Number       |           Bit Count           |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct BitCount(pub u16);
-
-        impl BitCount {
-            pub fn new(val: u16) -> BitCount {
-                BitCount(val)
-            }
-        }
-
-        impl PrimitiveValues for BitCount {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub bit_count: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub bit_count: Quantity,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Read Input Register リクエストを組み立てる。
+            Builder(reference_number: Address, bit_count: Quantity) |b| Modbus {
+                function: FunctionFieldValues::ReadInputRegister.0,
+                reference_number: b.reference_number,
+                bit_count: b.bit_count,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -454,32 +729,17 @@ pub mod read_input_register {
         //! |   Byte Count  |   Data ...
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ByteCount(pub u8);
-    
-        impl ByteCount {
-            pub fn new(val: u8) -> ByteCount {
-                ByteCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for ByteCount {
-            type T = (u8,);
-            fn to_primitive_values(&self) -> (u8,) {
-                (self.0,)
-            }
-        }
+        use super::super::{byte_count, pack_registers, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             pub byte_count: u8,
             #[length_fn = "data_length_b"]
@@ -494,6 +754,16 @@ pub mod read_input_register {
     
             byte_count as usize
         }
+
+        builder! {
+            /// Read Input Register の応答を組み立てる。
+            Builder(registers: Vec<u16>) |b| Modbus {
+                function: FunctionFieldValues::ReadInputRegister.0,
+                byte_count: byte_count(2 * b.registers.len())?,
+                data: pack_registers(&b.registers),
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -507,54 +777,35 @@ pub mod force_single_coil {
         //! |        Reference Number       |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-    
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-    
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-    
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-    
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, CoilValue, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub data: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub data: CoilValue,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Force Single Coil リクエストを組み立てる。
+            Builder(reference_number: Address, data: CoilValue) |b| Modbus {
+                function: FunctionFieldValues::ForceSingleCoil.0,
+                reference_number: b.reference_number,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -566,54 +817,35 @@ pub mod force_single_coil {
         //! |        Reference Number       |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-    
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-    
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-    
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-    
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, CoilValue, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub data: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub data: CoilValue,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Force Single Coil の応答を組み立てる。
+            Builder(reference_number: Address, data: CoilValue) |b| Modbus {
+                function: FunctionFieldValues::ForceSingleCoil.0,
+                reference_number: b.reference_number,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -627,54 +859,34 @@ pub mod preset_single_register {
         //! |        Reference Number       |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
             pub data: u16be,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Preset Single Register リクエストを組み立てる。
+            Builder(reference_number: Address, data: u16) |b| Modbus {
+                function: FunctionFieldValues::PresetSingleRegister.0,
+                reference_number: b.reference_number,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -686,54 +898,34 @@ pub mod preset_single_register {
         //! |        Reference Number       |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
             pub data: u16be,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Preset Single Register の応答を組み立てる。
+            Builder(reference_number: Address, data: u16) |b| Modbus {
+                function: FunctionFieldValues::PresetSingleRegister.0,
+                reference_number: b.reference_number,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -747,54 +939,33 @@ pub mod diagnostics {
         //! |            sub code           |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct SubCode(pub u16);
-    
-        impl SubCode {
-            pub fn new(val: u16) -> SubCode {
-                SubCode(val)
-            }
-        }
-    
-        impl PrimitiveValues for SubCode {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-    
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-    
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             pub sub_code: u16be,
             pub data: u16be,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Diagnostics リクエストを組み立てる。
+            Builder(sub_code: u16, data: u16) |b| Modbus {
+                function: FunctionFieldValues::Diagnostics.0,
+                sub_code: b.sub_code,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -807,42 +978,10 @@ pub mod diagnostics {
         //! |            sub code           |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         //use super::FunctionField;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct SubCode(pub u16);
-    
-        impl SubCode {
-            pub fn new(val: u16) -> SubCode {
-                SubCode(val)
-            }
-        }
-    
-        impl PrimitiveValues for SubCode {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-    
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-    
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{FunctionFieldValues, UnitId};
 
         /*
         #[allow(non_snake_case)]
@@ -859,7 +998,8 @@ pub mod diagnostics {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             //#[construct_with(u8)]
             //pub function: FunctionField,
             pub function: u8,
@@ -868,6 +1008,16 @@ pub mod diagnostics {
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Diagnostics の応答を組み立てる。
+            Builder(sub_code: u16, data: u16) |b| Modbus {
+                function: FunctionFieldValues::Diagnostics.0,
+                sub_code: b.sub_code,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -881,19 +1031,29 @@ pub mod fetch_communication_event_counter {
     
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
+        use super::super::{FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             //#[construct_with(u8)]
             //pub function: FunctionField,
             pub function: u8,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Fetch Communication Event Counter リクエストを組み立てる。
+            Builder() |_| Modbus {
+                function: FunctionFieldValues::FetchCommunicationEventCounter.0,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -905,54 +1065,33 @@ pub mod fetch_communication_event_counter {
         //! |             status            |        event counter          |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Status(pub u16);
-
-        impl Status {
-            pub fn new(val: u16) -> Status {
-                Status(val)
-            }
-        }
-
-        impl PrimitiveValues for Status {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct EventCounter(pub u16);
-
-        impl EventCounter {
-            pub fn new(val: u16) -> EventCounter {
-                EventCounter(val)
-            }
-        }
-
-        impl PrimitiveValues for EventCounter {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             pub status: u16be,
             pub event_counter: u16be,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Fetch Communication Event Counter の応答を組み立てる。
+            Builder(status: u16, event_counter: u16) |b| Modbus {
+                function: FunctionFieldValues::FetchCommunicationEventCounter.0,
+                status: b.status,
+                event_counter: b.event_counter,
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -966,17 +1105,27 @@ pub mod fetch_communication_event_counter_log {
 
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
+        use super::super::{FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Fetch Communication Event Counter Log リクエストを組み立てる。
+            Builder() |_| Modbus {
+                function: FunctionFieldValues::FetchCommunicationEventCounterLog.0,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -990,80 +1139,17 @@ pub mod fetch_communication_event_counter_log {
         //!  event counter  |       message counter         |     event ....
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ByteCount(pub u8);
-    
-        impl ByteCount {
-            pub fn new(val: u8) -> ByteCount {
-                ByteCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for ByteCount {
-            type T = (u8,);
-            fn to_primitive_values(&self) -> (u8,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Status(pub u16);
-    
-        impl Status {
-            pub fn new(val: u16) -> Status {
-                Status(val)
-            }
-        }
-    
-        impl PrimitiveValues for Status {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct EventCounter(pub u16);
-    
-        impl EventCounter {
-            pub fn new(val: u16) -> EventCounter {
-                EventCounter(val)
-            }
-        }
-    
-        impl PrimitiveValues for EventCounter {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct MessageCounter(pub u16);
-    
-        impl MessageCounter {
-            pub fn new(val: u16) -> MessageCounter {
-                MessageCounter(val)
-            }
-        }
-    
-        impl PrimitiveValues for MessageCounter {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{byte_count, FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             pub byte_count: u8,
             pub status: u16be,
@@ -1079,7 +1165,20 @@ pub mod fetch_communication_event_counter_log {
         fn data_length_g(modbus: &ModbusPacket) -> usize {
             let byte_count = modbus.get_byte_count();
     
-            byte_count as usize - 6
+            (byte_count as usize).saturating_sub(6)
+        }
+
+        builder! {
+            /// Fetch Communication Event Counter Log の応答を組み立てる。
+            Builder(status: u16, event_counter: u16, message_counter: u16, events: Vec<u8>) |b| Modbus {
+                function: FunctionFieldValues::FetchCommunicationEventCounterLog.0,
+                byte_count: byte_count(6 + b.events.len())?,
+                status: b.status,
+                event_counter: b.event_counter,
+                message_counter: b.message_counter,
+                data: b.events.clone(),
+                payload: vec![],
+            }
         }
     }
 }
@@ -1096,67 +1195,22 @@ pub mod force_multiple_coils {
         //! |  byte count   |             data       ....
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-    
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-    
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct RegisterCount(pub u16);
-    
-        impl RegisterCount {
-            pub fn new(val: u16) -> RegisterCount {
-                RegisterCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for RegisterCount {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ByteCount(pub u8);
-    
-        impl ByteCount {
-            pub fn new(val: u8) -> ByteCount {
-                ByteCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for ByteCount {
-            type T = (u8,);
-            fn to_primitive_values(&self) -> (u8,) {
-                (self.0,)
-            }
-        }
+        use super::super::{byte_count, pack_coils, quantity, Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub register_count: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub register_count: Quantity,
             pub byte_count: u8,
             #[length_fn = "data_length_h" ]
             pub data: Vec<u8>,
@@ -1170,6 +1224,18 @@ pub mod force_multiple_coils {
     
             byte_count as usize
         }
+
+        builder! {
+            /// Force Multiple Coils リクエストを組み立てる。コイル数と byte count は coils から求める。
+            Builder(reference_number: Address, coils: Vec<bool>) |b| Modbus {
+                function: FunctionFieldValues::ForceMultipleCoils.0,
+                reference_number: b.reference_number,
+                register_count: quantity(b.coils.len())?,
+                byte_count: byte_count(b.coils.len().div_ceil(8))?,
+                data: pack_coils(&b.coils),
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -1181,54 +1247,35 @@ pub mod force_multiple_coils {
         //! |        Reference Number       |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-    
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-    
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-    
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-    
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub data: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub data: Quantity,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Force Multiple Coils の応答を組み立てる。
+            Builder(reference_number: Address, data: Quantity) |b| Modbus {
+                function: FunctionFieldValues::ForceMultipleCoils.0,
+                reference_number: b.reference_number,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -1244,67 +1291,22 @@ pub mod preset_multiple_registers {
         //! |  byte count   |             data       ....
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-    
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-    
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct RegisterCount(pub u16);
-    
-        impl RegisterCount {
-            pub fn new(val: u16) -> RegisterCount {
-                RegisterCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for RegisterCount {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ByteCount(pub u8);
-    
-        impl ByteCount {
-            pub fn new(val: u8) -> ByteCount {
-                ByteCount(val)
-            }
-        }
-    
-        impl PrimitiveValues for ByteCount {
-            type T = (u8,);
-            fn to_primitive_values(&self) -> (u8,) {
-                (self.0,)
-            }
-        }
+        use super::super::{byte_count, pack_registers, quantity, Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub register_count: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub register_count: Quantity,
             pub byte_count: u8,
             #[length_fn = "data_length_h" ]
             pub data: Vec<u8>,
@@ -1318,6 +1320,18 @@ pub mod preset_multiple_registers {
     
             byte_count as usize
         }
+
+        builder! {
+            /// Preset Multiple Registers リクエストを組み立てる。レジスタ数と byte count は registers から求める。
+            Builder(reference_number: Address, registers: Vec<u16>) |b| Modbus {
+                function: FunctionFieldValues::PresetMultipleRegisters.0,
+                reference_number: b.reference_number,
+                register_count: quantity(b.registers.len())?,
+                byte_count: byte_count(2 * b.registers.len())?,
+                data: pack_registers(&b.registers),
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -1329,54 +1343,35 @@ pub mod preset_multiple_registers {
         //! |        Reference Number       |             data              |
         //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     
-        use pnet_macros_support::packet::PrimitiveValues;
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ReferenceNumber(pub u16);
-    
-        impl ReferenceNumber {
-            pub fn new(val: u16) -> ReferenceNumber {
-                ReferenceNumber(val)
-            }
-        }
-    
-        impl PrimitiveValues for ReferenceNumber {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
-    
-        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct Data(pub u16);
-    
-        impl Data {
-            pub fn new(val: u16) -> Data {
-                Data(val)
-            }
-        }
-    
-        impl PrimitiveValues for Data {
-            type T = (u16,);
-            fn to_primitive_values(&self) -> (u16,) {
-                (self.0,)
-            }
-        }
+        use super::super::{Address, FunctionFieldValues, Quantity, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
-            pub reference_number: u16be,
-            pub data: u16be,
+            #[construct_with(u16be)]
+            pub reference_number: Address,
+            #[construct_with(u16be)]
+            pub data: Quantity,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Preset Multiple Registers の応答を組み立てる。
+            Builder(reference_number: Address, data: Quantity) |b| Modbus {
+                function: FunctionFieldValues::PresetMultipleRegisters.0,
+                reference_number: b.reference_number,
+                data: b.data,
+                payload: vec![],
+            }
+        }
     }
 }
 
@@ -1390,17 +1385,27 @@ pub mod report_slave_id {
     
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
+        use super::super::{FunctionFieldValues, UnitId};
 
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Report Slave ID リクエストを組み立てる。
+            Builder() |_| Modbus {
+                function: FunctionFieldValues::ReportSlaveID.0,
+                payload: vec![],
+            }
+        }
     }
 
     pub mod reply {
@@ -1412,16 +1417,299 @@ pub mod report_slave_id {
     
         use pnet_macros::packet;
         use pnet_macros_support::types::*;
+        use super::super::{byte_count, FunctionFieldValues, UnitId};
     
         #[packet]
         pub struct Modbus {
             pub transaction: u16be,
             pub protocol: u16be,
             pub length: u16be,
-            pub unit: u8,
+            #[construct_with(u8)]
+            pub unit: UnitId,
             pub function: u8,
             #[payload]
             pub payload: Vec<u8>,
         }
+
+        builder! {
+            /// Report Slave ID の応答を組み立てる。先頭の byte count は data の長さから求め、payload に入れる。
+            Builder(data: Vec<u8>) |b| Modbus {
+                function: FunctionFieldValues::ReportSlaveID.0,
+                payload: [&[byte_count(b.data.len())?][..], &b.data].concat(),
+            }
+        }
+    }
+}
+
+pub mod exception {
+    pub mod reply {
+        //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        //! |          Transaction          |           Protocol            |
+        //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        //! |            Length             |      Unit     | Function+0x80 |
+        //! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        //! | Exception Code|
+        //! +-+-+-+-+-+-+-+-+
+
+        use pnet_macros::packet;
+        use pnet_macros_support::types::*;
+        use super::super::{FunctionField, UnitId, EXCEPTION_BIT};
+
+        #[packet]
+        pub struct Modbus {
+            pub transaction: u16be,
+            pub protocol: u16be,
+            pub length: u16be,
+            #[construct_with(u8)]
+            pub unit: UnitId,
+            pub function: u8,
+            pub exception_code: u8,
+            #[payload]
+            pub payload: Vec<u8>,
+        }
+
+        builder! {
+            /// 例外応答を組み立てる。function には元のファンクションコードを渡す。
+            Builder(function: FunctionField, exception_code: u8) |b| Modbus {
+                function: b.function.0 | EXCEPTION_BIT,
+                exception_code: b.exception_code,
+                payload: vec![],
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::fields::AduFields;
+
+    /// MBAP ヘッダの length が unit 以降のバイト数になっていること
+    fn check_header(bytes: &[u8], transaction: u16, unit: u8, function: u8) {
+        let modbus = ModbusTCPPacket::new(bytes).unwrap();
+        assert_eq!(modbus.get_transaction(), transaction);
+        assert_eq!(modbus.get_protocol(), 0);
+        assert_eq!(modbus.get_length() as usize, bytes.len() - 6);
+        assert_eq!(modbus.get_unit(), UnitId(unit));
+        assert_eq!(modbus.get_function(), FunctionField(function));
+    }
+
+    #[test]
+    fn read_requests() {
+        let bytes = read_coil_status::request::Builder::new(Address(100), Quantity(10))
+            .transaction(7)
+            .unit(UnitId(3))
+            .to_bytes()
+            .unwrap();
+        assert_eq!(bytes, [0, 7, 0, 0, 0, 6, 3, 1, 0, 100, 0, 10]);
+        let bytes = read_input_status::request::Builder::new(Address(1), Quantity(2000))
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 2);
+        let bytes = read_holding_register::request::Builder::new(Address(40), Quantity(125))
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 3);
+        let fields = AduFields::decode(&bytes, true).unwrap();
+        assert_eq!((fields.address, fields.quantity), (Some(40), Some(125)));
+        let bytes = read_input_register::request::Builder::new(Address(0), Quantity(1))
+            .unit(UnitId(255))
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 255, 4);
+        let m_packet = read_input_register::request::ModbusPacket::new(&bytes).unwrap();
+        assert_eq!(m_packet.get_reference_number(), Address(0));
+        assert_eq!(m_packet.get_bit_count(), Quantity(1));
+    }
+
+    #[test]
+    fn read_coil_replies() {
+        let coils = vec![true, false, true, true, false, false, false, false, true];
+        let bytes = read_coil_status::reply::Builder::new(coils.clone())
+            .transaction(1)
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 1, 0, 1);
+        let m_packet = read_coil_status::reply::ModbusPacket::new(&bytes).unwrap();
+        assert_eq!(m_packet.get_byte_count(), 2);
+        assert_eq!(m_packet.get_data(), [0b0000_1101, 0b0000_0001]);
+        let fields = AduFields::decode(&bytes, false).unwrap();
+        let expected: Vec<u16> = coils.iter().map(|&c| c as u16).collect();
+        assert_eq!(fields.values[..coils.len()], expected[..]);
+
+        let bytes = read_input_status::reply::Builder::new(vec![true; 8])
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 2);
+        assert_eq!(AduFields::decode(&bytes, false).unwrap().values, vec![1; 8]);
+    }
+
+    #[test]
+    fn read_register_replies() {
+        let registers = vec![0, 1, 0x1234, 0xffff];
+        let bytes = read_holding_register::reply::Builder::new(registers.clone())
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 3);
+        assert_eq!(bytes[8], 8);
+        assert_eq!(AduFields::decode(&bytes, false).unwrap().values, registers);
+        let bytes = read_input_register::reply::Builder::new(registers.clone())
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 4);
+        assert_eq!(AduFields::decode(&bytes, false).unwrap().values, registers);
+    }
+
+    #[test]
+    fn single_writes() {
+        for is_request in [true, false] {
+            let bytes = if is_request {
+                force_single_coil::request::Builder::new(Address(5), CoilValue::from_bool(true))
+                    .to_bytes()
+            } else {
+                force_single_coil::reply::Builder::new(Address(5), CoilValue::ON).to_bytes()
+            }
+            .unwrap();
+            check_header(&bytes, 0, 0, 5);
+            assert_eq!(bytes[10..], [0xff, 0x00]);
+            let fields = AduFields::decode(&bytes, is_request).unwrap();
+            assert_eq!((fields.address, fields.values), (Some(5), vec![1]));
+
+            let bytes = if is_request {
+                preset_single_register::request::Builder::new(Address(10), 500).to_bytes()
+            } else {
+                preset_single_register::reply::Builder::new(Address(10), 500).to_bytes()
+            }
+            .unwrap();
+            check_header(&bytes, 0, 0, 6);
+            let fields = AduFields::decode(&bytes, is_request).unwrap();
+            assert_eq!((fields.address, fields.values), (Some(10), vec![500]));
+        }
+    }
+
+    #[test]
+    fn diagnostics_and_event_counters() {
+        let bytes = diagnostics::request::Builder::new(0, 0xa537).to_bytes().unwrap();
+        check_header(&bytes, 0, 0, 8);
+        let m_packet = diagnostics::request::ModbusPacket::new(&bytes).unwrap();
+        assert_eq!((m_packet.get_sub_code(), m_packet.get_data()), (0, 0xa537));
+        let bytes = diagnostics::reply::Builder::new(10, 0).to_bytes().unwrap();
+        let m_packet = diagnostics::reply::ModbusPacket::new(&bytes).unwrap();
+        assert_eq!((m_packet.get_sub_code(), m_packet.get_data()), (10, 0));
+
+        let bytes = fetch_communication_event_counter::request::Builder::new()
+            .to_bytes()
+            .unwrap();
+        assert_eq!(bytes, [0, 0, 0, 0, 0, 2, 0, 11]);
+        let bytes = fetch_communication_event_counter::reply::Builder::new(0xffff, 0x108)
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 11);
+        let m_packet = fetch_communication_event_counter::reply::ModbusPacket::new(&bytes).unwrap();
+        assert_eq!((m_packet.get_status(), m_packet.get_event_counter()), (0xffff, 0x108));
+
+        let bytes = fetch_communication_event_counter_log::request::Builder::new()
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 12);
+        let bytes =
+            fetch_communication_event_counter_log::reply::Builder::new(0, 0x108, 0x121, vec![0x20, 0])
+                .to_bytes()
+                .unwrap();
+        check_header(&bytes, 0, 0, 12);
+        let m_packet =
+            fetch_communication_event_counter_log::reply::ModbusPacket::new(&bytes).unwrap();
+        assert_eq!(m_packet.get_byte_count(), 8);
+        assert_eq!(m_packet.get_message_counter(), 0x121);
+        assert_eq!(m_packet.get_data(), [0x20, 0]);
+    }
+
+    #[test]
+    fn multiple_writes() {
+        let coils = vec![true, false, true, true, false, false, true, true, true, false];
+        let bytes = force_multiple_coils::request::Builder::new(Address(19), coils.clone())
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 15);
+        let m_packet = force_multiple_coils::request::ModbusPacket::new(&bytes).unwrap();
+        assert_eq!(m_packet.get_register_count(), Quantity(10));
+        assert_eq!(m_packet.get_byte_count(), 2);
+        assert_eq!(m_packet.get_data(), [0xcd, 0x01]);
+        let fields = AduFields::decode(&bytes, true).unwrap();
+        let expected: Vec<u16> = coils.iter().map(|&c| c as u16).collect();
+        assert_eq!((fields.address, fields.values), (Some(19), expected));
+        let bytes = force_multiple_coils::reply::Builder::new(Address(19), Quantity(10))
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 15);
+        let fields = AduFields::decode(&bytes, false).unwrap();
+        assert_eq!((fields.address, fields.quantity), (Some(19), Some(10)));
+
+        let registers = vec![10, 258, 0xffff];
+        let bytes = preset_multiple_registers::request::Builder::new(Address(1), registers.clone())
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 16);
+        let fields = AduFields::decode(&bytes, true).unwrap();
+        assert_eq!(fields.quantity, Some(3));
+        assert_eq!(fields.values, registers);
+        let bytes = preset_multiple_registers::reply::Builder::new(Address(1), Quantity(3))
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 16);
+        let fields = AduFields::decode(&bytes, false).unwrap();
+        assert_eq!((fields.address, fields.quantity), (Some(1), Some(3)));
+    }
+
+    #[test]
+    fn report_slave_id_and_exception() {
+        let bytes = report_slave_id::request::Builder::new().to_bytes().unwrap();
+        check_header(&bytes, 0, 0, 17);
+        let bytes = report_slave_id::reply::Builder::new(vec![0x42, 0xff])
+            .to_bytes()
+            .unwrap();
+        check_header(&bytes, 0, 0, 17);
+        assert_eq!(bytes[8..], [2, 0x42, 0xff]);
+
+        let bytes = exception::reply::Builder::new(FunctionFieldValues::ReadHoldingRegister, 2)
+            .unit(UnitId(1))
+            .to_bytes()
+            .unwrap();
+        assert_eq!(bytes, [0, 0, 0, 0, 0, 3, 1, 0x83, 2]);
+        let fields = AduFields::decode(&bytes, false).unwrap();
+        assert_eq!((fields.function, fields.exception_code), (3, Some(2)));
+    }
+
+    #[test]
+    fn adu_length() {
+        let bytes = preset_multiple_registers::request::Builder::new(Address(0), vec![1])
+            .to_bytes()
+            .unwrap();
+        assert_eq!(check_adu_length(&bytes, true), Ok(()));
+        /* length が実際のバイト数と合わない */
+        assert!(check_adu_length(&bytes[..bytes.len() - 1], true).is_err());
+        /* length は合っているが Register Count 以降が欠けている */
+        assert!(check_adu_length(&[0, 1, 0, 0, 0, 4, 1, 16, 0, 0], true).is_err());
+        assert!(check_adu_length(&[0, 1, 0, 0, 0, 2, 1, 0x83], false).is_err());
+        assert_eq!(check_adu_length(&[0, 1, 0, 0, 0, 3, 1, 0x83, 2], false), Ok(()));
+        assert!(check_adu_length(&[0, 1, 0, 0, 0, 1, 1], true).is_err());
+    }
+
+    #[test]
+    fn too_long_pdu() {
+        /* 124 レジスタは 6 + 248 = 254 バイトで PDU の上限を超える */
+        assert!(preset_multiple_registers::request::Builder::new(Address(0), vec![0; 123])
+            .to_bytes()
+            .is_ok());
+        assert!(preset_multiple_registers::request::Builder::new(Address(0), vec![0; 124])
+            .to_bytes()
+            .is_err());
+        /* byte count は 1 バイトなので 256 バイト以上の data は切り詰めずに Err */
+        assert!(read_holding_register::reply::Builder::new(vec![0; 128])
+            .to_bytes()
+            .is_err());
+        assert!(report_slave_id::reply::Builder::new(vec![0; 300])
+            .to_bytes()
+            .is_err());
     }
 }
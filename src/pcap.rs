@@ -0,0 +1,271 @@
+//! pcap 形式 (libpcap のファイル形式) のキャプチャファイルを読む。
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                         Magic Number                          |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |        Version Major          |        Version Minor          |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                      Reserved (2 x 32bit)                     |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                           SnapLen                             |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                           LinkType                            |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!
+//! 続いて、レコードごとにタイムスタンプ (秒, マイクロ秒かナノ秒),
+//! 記録した長さ, 元の長さの 16 バイトのヘッダとデータが並ぶ。
+//! 書き出しはリトルエンディアン、マイクロ秒の形式にする。
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+pub const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LOOP: u32 = 108;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+pub const LINKTYPE_IPV4: u32 = 228;
+pub const LINKTYPE_IPV6: u32 = 229;
+pub const LINKTYPE_LINUX_SLL2: u32 = 276;
+/// USB の RS-485 スニファなどが使うユーザ定義のリンクタイプ (DLT_USER0 から DLT_USER15)
+pub const LINKTYPE_USER0: u32 = 147;
+pub const LINKTYPE_USER15: u32 = 162;
+
+const MAGIC_MICROSECONDS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOSECONDS: u32 = 0xa1b2_3c4d;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 262_144;
+
+pub struct Record {
+    /// 1970-01-01 からの経過時間
+    pub timestamp: Duration,
+    /// キャプチャ前の元の長さ (snaplen で切られていれば data より長い)
+    pub original_length: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct PcapReader<R: Read> {
+    reader: R,
+    swapped: bool,
+    nanoseconds: bool,
+    snaplen: u32,
+    linktype: u32,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: Read> PcapReader<R> {
+    /// ファイルヘッダを読み、pcap 形式でなければエラーを返す
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (swapped, nanoseconds) = if magic == MAGIC_MICROSECONDS {
+            (false, false)
+        } else if magic == MAGIC_NANOSECONDS {
+            (false, true)
+        } else if magic.swap_bytes() == MAGIC_MICROSECONDS {
+            (true, false)
+        } else if magic.swap_bytes() == MAGIC_NANOSECONDS {
+            (true, true)
+        } else {
+            return Err(invalid_data(format!("not a pcap file (magic {:#010x})", magic)));
+        };
+        let mut pcap = PcapReader {
+            reader,
+            swapped,
+            nanoseconds,
+            snaplen: 0,
+            linktype: 0,
+        };
+        pcap.snaplen = pcap.u32_at(&header, 16);
+        pcap.linktype = pcap.u32_at(&header, 20);
+        Ok(pcap)
+    }
+
+    pub fn linktype(&self) -> u32 {
+        self.linktype
+    }
+
+    fn u32_at(&self, buf: &[u8], offset: usize) -> u32 {
+        let val = u32::from_le_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ]);
+        if self.swapped {
+            val.swap_bytes()
+        } else {
+            val
+        }
+    }
+
+    /// 次のレコードを読む。ファイルの終わりでは None
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let seconds = self.u32_at(&header, 0) as u64;
+        let fraction = self.u32_at(&header, 4);
+        let captured_length = self.u32_at(&header, 8);
+        let original_length = self.u32_at(&header, 12);
+        /* 壊れたヘッダで巨大なバッファを確保しないよう、snaplen (小さければ書き出しと同じ上限) までにする */
+        if captured_length > self.snaplen.max(SNAPLEN) {
+            return Err(invalid_data(format!(
+                "record of {} bytes exceeds the snaplen {}",
+                captured_length, self.snaplen
+            )));
+        }
+        let nanoseconds = if self.nanoseconds {
+            Some(fraction).filter(|&nanoseconds| nanoseconds < 1_000_000_000)
+        } else {
+            fraction.checked_mul(1000).filter(|&nanoseconds| nanoseconds < 1_000_000_000)
+        };
+        let nanoseconds = nanoseconds
+            .ok_or_else(|| invalid_data(format!("invalid fraction of a second {}", fraction)))?;
+        let mut data = vec![0u8; captured_length as usize];
+        self.reader.read_exact(&mut data)?;
+        let timestamp = Duration::new(seconds, nanoseconds);
+        Ok(Some(Record {
+            timestamp,
+            original_length,
+            data,
+        }))
+    }
+}
+
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    /// ファイルヘッダに書いたリンクタイプ
+    linktype: u32,
+    /// リンクタイプが違うので書かなかったレコードの数
+    dropped: u64,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// ファイルヘッダを書いて作る。レコードを 1 つも書かなくても pcap ファイルとして読める
+    pub fn new(mut writer: W, linktype: u32) -> io::Result<PcapWriter<W>> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_MICROSECONDS.to_le_bytes());
+        header.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+        header.extend_from_slice(&[0u8; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&linktype.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(PcapWriter {
+            writer,
+            linktype,
+            dropped: 0,
+        })
+    }
+
+    pub fn linktype(&self) -> u32 {
+        self.linktype
+    }
+
+    /// リンクタイプが違うので書かなかったレコードの数
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// レコードを書く。1 つのファイルにはリンクタイプを 1 つしか書けないので、
+    /// ファイルヘッダと違うリンクタイプのものは書かずに数え、false を返す
+    pub fn write_record(
+        &mut self,
+        linktype: u32,
+        timestamp: Duration,
+        original_length: u32,
+        data: &[u8],
+    ) -> io::Result<bool> {
+        if linktype != self.linktype {
+            self.dropped += 1;
+            return Ok(false);
+        }
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        header.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        header.extend_from_slice(&original_length.max(data.len() as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)?;
+        Ok(true)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcap_file(records: &[([u32; 4], &[u8])]) -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(&MAGIC_MICROSECONDS.to_le_bytes());
+        file.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+        file.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+        file.extend_from_slice(&[0u8; 8]);
+        file.extend_from_slice(&65535u32.to_le_bytes());
+        file.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        for (header, data) in records {
+            for field in header {
+                file.extend_from_slice(&field.to_le_bytes());
+            }
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    #[test]
+    fn records() {
+        let data = [1, 3, 0, 0, 0, 2, 0xc4, 0x0b];
+        let file = pcap_file(&[([1_700_000_000, 999_999, 8, 8], &data)]);
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        assert_eq!(reader.linktype(), LINKTYPE_USER0);
+        let record = reader.next_record().unwrap().unwrap();
+        assert_eq!(record.timestamp, Duration::new(1_700_000_000, 999_999_000));
+        assert_eq!(record.data, data);
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_record_headers() {
+        /* マイクロ秒が 1 秒以上 (1000 倍すると u32 を超える) */
+        let file = pcap_file(&[([0, 4_294_967, 1, 1], &[0])]);
+        let error = PcapReader::new(&file[..]).unwrap().next_record().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        /* snaplen を超える長さのバッファは確保しない */
+        let file = pcap_file(&[([0, 0, 0xffff_ffff, 0xffff_ffff], &[])]);
+        let error = PcapReader::new(&file[..]).unwrap().next_record().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writer() {
+        /* レコードがなくてもファイルヘッダだけの pcap ファイルになる */
+        let mut file = Vec::new();
+        PcapWriter::new(&mut file, LINKTYPE_USER0).unwrap();
+        assert_eq!(file.len(), 24);
+        assert!(PcapReader::new(&file[..]).unwrap().next_record().unwrap().is_none());
+        /* ファイルヘッダと違うリンクタイプのレコードは書かずに数える */
+        let mut file = Vec::new();
+        let mut writer = PcapWriter::new(&mut file, LINKTYPE_USER0).unwrap();
+        let timestamp = Duration::new(1_700_000_000, 5_000);
+        assert!(writer.write_record(LINKTYPE_USER0, timestamp, 2, &[1, 2]).unwrap());
+        assert!(!writer.write_record(1, timestamp, 2, &[3, 4]).unwrap());
+        assert!(!writer.write_record(1, timestamp, 2, &[5, 6]).unwrap());
+        assert_eq!(writer.dropped(), 2);
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        let record = reader.next_record().unwrap().unwrap();
+        assert_eq!((record.timestamp, record.data), (timestamp, vec![1, 2]));
+        assert!(reader.next_record().unwrap().is_none());
+    }
+}
@@ -0,0 +1,286 @@
+//! IPv4 のフラグメントと IPv6 の Fragment ヘッダで分割されたデータグラムを組み立て直す。
+//! 一定時間そろわなかったデータグラムは捨て、保持するフラグメントの合計が上限を超えたら
+//! 古いデータグラムから捨てる。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// フラグメントがそろうまで待つ時間
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 保持するフラグメントの合計の上限
+pub const DEFAULT_MEMORY_LIMIT: usize = 4 * 1024 * 1024;
+/// 組み立て後のデータグラム (IP ヘッダを除く) の最大長
+const MAX_DATAGRAM: usize = 65535;
+
+/// 同じデータグラムのフラグメントをまとめるためのキー
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub vlans: Vec<u16>,
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: u8,
+    pub identification: u32,
+}
+
+struct Datagram {
+    /// 最初のフラグメントを受け取った時刻
+    first_seen: Duration,
+    /// (オフセット, データ)
+    fragments: Vec<(usize, Vec<u8>)>,
+    /// 最後のフラグメントを受け取っていれば全体の長さ
+    total_length: Option<usize>,
+    size: usize,
+}
+
+impl Datagram {
+    /// 全体がそろっていれば組み立てたデータを返す
+    fn assemble(&mut self) -> Option<Vec<u8>> {
+        let total_length = self.total_length?;
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut covered = 0;
+        for (offset, data) in &self.fragments {
+            if *offset > covered {
+                return None;
+            }
+            covered = covered.max(offset + data.len());
+        }
+        if covered < total_length {
+            return None;
+        }
+        let mut datagram = vec![0u8; total_length];
+        /* 重なった部分は先に来たオフセットのデータを残す */
+        for (offset, data) in self.fragments.iter().rev() {
+            let end = (offset + data.len()).min(total_length);
+            if *offset < end {
+                datagram[*offset..end].copy_from_slice(&data[..end - offset]);
+            }
+        }
+        Some(datagram)
+    }
+}
+
+pub struct Reassembler {
+    pending: HashMap<FragmentKey, Datagram>,
+    timeout: Duration,
+    memory_limit: usize,
+    memory: usize,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, memory_limit: usize) -> Reassembler {
+        Reassembler {
+            pending: HashMap::new(),
+            timeout,
+            memory_limit,
+            memory: 0,
+        }
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<Datagram> {
+        let datagram = self.pending.remove(key)?;
+        self.memory -= datagram.size;
+        Some(datagram)
+    }
+
+    /// 時間切れのデータグラムを捨てる
+    fn expire(&mut self, now: Duration) {
+        let timeout = self.timeout;
+        let expired: Vec<FragmentKey> = self
+            .pending
+            .iter()
+            .filter(|(_, datagram)| now.saturating_sub(datagram.first_seen) > timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    /// 上限に収まるまで古いデータグラムから捨てる
+    fn evict(&mut self, incoming: usize) {
+        while self.memory + incoming > self.memory_limit {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, datagram)| datagram.first_seen)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => {
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// フラグメントを追加する。データグラム全体がそろえば組み立てたペイロードを返す。
+    /// offset はバイト単位、more は後続のフラグメントがあるかどうか。
+    pub fn push(
+        &mut self,
+        now: Duration,
+        key: FragmentKey,
+        offset: usize,
+        more: bool,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        self.expire(now);
+        if offset + data.len() > MAX_DATAGRAM || data.len() > self.memory_limit {
+            self.remove(&key);
+            return None;
+        }
+        self.evict(data.len());
+        let datagram = self.pending.entry(key.clone()).or_insert_with(|| Datagram {
+            first_seen: now,
+            fragments: Vec::new(),
+            total_length: None,
+            size: 0,
+        });
+        if !more {
+            datagram.total_length = Some(offset + data.len());
+        }
+        datagram.fragments.push((offset, data.to_vec()));
+        datagram.size += data.len();
+        self.memory += data.len();
+        let assembled = datagram.assemble();
+        if assembled.is_some() {
+            self.remove(&key);
+        }
+        assembled
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new(DEFAULT_TIMEOUT, DEFAULT_MEMORY_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ipv6::FragmentPacket;
+
+    fn key(identification: u32) -> FragmentKey {
+        FragmentKey {
+            vlans: Vec::new(),
+            source: "192.168.0.1".parse().unwrap(),
+            destination: "192.168.0.2".parse().unwrap(),
+            protocol: 17,
+            identification,
+        }
+    }
+
+    fn seconds(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(seconds(0), key(1), 16, false, &[3; 4]), None);
+        assert_eq!(reassembler.push(seconds(0), key(1), 0, true, &[1; 8]), None);
+        let datagram = reassembler.push(seconds(1), key(1), 8, true, &[2; 8]).unwrap();
+        assert_eq!(datagram, [vec![1; 8], vec![2; 8], vec![3; 4]].concat());
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.memory, 0);
+    }
+
+    #[test]
+    fn overlapping() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(seconds(0), key(1), 8, false, &[2; 16]), None);
+        /* 重なった部分は先に来たオフセットのデータを残す */
+        let datagram = reassembler.push(seconds(0), key(1), 0, true, &[1; 16]).unwrap();
+        assert_eq!(datagram, [vec![1; 16], vec![2; 8]].concat());
+        /* 同じフラグメントが重ねて来ても組み立てられる */
+        assert_eq!(reassembler.push(seconds(0), key(2), 0, true, &[1; 8]), None);
+        assert_eq!(reassembler.push(seconds(0), key(2), 0, true, &[1; 8]), None);
+        assert_eq!(reassembler.push(seconds(0), key(2), 8, false, &[2; 8]).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn missing_last() {
+        let mut reassembler = Reassembler::default();
+        for offset in (0..64).step_by(8) {
+            assert_eq!(reassembler.push(seconds(0), key(1), offset, true, &[0; 8]), None);
+        }
+        /* 最後のフラグメントがそろっても途中が欠けていれば渡さない */
+        assert_eq!(reassembler.push(seconds(0), key(2), 0, true, &[0; 8]), None);
+        assert_eq!(reassembler.push(seconds(0), key(2), 16, false, &[0; 8]), None);
+        assert_eq!(reassembler.pending.len(), 2);
+        assert_eq!(reassembler.memory, 64 + 16);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(DEFAULT_TIMEOUT, seconds(30));
+        assert_eq!(reassembler.push(seconds(100), key(1), 0, true, &[1; 8]), None);
+        assert_eq!(reassembler.push(seconds(100), key(2), 0, true, &[1; 8]), None);
+        /* 30 秒ちょうどならまだ待つ */
+        assert!(reassembler.push(seconds(130), key(1), 8, false, &[2; 8]).is_some());
+        /* 30 秒を過ぎたものは捨て、後から来た残りだけでは組み立てない */
+        assert_eq!(reassembler.push(seconds(131), key(2), 8, false, &[2; 8]), None);
+        assert_eq!(reassembler.pending.len(), 1);
+        assert_eq!(reassembler.memory, 8);
+        reassembler.expire(seconds(200));
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.memory, 0);
+    }
+
+    #[test]
+    fn memory_limit() {
+        assert_eq!(DEFAULT_MEMORY_LIMIT, 4 * 1024 * 1024);
+        let mut reassembler = Reassembler::new(DEFAULT_TIMEOUT, 24);
+        assert_eq!(reassembler.push(seconds(2), key(1), 0, true, &[1; 8]), None);
+        assert_eq!(reassembler.push(seconds(1), key(2), 0, true, &[1; 8]), None);
+        assert_eq!(reassembler.push(seconds(3), key(3), 0, true, &[1; 8]), None);
+        /* 上限を超えるので最初に受け取った時刻が最も古い 2 を捨てる */
+        assert_eq!(reassembler.push(seconds(4), key(4), 0, true, &[1; 8]), None);
+        assert!(!reassembler.pending.contains_key(&key(2)));
+        assert_eq!(reassembler.memory, 24);
+        /* 次に古いのは 1 自身なので、先のフラグメントが捨てられて組み立てられない */
+        assert!(reassembler.push(seconds(4), key(1), 8, false, &[2; 8]).is_none());
+        assert_eq!(reassembler.pending[&key(1)].fragments, vec![(8, vec![2; 8])]);
+        assert_eq!(reassembler.memory, 24);
+        /* 上限より大きいフラグメントや 65535 バイトを超えるデータグラムは捨てる */
+        assert_eq!(reassembler.push(seconds(5), key(4), 8, false, &[2; 25]), None);
+        assert!(!reassembler.pending.contains_key(&key(4)));
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(seconds(0), key(5), 0, true, &[1; 8]), None);
+        assert_eq!(reassembler.push(seconds(0), key(5), 65532, false, &[2; 8]), None);
+        assert!(reassembler.pending.is_empty());
+        /* 捨てるものがなければそのまま抜ける */
+        reassembler.evict(DEFAULT_MEMORY_LIMIT + 1);
+        assert_eq!(reassembler.memory, 0);
+    }
+
+    #[test]
+    fn ipv6_fragments() {
+        /* Fragment ヘッダ: 次のヘッダ UDP, オフセット 8 バイト単位 + M フラグ, 識別子 32 ビット */
+        let first = [17, 0, 0x00, 0x01, 0x12, 0x34, 0x56, 0x78];
+        let last = [17, 0, 0x00, 0x08, 0x12, 0x34, 0x56, 0x78];
+        let mut reassembler = Reassembler::default();
+        let mut datagram = None;
+        for (header, data) in [(&last, &[2u8; 4][..]), (&first, &[1u8; 8][..])] {
+            let fragment = FragmentPacket::new(header).unwrap();
+            let key = FragmentKey {
+                vlans: vec![10],
+                source: "2001:db8::1".parse().unwrap(),
+                destination: "2001:db8::2".parse().unwrap(),
+                protocol: fragment.get_next_header().0,
+                identification: fragment.get_id(),
+            };
+            assert_eq!(key.identification, 0x12345678);
+            datagram = reassembler.push(
+                seconds(0),
+                key,
+                fragment.get_fragment_offset() as usize,
+                !fragment.is_last_fragment(),
+                data,
+            );
+        }
+        assert_eq!(datagram.unwrap(), [vec![1; 8], vec![2; 4]].concat());
+    }
+}
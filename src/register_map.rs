@@ -0,0 +1,562 @@
+//! --register-map のレジスタマップ。デバイス (サーバ側の IP アドレス、シリアルバスなら "serial") と
+//! unit ごとに、コイル・レジスタの番号の範囲にタグ名、データ型、スケール、単位、説明を付け、
+//! 読み書きした値を "TankLevel = 73.4 %" のように表す。
+//!
+//! ファイルは拡張子が .csv なら見出しの行に続けて 1 行に 1 つ、それ以外は TOML の [[register]] の表で書く。
+//! キー (列) は次のとおりで、table, address, name は必須。
+//!
+//! | キー        | 内容                                                                    |
+//! |-------------|-------------------------------------------------------------------------|
+//! | device      | IP アドレスか "serial" ("*" か省略ですべて)                             |
+//! | unit        | unit ID ("*" か省略ですべて)                                            |
+//! | table       | coil, discrete_input, input_register, holding_register                  |
+//! | address     | PDU 上の 0 始まりの番号か、両端を含む範囲 "100..109"                    |
+//! | name        | タグ名。範囲に型の値が複数入るときは "Name[0]" のように番号を付ける     |
+//! | type        | bool, u16, i16, u32, i32, f32 (既定はコイルで bool、レジスタで u16)     |
+//! | scale       | 値に掛ける数 (既定 1)                                                   |
+//! | offset      | 掛けた後に足す数 (既定 0)                                               |
+//! | units       | 単位 ("%" など)                                                         |
+//! | description | 説明                                                                    |
+//!
+//! 32 ビットの型は上位ワードが先の 2 レジスタ。同じ番号に当てはまるものが複数あれば先に書いたものを使う。
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+use super::packet::fields::{AduFields, Table};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DataType {
+    Bool,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl DataType {
+    fn parse(name: &str) -> Result<DataType, String> {
+        match name {
+            "bool" => Ok(DataType::Bool),
+            "u16" => Ok(DataType::U16),
+            "i16" => Ok(DataType::I16),
+            "u32" => Ok(DataType::U32),
+            "i32" => Ok(DataType::I32),
+            "f32" => Ok(DataType::F32),
+            _ => Err(format!("unknown type: {}", name)),
+        }
+    }
+
+    /// 値 1 つが使うコイル・レジスタの数
+    fn width(self) -> usize {
+        match self {
+            DataType::Bool | DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::I32 | DataType::F32 => 2,
+        }
+    }
+
+    fn decode(self, words: &[u16]) -> f64 {
+        let long = || ((words[0] as u32) << 16) | words[1] as u32;
+        match self {
+            DataType::Bool => (words[0] != 0) as u8 as f64,
+            DataType::U16 => words[0] as f64,
+            DataType::I16 => words[0] as i16 as f64,
+            DataType::U32 => long() as f64,
+            DataType::I32 => long() as i32 as f64,
+            DataType::F32 => f32::from_bits(long()) as f64,
+        }
+    }
+}
+
+fn parse_table(name: &str) -> Result<Table, String> {
+    match name {
+        "coil" => Ok(Table::Coil),
+        "discrete_input" => Ok(Table::DiscreteInput),
+        "input_register" => Ok(Table::InputRegister),
+        "holding_register" => Ok(Table::HoldingRegister),
+        _ => Err(format!("unknown table: {}", name)),
+    }
+}
+
+/// レジスタマップの 1 つの範囲
+#[derive(Debug)]
+struct Entry {
+    /// None ならすべてのデバイス
+    device: Option<String>,
+    unit: Option<u8>,
+    table: Table,
+    /// 両端を含む範囲
+    first: u16,
+    last: u16,
+    name: String,
+    data_type: DataType,
+    scale: f64,
+    offset: f64,
+    units: String,
+    description: String,
+}
+
+impl Entry {
+    /// キーと値の組から作る
+    fn new(values: &HashMap<String, String>) -> Result<Entry, String> {
+        let get = |key: &str| values.get(key).map(|v| v.trim()).filter(|v| !v.is_empty());
+        let required = |key: &str| get(key).ok_or_else(|| format!("{} is required", key));
+        let float = |key: &str, default: f64| match get(key) {
+            Some(text) => text
+                .parse::<f64>()
+                .map_err(|_| format!("invalid {}: {}", key, text)),
+            None => Ok(default),
+        };
+        let address = |text: &str| {
+            text.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid address: {}", text))
+        };
+        for key in values.keys() {
+            if !KEYS.contains(&&key[..]) {
+                return Err(format!("unknown key: {}", key));
+            }
+        }
+
+        let table = parse_table(required("table")?)?;
+        let range = required("address")?;
+        let (first, last) = match range.split_once("..") {
+            Some((first, last)) => (address(first)?, address(last)?),
+            None => (address(range)?, address(range)?),
+        };
+        let data_type = match (get("type"), table) {
+            (Some(name), _) => DataType::parse(name)?,
+            (None, Table::Coil) | (None, Table::DiscreteInput) => DataType::Bool,
+            (None, _) => DataType::U16,
+        };
+        let coils = matches!(table, Table::Coil | Table::DiscreteInput);
+        if coils && data_type != DataType::Bool {
+            return Err(format!("{} holds only bool", table.name()));
+        }
+        let length = (last as usize + 1).saturating_sub(first as usize);
+        if length == 0 {
+            return Err(format!("address {}..{} is empty", first, last));
+        }
+        if !length.is_multiple_of(data_type.width()) {
+            return Err(format!(
+                "address {}..{} is not a multiple of {} registers",
+                first,
+                last,
+                data_type.width()
+            ));
+        }
+        Ok(Entry {
+            device: get("device").filter(|d| *d != "*").map(String::from),
+            unit: match get("unit").filter(|u| *u != "*") {
+                Some(text) => Some(text.parse().map_err(|_| format!("invalid unit: {}", text))?),
+                None => None,
+            },
+            table,
+            first,
+            last,
+            name: required("name")?.to_string(),
+            data_type,
+            scale: float("scale", 1.0)?,
+            offset: float("offset", 0.0)?,
+            units: get("units").unwrap_or("").to_string(),
+            description: get("description").unwrap_or("").to_string(),
+        })
+    }
+
+    fn matches(&self, device: &str, fields: &AduFields, table: Table) -> bool {
+        self.table == table
+            && self.device.as_deref().is_none_or(|d| d == device)
+            && self.unit.is_none_or(|u| u == fields.unit)
+    }
+}
+
+const KEYS: [&str; 10] = [
+    "device",
+    "unit",
+    "table",
+    "address",
+    "name",
+    "type",
+    "scale",
+    "offset",
+    "units",
+    "description",
+];
+
+/// タグの付いた値
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagValue {
+    pub name: String,
+    /// スケールとオフセットを適用した値
+    pub value: f64,
+    pub units: String,
+    pub description: String,
+}
+
+impl fmt::Display for TagValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} = {}", self.name, number(self.value))?;
+        if !self.units.is_empty() {
+            write!(f, " {}", self.units)?;
+        }
+        Ok(())
+    }
+}
+
+/// 小数点以下 6 桁までで、末尾の 0 を落とす (0.1 倍した値が 73.39999999 にならないように)
+pub fn number(value: f64) -> String {
+    let text = format!("{:.6}", value);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    if text == "-0" {
+        "0".to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+#[derive(Debug)]
+pub struct RegisterMap {
+    entries: Vec<Entry>,
+}
+
+impl RegisterMap {
+    /// ファイルを読む。誤りがあれば行番号付きのメッセージを返す
+    pub fn open(path: &str) -> Result<RegisterMap, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        RegisterMap::parse(&text, path.to_ascii_lowercase().ends_with(".csv"))
+    }
+
+    /// 読んだ文字列から作る。csv が false なら TOML として読む
+    fn parse(text: &str, csv: bool) -> Result<RegisterMap, String> {
+        let tables = if csv { parse_csv(text)? } else { parse_toml(text)? };
+        let entries = tables
+            .iter()
+            .map(|(line, values)| Entry::new(values).map_err(|e| format!("line {}: {}", line, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(RegisterMap { entries })
+    }
+
+    /// 読み書きした値にタグを付ける。device はサーバ側のアドレス (シリアルバスなら "serial")
+    pub fn annotate(&self, device: &str, fields: &AduFields) -> Vec<TagValue> {
+        let (table, address) = match (fields.table(), fields.address) {
+            (Some(table), Some(address)) if !fields.values.is_empty() => (table, address as usize),
+            _ => return Vec::new(),
+        };
+        let end = address + fields.values.len();
+        let mut tags: Vec<(usize, TagValue)> = Vec::new();
+        for entry in self
+            .entries
+            .iter()
+            .filter(|entry| entry.matches(device, fields, table))
+        {
+            let width = entry.data_type.width();
+            let count = (entry.last - entry.first) as usize / width + 1;
+            for index in 0..count {
+                let start = entry.first as usize + index * width;
+                if start < address
+                    || start + width > end
+                    || tags.iter().any(|(tagged, _)| *tagged == start)
+                {
+                    continue;
+                }
+                let raw = entry.data_type.decode(&fields.values[start - address..]);
+                let name = if count == 1 {
+                    entry.name.clone()
+                } else {
+                    format!("{}[{}]", entry.name, index)
+                };
+                tags.push((
+                    start,
+                    TagValue {
+                        name,
+                        value: raw * entry.scale + entry.offset,
+                        units: entry.units.clone(),
+                        description: entry.description.clone(),
+                    },
+                ));
+            }
+        }
+        tags.sort_by_key(|(start, _)| *start);
+        tags.into_iter().map(|(_, tag)| tag).collect()
+    }
+}
+
+/// (始まりの行番号, キーと値)
+type Record = (usize, HashMap<String, String>);
+
+/// TOML の [[register]] の表を Record にする。文字列、整数、小数の値だけを読む
+fn parse_toml(text: &str) -> Result<Vec<Record>, String> {
+    let mut tables: Vec<Record> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "[[register]]" {
+            tables.push((number, HashMap::new()));
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected [[register]] or key = value", number))?;
+        let table = match tables.last_mut() {
+            Some((_, table)) => table,
+            None => return Err(format!("line {}: key outside [[register]]", number)),
+        };
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            unquote(quoted).ok_or_else(|| format!("line {}: unterminated string", number))?
+        } else {
+            value.to_string()
+        };
+        table.insert(key.trim().to_string(), value);
+    }
+    Ok(tables)
+}
+
+/// '#' からのコメントを外す (文字列の中の '#' は残す)
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// 開きの '"' の後から閉じの '"' までを取り出す
+fn unquote(text: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// CSV を見出しの列名で Record にする。'"' で囲んだ列の中の ',' と '""' を扱う
+fn parse_csv(text: &str) -> Result<Vec<Record>, String> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+    let header = match lines.next() {
+        Some((_, header)) => split_csv(header),
+        None => return Ok(Vec::new()),
+    };
+    lines
+        .map(|(number, line)| {
+            let fields = split_csv(line);
+            if fields.len() > header.len() {
+                return Err(format!("line {}: more columns than the header", number + 1));
+            }
+            let values = header.iter().cloned().zip(fields).collect();
+            Ok((number + 1, values))
+        })
+        .collect()
+}
+
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.iter().map(|field| field.trim().to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(function: u8, address: u16, values: &[u16]) -> AduFields {
+        AduFields {
+            unit: 1,
+            function,
+            address: Some(address),
+            values: values.to_vec(),
+            ..AduFields::default()
+        }
+    }
+
+    fn tags(map: &RegisterMap, device: &str, fields: &AduFields) -> Vec<String> {
+        map.annotate(device, fields).iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn comments_and_strings() {
+        assert_eq!(strip_comment(r#"name = "a#b" # tag"#), r#"name = "a#b" "#);
+        assert_eq!(strip_comment(r##"name = "a\"#" # tag"##), r##"name = "a\"#" "##);
+        assert_eq!(strip_comment("# whole line"), "");
+        assert_eq!(unquote(r#"abc" # rest"#).as_deref(), Some("abc"));
+        assert_eq!(unquote(r#"a\"b\nc\\""#).as_deref(), Some("a\"b\nc\\"));
+        assert_eq!(unquote("abc"), None);
+    }
+
+    #[test]
+    fn toml() {
+        let text = "# header\n[[register]]\ntable = \"coil\"  # comment\naddress = 1\n\n  [[register]]\nname = \"A # B\"\n";
+        let tables = parse_toml(text).unwrap();
+        assert_eq!(tables.iter().map(|(line, _)| *line).collect::<Vec<_>>(), vec![2, 6]);
+        assert_eq!(tables[0].1["table"], "coil");
+        assert_eq!(tables[0].1["address"], "1");
+        assert_eq!(tables[1].1["name"], "A # B");
+        assert_eq!(parse_toml("name = \"A\"").unwrap_err(), "line 1: key outside [[register]]");
+        assert_eq!(
+            parse_toml("[[register]]\n\nname").unwrap_err(),
+            "line 3: expected [[register]] or key = value"
+        );
+        assert_eq!(
+            parse_toml("[[register]]\nname = \"A").unwrap_err(),
+            "line 2: unterminated string"
+        );
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            split_csv(r#"a,"b,c","say ""hi""", d "#),
+            vec!["a", "b,c", "say \"hi\"", "d"]
+        );
+        assert_eq!(split_csv(""), vec![""]);
+        let text = "# map\ntable,address,name\n  # indented comment\n\ncoil,1,Pump\nholding_register,\"2..3\",\"A, B\"\n";
+        let tables = parse_csv(text).unwrap();
+        assert_eq!(tables.iter().map(|(line, _)| *line).collect::<Vec<_>>(), vec![5, 6]);
+        assert_eq!(tables[0].1["name"], "Pump");
+        assert_eq!(tables[1].1["address"], "2..3");
+        assert_eq!(tables[1].1["name"], "A, B");
+        assert_eq!(
+            parse_csv("table,address\ncoil,1,Pump").unwrap_err(),
+            "line 2: more columns than the header"
+        );
+        assert!(parse_csv("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn entries() {
+        let entry = |text: &str| RegisterMap::parse(&format!("[[register]]\n{}", text), false).map(|_| ());
+        assert_eq!(entry("table = \"coil\"\naddress = 0\nname = \"A\""), Ok(()));
+        assert_eq!(entry("table = \"coil\"\nname = \"A\"").unwrap_err(), "line 1: address is required");
+        assert_eq!(
+            entry("table = \"coil\"\naddress = 0\nname = \"A\"\ncolour = 1").unwrap_err(),
+            "line 1: unknown key: colour"
+        );
+        assert_eq!(
+            entry("table = \"coil\"\naddress = 0\nname = \"A\"\ntype = \"u16\"").unwrap_err(),
+            "line 1: coil holds only bool"
+        );
+        assert_eq!(
+            entry("table = \"discrete_input\"\naddress = 0\nname = \"A\"\ntype = \"f32\"").unwrap_err(),
+            "line 1: discrete_input holds only bool"
+        );
+        assert_eq!(
+            entry("table = \"holding_register\"\naddress = \"5..4\"\nname = \"A\"").unwrap_err(),
+            "line 1: address 5..4 is empty"
+        );
+        assert_eq!(
+            entry("table = \"holding_register\"\naddress = \"0..2\"\nname = \"A\"\ntype = \"u32\"").unwrap_err(),
+            "line 1: address 0..2 is not a multiple of 2 registers"
+        );
+        assert_eq!(
+            entry("table = \"holding_register\"\naddress = \"0..70000\"\nname = \"A\"").unwrap_err(),
+            "line 1: invalid address: 70000"
+        );
+        assert_eq!(
+            entry("table = \"holding_register\"\naddress = 0\nname = \"A\"\nscale = x").unwrap_err(),
+            "line 1: invalid scale: x"
+        );
+        /* 誤りは表の始まりの行番号で示す */
+        let text = "table,address,name\ncoil,0,A\n\nregister,1,B\n";
+        assert_eq!(RegisterMap::parse(text, true).unwrap_err(), "line 4: unknown table: register");
+    }
+
+    #[test]
+    fn annotate() {
+        let text = "
+[[register]]
+device = \"192.168.0.10\"
+table = \"holding_register\"
+address = 100
+name = \"TankLevel\"
+scale = 0.1
+units = \"%\"
+description = \"tank 1\"
+
+[[register]]
+table = \"holding_register\"
+address = \"100..105\"
+name = \"Raw\"
+
+[[register]]
+table = \"input_register\"
+address = \"0..3\"
+name = \"Flow\"
+type = \"f32\"
+
+[[register]]
+unit = 2
+table = \"input_register\"
+address = \"10..11\"
+name = \"Total\"
+type = \"u32\"
+offset = -1
+
+[[register]]
+table = \"coil\"
+address = 3
+name = \"Pump\"
+";
+        let map = RegisterMap::parse(text, false).unwrap();
+        assert_eq!(
+            map.annotate("192.168.0.10", &read(3, 100, &[734]))[0],
+            TagValue {
+                name: "TankLevel".to_string(),
+                value: 73.4,
+                units: "%".to_string(),
+                description: "tank 1".to_string(),
+            }
+        );
+        /* 先に書いたものを使い、範囲からはみ出す番号は付けない */
+        assert_eq!(
+            tags(&map, "192.168.0.10", &read(3, 99, &[1, 734, 2])),
+            vec!["TankLevel = 73.4 %", "Raw[1] = 2"]
+        );
+        assert_eq!(tags(&map, "192.168.0.11", &read(3, 100, &[734])), vec!["Raw[0] = 734"]);
+        /* 32 ビットの値は上位ワードが先で、両方のワードが揃ったものだけ */
+        let flow = read(4, 1, &[0x4049, 0x4049, 0x0fdb]);
+        assert_eq!(tags(&map, "serial", &flow), vec!["Flow[1] = 3.141593"]);
+        let total = AduFields { unit: 2, ..read(4, 10, &[1, 0]) };
+        assert_eq!(tags(&map, "serial", &total), vec!["Total = 65535"]);
+        assert!(tags(&map, "serial", &read(4, 10, &[1, 0])).is_empty());
+        assert_eq!(tags(&map, "serial", &read(1, 0, &[0, 0, 0, 1])), vec!["Pump = 1"]);
+        /* 同じ番号でも表が違えば付けない */
+        assert!(tags(&map, "serial", &read(2, 3, &[1])).is_empty());
+        assert!(tags(&map, "serial", &read(3, 100, &[])).is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+//! stats コマンドの集計。デバイス (サーバ側のアドレス、シリアルバスなら "serial")、unit、
+//! ファンクションコードごとにリクエスト・応答・例外応答の数と応答時間を数える。
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use super::output::{device_name, Event, Sink, Time};
+use super::packet::fields::AduFields;
+
+#[derive(Default)]
+struct Counter {
+    requests: u64,
+    replies: u64,
+    exceptions: u64,
+    latency_min: Option<Duration>,
+    latency_max: Option<Duration>,
+    latency_total: Duration,
+    /// 応答時間が分かった (リクエストと組にできた) 応答の数
+    paired: u32,
+}
+
+#[derive(Default)]
+pub struct Statistics {
+    pub frames: u64,
+    pub bytes: u64,
+    counters: BTreeMap<(String, u8, u8), Counter>,
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics::default()
+    }
+
+    pub fn record_frame(&mut self, length: usize) {
+        self.frames += 1;
+        self.bytes += length as u64;
+    }
+
+    pub fn record_adu(&mut self, device: String, fields: &AduFields) {
+        let counter = self
+            .counters
+            .entry((device, fields.unit, fields.function))
+            .or_default();
+        if fields.is_request {
+            counter.requests += 1;
+            return;
+        }
+        counter.replies += 1;
+        if fields.exception {
+            counter.exceptions += 1;
+        }
+        if let Some(latency) = fields.latency {
+            counter.latency_min = Some(counter.latency_min.map_or(latency, |min| min.min(latency)));
+            counter.latency_max = Some(counter.latency_max.map_or(latency, |max| max.max(latency)));
+            counter.latency_total += latency;
+            counter.paired += 1;
+        }
+    }
+
+    pub fn print(&self) {
+        println!("frames: {}, bytes: {}", self.frames, self.bytes);
+        println!(
+            "{:<24} {:>4} {:>4} {:>9} {:>9} {:>10} {:>28}",
+            "device", "unit", "fc", "requests", "replies", "exceptions", "latency min/avg/max (ms)"
+        );
+        for ((device, unit, function), counter) in &self.counters {
+            let latency = match (counter.latency_min, counter.latency_max) {
+                (Some(min), Some(max)) => format!(
+                    "{:.3}/{:.3}/{:.3}",
+                    milliseconds(min),
+                    milliseconds(counter.latency_total / counter.paired),
+                    milliseconds(max)
+                ),
+                _ => "-".to_string(),
+            };
+            println!(
+                "{:<24} {:>4} {:>4} {:>9} {:>9} {:>10} {:>28}",
+                device,
+                unit,
+                function,
+                counter.requests,
+                counter.replies,
+                counter.exceptions,
+                latency
+            );
+        }
+    }
+}
+
+/// stats コマンドの出力。入力が終わったら表を書く
+impl Sink for Statistics {
+    fn event(&mut self, _time: &Time, event: &Event) {
+        match event {
+            Event::FrameSeen(frame) => self.record_frame(frame.data.len()),
+            Event::ModbusRequest(adu) | Event::ModbusReply(adu) | Event::Exception(adu) => {
+                self.record_adu(device_name(adu.flow, adu.fields.is_request), adu.fields)
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(&mut self) {
+        self.print();
+    }
+}
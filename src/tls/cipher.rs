@@ -0,0 +1,276 @@
+//! 鍵ログの秘密からレコードの鍵を導出し、AES-GCM のレコードを復号する。
+//! 対応するのは TLS 1.3 の TLS_AES_128_GCM_SHA256 / TLS_AES_256_GCM_SHA384 と、
+//! TLS 1.2 の AES-GCM の暗号スイートだけ。
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha384};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Hash {
+    Sha256,
+    Sha384,
+}
+
+/// 復号できる暗号スイートの鍵長とハッシュ
+fn suite_parameters(cipher_suite: u16) -> Option<(usize, Hash)> {
+    match cipher_suite {
+        0x1301 | 0x009c | 0x009e | 0xc02b | 0xc02f => Some((16, Hash::Sha256)),
+        0x1302 | 0x009d | 0x009f | 0xc02c | 0xc030 => Some((32, Hash::Sha384)),
+        _ => None,
+    }
+}
+
+/// 鍵ログがあれば復号できる暗号スイートか
+pub fn is_supported(cipher_suite: u16) -> bool {
+    suite_parameters(cipher_suite).is_some()
+}
+
+fn hmac(hash: Hash, key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    match hash {
+        Hash::Sha256 => {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+            for d in data {
+                mac.update(d);
+            }
+            mac.finalize().into_bytes().to_vec()
+        }
+        Hash::Sha384 => {
+            let mut mac = <Hmac<Sha384> as Mac>::new_from_slice(key).unwrap();
+            for d in data {
+                mac.update(d);
+            }
+            mac.finalize().into_bytes().to_vec()
+        }
+    }
+}
+
+/// TLS 1.2 の PRF (P_hash)
+fn prf(hash: Hash, secret: &[u8], label: &[u8], seed: &[u8], length: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(length);
+    let mut a = hmac(hash, secret, &[label, seed]);
+    while output.len() < length {
+        output.extend_from_slice(&hmac(hash, secret, &[&a, label, seed]));
+        a = hmac(hash, secret, &[&a]);
+    }
+    output.truncate(length);
+    output
+}
+
+/// TLS 1.3 の HKDF-Expand-Label (context は空)
+fn expand_label(hash: Hash, secret: &[u8], label: &str, length: usize) -> Option<Vec<u8>> {
+    let full_label = format!("tls13 {}", label);
+    let mut info = Vec::new();
+    info.extend_from_slice(&(length as u16).to_be_bytes());
+    info.push(full_label.len() as u8);
+    info.extend_from_slice(full_label.as_bytes());
+    info.push(0);
+    let mut output = vec![0u8; length];
+    match hash {
+        Hash::Sha256 => Hkdf::<Sha256>::from_prk(secret)
+            .ok()?
+            .expand(&info, &mut output)
+            .ok()?,
+        Hash::Sha384 => Hkdf::<Sha384>::from_prk(secret)
+            .ok()?
+            .expand(&info, &mut output)
+            .ok()?,
+    }
+    Some(output)
+}
+
+enum Cipher {
+    Aes128(Box<Aes128Gcm>),
+    Aes256(Box<Aes256Gcm>),
+}
+
+/// 一方向のレコードを復号する鍵と IV
+pub struct RecordKeys {
+    cipher: Cipher,
+    iv: Vec<u8>,
+    tls13: bool,
+}
+
+impl RecordKeys {
+    fn new(key: &[u8], iv: Vec<u8>, tls13: bool) -> Option<RecordKeys> {
+        let cipher = match key.len() {
+            16 => Cipher::Aes128(Box::new(Aes128Gcm::new_from_slice(key).ok()?)),
+            32 => Cipher::Aes256(Box::new(Aes256Gcm::new_from_slice(key).ok()?)),
+            _ => return None,
+        };
+        Some(RecordKeys { cipher, iv, tls13 })
+    }
+
+    /// TLS 1.3 のトラフィック秘密から鍵を導出する
+    pub fn tls13(cipher_suite: u16, secret: &[u8]) -> Option<RecordKeys> {
+        let (key_length, hash) = suite_parameters(cipher_suite)?;
+        let key = expand_label(hash, secret, "key", key_length)?;
+        let iv = expand_label(hash, secret, "iv", 12)?;
+        RecordKeys::new(&key, iv, true)
+    }
+
+    /// TLS 1.2 のマスターシークレットからクライアント側とサーバ側の鍵を導出する
+    pub fn tls12(
+        cipher_suite: u16,
+        master_secret: &[u8],
+        client_random: &[u8],
+        server_random: &[u8],
+    ) -> Option<(RecordKeys, RecordKeys)> {
+        let (key_length, hash) = suite_parameters(cipher_suite)?;
+        let mut seed = server_random.to_vec();
+        seed.extend_from_slice(client_random);
+        let block = prf(hash, master_secret, b"key expansion", &seed, 2 * key_length + 8);
+        let (client_key, rest) = block.split_at(key_length);
+        let (server_key, rest) = rest.split_at(key_length);
+        let (client_iv, server_iv) = rest.split_at(4);
+        Some((
+            RecordKeys::new(client_key, client_iv.to_vec(), false)?,
+            RecordKeys::new(server_key, server_iv.to_vec(), false)?,
+        ))
+    }
+
+    /// レコードを復号し、(中身の content type, 平文) を返す。認証に失敗すれば None
+    pub fn decrypt(
+        &self,
+        sequence: u64,
+        content_type: u8,
+        version: u16,
+        fragment: &[u8],
+    ) -> Option<(u8, Vec<u8>)> {
+        let (nonce, ciphertext, aad) = if self.tls13 {
+            let mut nonce = self.iv.clone();
+            for (n, s) in nonce[4..].iter_mut().zip(sequence.to_be_bytes().iter()) {
+                *n ^= s;
+            }
+            let mut aad = vec![content_type];
+            aad.extend_from_slice(&version.to_be_bytes());
+            aad.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            (nonce, fragment, aad)
+        } else {
+            if fragment.len() < 8 + 16 {
+                return None;
+            }
+            let mut nonce = self.iv.clone();
+            nonce.extend_from_slice(&fragment[..8]);
+            let ciphertext = &fragment[8..];
+            let mut aad = sequence.to_be_bytes().to_vec();
+            aad.push(content_type);
+            aad.extend_from_slice(&version.to_be_bytes());
+            aad.extend_from_slice(&((ciphertext.len() - 16) as u16).to_be_bytes());
+            (nonce, ciphertext, aad)
+        };
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        let nonce = Nonce::from_slice(&nonce);
+        let mut plaintext = match &self.cipher {
+            Cipher::Aes128(cipher) => cipher.decrypt(nonce, payload).ok()?,
+            Cipher::Aes256(cipher) => cipher.decrypt(nonce, payload).ok()?,
+        };
+        if !self.tls13 {
+            return Some((content_type, plaintext));
+        }
+        /* TLS 1.3 では平文の後ろに本当の content type と 0 の詰め物が付く */
+        while plaintext.last() == Some(&0) {
+            plaintext.pop();
+        }
+        let inner_type = plaintext.pop()?;
+        Some((inner_type, plaintext))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /* Read Holding Registers の要求 */
+    const ADU: [u8; 12] = [0, 1, 0, 0, 0, 6, 1, 3, 0, 0, 0, 2];
+
+    /// RFC 8448 3. Simple 1-RTT Handshake のトラフィック秘密と書き込み鍵
+    #[test]
+    fn tls13_key_schedule() {
+        let vectors = [
+            (
+                "b3 ed db 12 6e 06 7f 35 a7 80 b3 ab f4 5e 2d 8f 3b 1a 95 07 38 f5 2e 96 00 74 6a 0e 27 a5 5a 21",
+                "db fa a6 93 d1 76 2c 5b 66 6a f5 d9 50 25 8d 01",
+                "5b d3 c7 1b 83 6e 0b 76 bb 73 26 5f",
+            ),
+            (
+                "b6 7b 7d 69 0c c1 6c 4e 75 e5 42 13 cb 2d 37 b4 e9 c9 12 bc de d9 10 5d 42 be fd 59 d3 91 ad 38",
+                "3f ce 51 60 09 c2 17 27 d0 f2 e4 e8 6e e4 03 bc",
+                "5d 31 3e b2 67 12 76 ee 13 00 0b 30",
+            ),
+            (
+                "a1 1a f9 f0 55 31 f8 56 ad 47 11 6b 45 a9 50 32 82 04 b4 f4 4b fb 6b 3a 4b 4f 1f 3f cb 63 16 43",
+                "9f 02 28 3b 6c 9c 07 ef c2 6b b9 f2 ac 92 e3 56",
+                "cf 78 2b 88 dd 83 54 9a ad f1 e9 84",
+            ),
+        ];
+        for (secret, key, iv) in vectors.iter() {
+            let secret = hex(secret);
+            assert_eq!(expand_label(Hash::Sha256, &secret, "key", 16), Some(hex(key)));
+            assert_eq!(expand_label(Hash::Sha256, &secret, "iv", 12), Some(hex(iv)));
+        }
+    }
+
+    /// TLS 1.2 の PRF (SHA-256) の既知の出力
+    #[test]
+    fn tls12_prf() {
+        let output = prf(
+            Hash::Sha256,
+            &hex("9b be 43 6b a9 40 f0 17 b1 76 52 84 9a 71 db 35"),
+            b"test label",
+            &hex("a0 ba 9f 93 6c da 31 18 27 a6 f7 96 ff d5 19 8c"),
+            100,
+        );
+        assert_eq!(
+            output,
+            hex("e3 f2 29 ba 72 7b e1 7b 8d 12 26 20 55 7c d4 53 c2 aa b2 1d 07 c3 d4 95 32 9b 52 d4 e6 1e
+                 db 5a 6b 30 17 91 e9 0d 35 c9 c9 a4 6b 4e 14 ba f9 af 0f a0 22 f7 07 7d ef 17 ab fd 37
+                 97 c0 56 4b ab 4f bc 91 66 6e 9d ef 9b 97 fc e3 4f 79 67 89 ba a4 80 82 d1 22 ee 42 c5
+                 a7 2e 5a 51 10 ff f7 01 87 34 7b 66")
+        );
+    }
+
+    /// RFC 8448 のサーバのアプリケーション鍵で暗号化した 2 番目のレコード
+    #[test]
+    fn tls13_record() {
+        let secret = hex("a1 1a f9 f0 55 31 f8 56 ad 47 11 6b 45 a9 50 32 82 04 b4 f4 4b fb 6b 3a 4b 4f 1f 3f cb 63 16 43");
+        let keys = RecordKeys::tls13(0x1301, &secret).unwrap();
+        let fragment = hex("2e 93 7c 12 eb 49 c0 44 ed 31 a7 3f 1b 52 ca c6 e8 36 fa 87 62 61 91 79 e0 f7 db de 43 be 45");
+        assert_eq!(keys.decrypt(1, 23, 0x0303, &fragment), Some((23, ADU.to_vec())));
+        /* 順序番号が違えば nonce が合わず認証に失敗する */
+        assert_eq!(keys.decrypt(0, 23, 0x0303, &fragment), None);
+    }
+
+    /// TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 のクライアント側の 2 番目のレコード
+    #[test]
+    fn tls12_record() {
+        let master_secret: Vec<u8> = (0..48).collect();
+        let (client, _) =
+            RecordKeys::tls12(0xc02f, &master_secret, &[0x11; 32], &[0x22; 32]).unwrap();
+        let fragment = hex("00 00 00 00 00 00 00 01 4d e6 3d d5 6a 26 ed 90 f0 cb b8 6e ed 41 cd b5
+                            b9 6b 15 76 4e a0 a7 af b5 be d2 fb");
+        assert_eq!(client.decrypt(1, 23, 0x0303, &fragment), Some((23, ADU.to_vec())));
+        assert_eq!(client.decrypt(1, 23, 0x0303, &fragment[..20]), None);
+    }
+
+    #[test]
+    fn unsupported_suites() {
+        assert!(is_supported(0x1301));
+        assert!(!is_supported(0x1303));
+        assert!(!is_supported(0xcca8));
+        assert!(RecordKeys::tls13(0x1303, &[0; 32]).is_none());
+    }
+}
@@ -0,0 +1,67 @@
+//! SSLKEYLOGFILE 形式の鍵ログ ("<ラベル> <client random の16進> <秘密の16進>" の行) を読む。
+//! キャプチャ中に書き足されることがあるので、見つからなかったときはファイルを読み直す。
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+pub const CLIENT_RANDOM: &str = "CLIENT_RANDOM";
+pub const CLIENT_HANDSHAKE_TRAFFIC_SECRET: &str = "CLIENT_HANDSHAKE_TRAFFIC_SECRET";
+pub const SERVER_HANDSHAKE_TRAFFIC_SECRET: &str = "SERVER_HANDSHAKE_TRAFFIC_SECRET";
+pub const CLIENT_TRAFFIC_SECRET_0: &str = "CLIENT_TRAFFIC_SECRET_0";
+pub const SERVER_TRAFFIC_SECRET_0: &str = "SERVER_TRAFFIC_SECRET_0";
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub struct KeyLog {
+    path: String,
+    secrets: HashMap<(String, Vec<u8>), Vec<u8>>,
+}
+
+impl KeyLog {
+    pub fn open(path: &str) -> io::Result<KeyLog> {
+        let mut keylog = KeyLog {
+            path: path.to_string(),
+            secrets: HashMap::new(),
+        };
+        keylog.reload()?;
+        Ok(keylog)
+    }
+
+    /// ファイルを読み直す。コメントや読めない行は飛ばす。
+    pub fn reload(&mut self) -> io::Result<()> {
+        let text = fs::read_to_string(&self.path)?;
+        for line in text.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                continue;
+            }
+            if let (Some(client_random), Some(secret)) =
+                (decode_hex(fields[1]), decode_hex(fields[2]))
+            {
+                self.secrets
+                    .insert((fields[0].to_string(), client_random), secret);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&mut self, label: &str, client_random: &[u8]) -> Option<Vec<u8>> {
+        let key = (label.to_string(), client_random.to_vec());
+        if !self.secrets.contains_key(&key) {
+            let _ = self.reload();
+        }
+        self.secrets.get(&key).cloned()
+    }
+}
@@ -0,0 +1,542 @@
+//! Modbus/TCP Security (TLS 上の Modbus/TCP, 802番ポート) のハンドシェイクを解析する。
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Content Type  |            Version            |    Length     |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |    Length     |   Fragment ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!
+//! TCP のストリームからレコードを組み立て、平文のハンドシェイクから
+//! バージョン, 暗号スイート, SNI, 証明書を取り出す。鍵ログがあれば暗号化された
+//! レコードを復号し、アプリケーションデータ (Modbus/TCP の ADU) を返す。
+//! TCP の再送や順序の入れ替わりは考慮しない。
+
+pub mod cipher;
+pub mod keylog;
+pub mod x509;
+
+use self::cipher::RecordKeys;
+use self::keylog::KeyLog;
+
+pub const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+pub const CONTENT_ALERT: u8 = 21;
+pub const CONTENT_HANDSHAKE: u8 = 22;
+pub const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const HANDSHAKE_CERTIFICATE: u8 = 11;
+const HANDSHAKE_FINISHED: u8 = 20;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+pub const TLS_1_3: u16 = 0x0304;
+
+/// HelloRetryRequest は ServerHello の random をこの値 (SHA-256("HelloRetryRequest")) にして送られる
+const HELLO_RETRY_REQUEST_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// レコードの最大長 (暗号化によるふくらみを含む)
+const MAX_RECORD: usize = 16384 + 2048;
+
+pub fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSL 3.0".to_string(),
+        0x0301 => "TLS 1.0".to_string(),
+        0x0302 => "TLS 1.1".to_string(),
+        0x0303 => "TLS 1.2".to_string(),
+        0x0304 => "TLS 1.3".to_string(),
+        _ => format!("{:#06x}", version),
+    }
+}
+
+pub fn cipher_suite_name(cipher_suite: u16) -> String {
+    let name = match cipher_suite {
+        0x1301 => "TLS_AES_128_GCM_SHA256",
+        0x1302 => "TLS_AES_256_GCM_SHA384",
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256",
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+        0x009e => "TLS_DHE_RSA_WITH_AES_128_GCM_SHA256",
+        0x009f => "TLS_DHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        _ => return format!("{:#06x}", cipher_suite),
+    };
+    format!("{} ({:#06x})", name, cipher_suite)
+}
+
+pub enum Event {
+    ClientHello {
+        version: u16,
+        server_name: Option<String>,
+    },
+    ServerHello {
+        version: u16,
+        cipher_suite: u16,
+    },
+    /// TLS 1.3 でサーバが ClientHello のやり直しを求めた
+    HelloRetryRequest {
+        cipher_suite: u16,
+    },
+    /// 鍵ログはあるが復号に対応していない暗号スイート
+    UnsupportedCipherSuite(u16),
+    Certificate(x509::Certificate),
+    Alert {
+        level: u8,
+        description: u8,
+    },
+    /// 復号したアプリケーションデータ
+    ApplicationData(Vec<u8>),
+    /// 鍵がないなどで復号できなかったレコード
+    Encrypted {
+        content_type: u8,
+        length: usize,
+    },
+    Malformed(&'static str),
+}
+
+/// 通信の一方向ぶんの状態
+#[derive(Default)]
+struct Direction {
+    /// 組み立て途中のレコード
+    records: Vec<u8>,
+    /// 組み立て途中のハンドシェイクメッセージ
+    handshake: Vec<u8>,
+    /// これ以降のレコードは暗号化されている
+    encrypted: bool,
+    keys: Option<RecordKeys>,
+    sequence: u64,
+}
+
+impl Direction {
+    fn set_keys(&mut self, keys: Option<RecordKeys>) {
+        self.encrypted = true;
+        self.keys = keys;
+        self.sequence = 0;
+    }
+}
+
+/// 1 本の TLS 接続の状態
+#[derive(Default)]
+pub struct Session {
+    client_random: Vec<u8>,
+    server_random: Vec<u8>,
+    version: u16,
+    cipher_suite: u16,
+    client: Direction,
+    server: Direction,
+}
+
+/// 先頭から n バイトを切り出す
+fn take(input: &[u8], n: usize) -> Option<(&[u8], &[u8])> {
+    if input.len() < n {
+        None
+    } else {
+        Some(input.split_at(n))
+    }
+}
+
+fn take_u8(input: &[u8]) -> Option<(u8, &[u8])> {
+    let (value, rest) = take(input, 1)?;
+    Some((value[0], rest))
+}
+
+fn take_u16(input: &[u8]) -> Option<(u16, &[u8])> {
+    let (value, rest) = take(input, 2)?;
+    Some((u16::from_be_bytes([value[0], value[1]]), rest))
+}
+
+fn take_u24(input: &[u8]) -> Option<(usize, &[u8])> {
+    let (value, rest) = take(input, 3)?;
+    Some((
+        (value[0] as usize) << 16 | (value[1] as usize) << 8 | value[2] as usize,
+        rest,
+    ))
+}
+
+/// 1 バイトの長さが付いたベクタ
+fn take_vec8(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = take_u8(input)?;
+    take(rest, length as usize)
+}
+
+/// 2 バイトの長さが付いたベクタ
+fn take_vec16(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = take_u16(input)?;
+    take(rest, length as usize)
+}
+
+/// 3 バイトの長さが付いたベクタ
+fn take_vec24(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = take_u24(input)?;
+    take(rest, length)
+}
+
+/// 拡張の並びを (種類, 中身) に分ける
+fn extensions(input: &[u8]) -> Option<Vec<(u16, &[u8])>> {
+    let mut list = Vec::new();
+    if input.is_empty() {
+        return Some(list);
+    }
+    let (mut input, _) = take_vec16(input)?;
+    while !input.is_empty() {
+        let (extension_type, rest) = take_u16(input)?;
+        let (data, rest) = take_vec16(rest)?;
+        list.push((extension_type, data));
+        input = rest;
+    }
+    Some(list)
+}
+
+fn server_name(data: &[u8]) -> Option<String> {
+    let (mut list, _) = take_vec16(data)?;
+    while !list.is_empty() {
+        let (name_type, rest) = take_u8(list)?;
+        let (name, rest) = take_vec16(rest)?;
+        if name_type == 0 {
+            return Some(String::from_utf8_lossy(name).into_owned());
+        }
+        list = rest;
+    }
+    None
+}
+
+impl Session {
+    /// TCP のペイロードを受け取り、組み立てられたレコードを解析する
+    pub fn push(
+        &mut self,
+        from_client: bool,
+        data: &[u8],
+        keylog: Option<&mut KeyLog>,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut keylog = keylog;
+        let direction = if from_client {
+            &mut self.client
+        } else {
+            &mut self.server
+        };
+        direction.records.extend_from_slice(data);
+        let mut records = Vec::new();
+        loop {
+            let buffer = &direction.records;
+            if buffer.len() < 5 {
+                break;
+            }
+            let length = u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+            if length > MAX_RECORD {
+                events.push(Event::Malformed("record too long"));
+                direction.records.clear();
+                break;
+            }
+            if buffer.len() < 5 + length {
+                break;
+            }
+            let record: Vec<u8> = direction.records.drain(..5 + length).collect();
+            records.push(record);
+        }
+        for record in records {
+            self.handle_record(from_client, &record, keylog.as_deref_mut(), &mut events);
+        }
+        events
+    }
+
+    fn direction(&mut self, from_client: bool) -> &mut Direction {
+        if from_client {
+            &mut self.client
+        } else {
+            &mut self.server
+        }
+    }
+
+    fn handle_record(
+        &mut self,
+        from_client: bool,
+        record: &[u8],
+        keylog: Option<&mut KeyLog>,
+        events: &mut Vec<Event>,
+    ) {
+        let content_type = record[0];
+        let version = u16::from_be_bytes([record[1], record[2]]);
+        let fragment = &record[5..];
+        let tls13 = self.version == TLS_1_3;
+
+        if content_type == CONTENT_CHANGE_CIPHER_SPEC {
+            /* TLS 1.3 では互換性のためだけに送られる */
+            if !tls13 {
+                let keys = self.tls12_keys(from_client, keylog);
+                self.direction(from_client).set_keys(keys);
+            }
+            return;
+        }
+
+        let direction = self.direction(from_client);
+        let (content_type, plaintext) = if direction.encrypted {
+            let decrypted = match &direction.keys {
+                Some(keys) => keys.decrypt(direction.sequence, content_type, version, fragment),
+                None => None,
+            };
+            direction.sequence += 1;
+            match decrypted {
+                Some(decrypted) => decrypted,
+                None => {
+                    events.push(Event::Encrypted {
+                        content_type,
+                        length: fragment.len(),
+                    });
+                    return;
+                }
+            }
+        } else {
+            (content_type, fragment.to_vec())
+        };
+
+        match content_type {
+            CONTENT_HANDSHAKE => {
+                self.direction(from_client)
+                    .handshake
+                    .extend_from_slice(&plaintext);
+                self.handle_handshake(from_client, keylog, events);
+            }
+            CONTENT_ALERT => {
+                if plaintext.len() >= 2 {
+                    events.push(Event::Alert {
+                        level: plaintext[0],
+                        description: plaintext[1],
+                    });
+                } else {
+                    events.push(Event::Malformed("short alert"));
+                }
+            }
+            CONTENT_APPLICATION_DATA => events.push(Event::ApplicationData(plaintext)),
+            _ => events.push(Event::Malformed("unknown content type")),
+        }
+    }
+
+    /// 組み立てたハンドシェイクメッセージを順に解析する
+    fn handle_handshake(
+        &mut self,
+        from_client: bool,
+        keylog: Option<&mut KeyLog>,
+        events: &mut Vec<Event>,
+    ) {
+        let mut keylog = keylog;
+        loop {
+            let buffer = &self.direction(from_client).handshake;
+            if buffer.len() < 4 {
+                break;
+            }
+            let length = (buffer[1] as usize) << 16 | (buffer[2] as usize) << 8 | buffer[3] as usize;
+            if buffer.len() < 4 + length {
+                break;
+            }
+            let message: Vec<u8> = self
+                .direction(from_client)
+                .handshake
+                .drain(..4 + length)
+                .collect();
+            let body = &message[4..];
+            let parsed = match message[0] {
+                HANDSHAKE_CLIENT_HELLO => self.client_hello(body, events),
+                HANDSHAKE_SERVER_HELLO => self.server_hello(body, keylog.as_deref_mut(), events),
+                HANDSHAKE_CERTIFICATE => self.certificate(body, events),
+                HANDSHAKE_FINISHED => {
+                    self.finished(from_client, keylog.as_deref_mut());
+                    Some(())
+                }
+                _ => Some(()),
+            };
+            if parsed.is_none() {
+                events.push(Event::Malformed("handshake message"));
+            }
+        }
+    }
+
+    fn client_hello(&mut self, body: &[u8], events: &mut Vec<Event>) -> Option<()> {
+        let (version, rest) = take_u16(body)?;
+        let (random, rest) = take(rest, 32)?;
+        let (_, rest) = take_vec8(rest)?; /* session id */
+        let (_, rest) = take_vec16(rest)?; /* cipher suites */
+        let (_, rest) = take_vec8(rest)?; /* compression methods */
+        let mut server_name_value = None;
+        let mut offered_version = version;
+        for (extension_type, data) in extensions(rest)? {
+            match extension_type {
+                EXTENSION_SERVER_NAME => server_name_value = server_name(data),
+                EXTENSION_SUPPORTED_VERSIONS => {
+                    let (mut versions, _) = take_vec8(data)?;
+                    while let Some((v, rest)) = take_u16(versions) {
+                        /* GREASE (0x?a?a) は除く */
+                        if v & 0x0f0f != 0x0a0a && v > offered_version {
+                            offered_version = v;
+                        }
+                        versions = rest;
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.client_random = random.to_vec();
+        events.push(Event::ClientHello {
+            version: offered_version,
+            server_name: server_name_value,
+        });
+        Some(())
+    }
+
+    fn server_hello(
+        &mut self,
+        body: &[u8],
+        keylog: Option<&mut KeyLog>,
+        events: &mut Vec<Event>,
+    ) -> Option<()> {
+        let (version, rest) = take_u16(body)?;
+        let (random, rest) = take(rest, 32)?;
+        let (_, rest) = take_vec8(rest)?; /* session id */
+        let (cipher_suite, rest) = take_u16(rest)?;
+        let (_, rest) = take_u8(rest)?; /* compression method */
+        let mut selected_version = version;
+        for (extension_type, data) in extensions(rest)? {
+            if extension_type == EXTENSION_SUPPORTED_VERSIONS {
+                selected_version = take_u16(data)?.0;
+            }
+        }
+        self.version = selected_version;
+        self.cipher_suite = cipher_suite;
+        if random == HELLO_RETRY_REQUEST_RANDOM {
+            /* 暗号化はまだ始まらず、やり直しの ClientHello と本当の ServerHello が平文で続く */
+            events.push(Event::HelloRetryRequest { cipher_suite });
+            return Some(());
+        }
+        self.server_random = random.to_vec();
+        events.push(Event::ServerHello {
+            version: selected_version,
+            cipher_suite,
+        });
+        if keylog.is_some() && !cipher::is_supported(cipher_suite) {
+            events.push(Event::UnsupportedCipherSuite(cipher_suite));
+        }
+        if selected_version == TLS_1_3 {
+            /* ServerHello より後ろはどちらの方向もハンドシェイクの鍵で暗号化される */
+            let mut keylog = keylog;
+            let client = self.tls13_keys(keylog::CLIENT_HANDSHAKE_TRAFFIC_SECRET, keylog.as_deref_mut());
+            let server = self.tls13_keys(keylog::SERVER_HANDSHAKE_TRAFFIC_SECRET, keylog);
+            self.client.set_keys(client);
+            self.server.set_keys(server);
+        }
+        Some(())
+    }
+
+    fn certificate(&mut self, body: &[u8], events: &mut Vec<Event>) -> Option<()> {
+        let mut rest = body;
+        if self.version == TLS_1_3 {
+            rest = take_vec8(rest)?.1; /* certificate request context */
+        }
+        let (list, _) = take_vec24(rest)?;
+        /* 先頭がエンドエンティティの証明書 */
+        let (der, _) = take_vec24(list)?;
+        match x509::parse(der) {
+            Some(certificate) => events.push(Event::Certificate(certificate)),
+            None => events.push(Event::Malformed("certificate")),
+        }
+        Some(())
+    }
+
+    /// TLS 1.3 では Finished の後からアプリケーションデータ用の鍵に切り替わる
+    fn finished(&mut self, from_client: bool, keylog: Option<&mut KeyLog>) {
+        if self.version != TLS_1_3 {
+            return;
+        }
+        let label = if from_client {
+            keylog::CLIENT_TRAFFIC_SECRET_0
+        } else {
+            keylog::SERVER_TRAFFIC_SECRET_0
+        };
+        let keys = self.tls13_keys(label, keylog);
+        self.direction(from_client).set_keys(keys);
+    }
+
+    fn tls13_keys(&self, label: &str, keylog: Option<&mut KeyLog>) -> Option<RecordKeys> {
+        let secret = keylog?.get(label, &self.client_random)?;
+        RecordKeys::tls13(self.cipher_suite, &secret)
+    }
+
+    fn tls12_keys(&self, from_client: bool, keylog: Option<&mut KeyLog>) -> Option<RecordKeys> {
+        let master_secret = keylog?.get(keylog::CLIENT_RANDOM, &self.client_random)?;
+        let (client, server) = RecordKeys::tls12(
+            self.cipher_suite,
+            &master_secret,
+            &self.client_random,
+            &self.server_random,
+        )?;
+        Some(if from_client { client } else { server })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// ServerHello (supported_versions で TLS 1.3 を選ぶ) を 1 レコードにする
+    fn server_hello(random: [u8; 32], cipher_suite: u16) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&random);
+        body.push(0); /* session id */
+        body.extend_from_slice(&cipher_suite.to_be_bytes());
+        body.push(0); /* compression method */
+        body.extend_from_slice(&[0, 6, 0, 43, 0, 2, 0x03, 0x04]);
+        let mut record = vec![CONTENT_HANDSHAKE, 0x03, 0x03];
+        record.extend_from_slice(&(body.len() as u16 + 4).to_be_bytes());
+        record.extend_from_slice(&[HANDSHAKE_SERVER_HELLO, 0, 0, body.len() as u8]);
+        record.extend_from_slice(&body);
+        record
+    }
+
+    #[test]
+    fn hello_retry_request() {
+        let mut session = Session::default();
+        let events = session.push(false, &server_hello(HELLO_RETRY_REQUEST_RANDOM, 0x1301), None);
+        assert!(matches!(
+            events[..],
+            [Event::HelloRetryRequest {
+                cipher_suite: 0x1301
+            }]
+        ));
+        /* やり直しの後も平文のまま本当の ServerHello を読める */
+        let events = session.push(false, &server_hello([0x22; 32], 0x1301), None);
+        assert!(matches!(
+            events[..],
+            [Event::ServerHello {
+                version: TLS_1_3,
+                cipher_suite: 0x1301
+            }]
+        ));
+        assert_eq!(session.server_random, [0x22; 32]);
+        assert!(session.server.encrypted);
+    }
+
+    #[test]
+    fn unsupported_cipher_suite() {
+        let path = std::env::temp_dir().join(format!("packetdump-keylog-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let mut keylog = KeyLog::open(path.to_str().unwrap()).unwrap();
+        let mut session = Session::default();
+        let events = session.push(false, &server_hello([0x22; 32], 0x1303), Some(&mut keylog));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            events[..],
+            [Event::ServerHello { .. }, Event::UnsupportedCipherSuite(0x1303)]
+        ));
+        /* 鍵ログがなければ警告しない */
+        let events = Session::default().push(false, &server_hello([0x22; 32], 0x1303), None);
+        assert_eq!(events.len(), 1);
+    }
+}
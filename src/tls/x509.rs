@@ -0,0 +1,152 @@
+//! 証明書 (X.509, DER) から subject, issuer と Modbus Role 拡張を取り出す。
+//! 必要な部分だけを読む簡単な DER パーサで、署名の検証などは行わない。
+
+/// Modbus/TCP Security の Role 拡張 (値は UTF8String の役割名)
+pub const MODBUS_ROLE_OID: &str = "1.3.6.1.4.1.50316.802.1";
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    pub role: Option<String>,
+}
+
+/// タグ, 中身, 残りに分ける
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    if input.len() < 2 {
+        return None;
+    }
+    let tag = input[0];
+    let (length, header) = if input[1] & 0x80 == 0 {
+        (input[1] as usize, 2)
+    } else {
+        let count = (input[1] & 0x7f) as usize;
+        if count == 0 || count > 4 || input.len() < 2 + count {
+            return None;
+        }
+        let length = input[2..2 + count]
+            .iter()
+            .fold(0usize, |length, byte| length << 8 | *byte as usize);
+        (length, 2 + count)
+    };
+    if input.len() < header + length {
+        return None;
+    }
+    Some((tag, &input[header..header + length], &input[header + length..]))
+}
+
+/// 期待したタグでなければ None
+fn expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match read_tlv(input) {
+        Some((t, content, rest)) if t == tag => Some((content, rest)),
+        _ => None,
+    }
+}
+
+fn oid_to_string(oid: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut value: u64 = 0;
+    for byte in oid {
+        value = value << 7 | (*byte & 0x7f) as u64;
+        if *byte & 0x80 == 0 {
+            if parts.is_empty() {
+                let first = if value < 80 { value / 40 } else { 2 };
+                parts.push(first.to_string());
+                parts.push((value - first * 40).to_string());
+            } else {
+                parts.push(value.to_string());
+            }
+            value = 0;
+        }
+    }
+    parts.join(".")
+}
+
+fn attribute_name(oid: &str) -> &str {
+    match oid {
+        "2.5.4.3" => "CN",
+        "2.5.4.6" => "C",
+        "2.5.4.7" => "L",
+        "2.5.4.8" => "ST",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        _ => oid,
+    }
+}
+
+/// Name (RDN の並び) を "CN=..., O=..." の形にする
+fn name_to_string(name: &[u8]) -> Option<String> {
+    let mut attributes = Vec::new();
+    let mut rdns = name;
+    while !rdns.is_empty() {
+        let (rdn, rest) = expect(rdns, TAG_SET)?;
+        rdns = rest;
+        let mut values = rdn;
+        while !values.is_empty() {
+            let (pair, rest) = expect(values, TAG_SEQUENCE)?;
+            values = rest;
+            let (oid, value) = expect(pair, TAG_OID)?;
+            let (_, text, _) = read_tlv(value)?;
+            attributes.push(format!(
+                "{}={}",
+                attribute_name(&oid_to_string(oid)),
+                String::from_utf8_lossy(text)
+            ));
+        }
+    }
+    Some(attributes.join(", "))
+}
+
+/// 拡張の並びから Modbus Role を探す
+fn find_role(extensions: &[u8]) -> Option<String> {
+    let (mut list, _) = expect(extensions, TAG_SEQUENCE)?;
+    while !list.is_empty() {
+        let (extension, rest) = expect(list, TAG_SEQUENCE)?;
+        list = rest;
+        let (oid, mut fields) = expect(extension, TAG_OID)?;
+        if let Some((_, rest)) = expect(fields, TAG_BOOLEAN) {
+            fields = rest;
+        }
+        if oid_to_string(oid) == MODBUS_ROLE_OID {
+            let (value, _) = expect(fields, TAG_OCTET_STRING)?;
+            let (_, role, _) = read_tlv(value)?;
+            return Some(String::from_utf8_lossy(role).into_owned());
+        }
+    }
+    None
+}
+
+/// DER の証明書を解析する。形式が崩れていれば None
+pub fn parse(der: &[u8]) -> Option<Certificate> {
+    let (certificate, _) = expect(der, TAG_SEQUENCE)?;
+    let (tbs, _) = expect(certificate, TAG_SEQUENCE)?;
+    let mut fields = tbs;
+    if let Some((_, rest)) = expect(fields, TAG_VERSION) {
+        fields = rest;
+    }
+    let (_, fields) = expect(fields, TAG_INTEGER)?; /* serialNumber */
+    let (_, fields) = expect(fields, TAG_SEQUENCE)?; /* signature */
+    let (issuer, fields) = expect(fields, TAG_SEQUENCE)?;
+    let (_, fields) = expect(fields, TAG_SEQUENCE)?; /* validity */
+    let (subject, mut fields) = expect(fields, TAG_SEQUENCE)?;
+    let mut role = None;
+    while let Some((tag, content, rest)) = read_tlv(fields) {
+        if tag == TAG_EXTENSIONS {
+            role = find_role(content);
+        }
+        fields = rest;
+    }
+    Some(Certificate {
+        subject: name_to_string(subject)?,
+        issuer: name_to_string(issuer)?,
+        role,
+    })
+}
@@ -0,0 +1,705 @@
+//! -v の階層表示と -x の 16 進ダンプ。解析とは別にフレームを先頭から読み直し、
+//! 各層 (リンク層, IP, TCP/UDP, MBAP, PDU) のフィールドをフレーム先頭からのオフセットと長さ付きで並べる。
+//! PDU の値は解析と同じ AduFields::decode で読み、表示と同じ名前 (address など) で示す。
+//! フレームが途中で切れていれば、そこまでの層とフィールドだけを返す。
+
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use super::link::{self, LinkType};
+use super::packet::fields::AduFields;
+use super::packet::modbus_tcp::*;
+use super::{skip_ipv6_extensions, Framing};
+
+const ETHERNET_HEADER_LENGTH: usize = 14;
+const VLAN_TAG_LENGTH: usize = 4;
+const IPV4_MINIMUM_HEADER_LENGTH: usize = 20;
+const IPV6_HEADER_LENGTH: usize = 40;
+const TCP_MINIMUM_HEADER_LENGTH: usize = 20;
+const UDP_HEADER_LENGTH: usize = 8;
+const RTU_CRC_LENGTH: usize = 2;
+
+const HEX_DUMP_WIDTH: usize = 16;
+/// 端末に出すときに Modbus のバイトを反転表示する
+const HIGHLIGHT_START: &str = "\x1b[7m";
+const HIGHLIGHT_END: &str = "\x1b[0m";
+
+pub struct Node {
+    pub label: String,
+    /// フレーム先頭からのオフセット
+    pub offset: usize,
+    pub length: usize,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    fn new(label: impl Into<String>, offset: usize, length: usize) -> Node {
+        Node {
+            label: label.into(),
+            offset,
+            length,
+            children: Vec::new(),
+        }
+    }
+
+    fn field(&mut self, name: &str, value: impl Display, offset: usize, length: usize) {
+        self.children
+            .push(Node::new(format!("{}: {}", name, value), offset, length));
+    }
+}
+
+#[derive(Default)]
+pub struct Dissection {
+    pub layers: Vec<Node>,
+    /// Modbus (MBAP ヘッダからの ADU, RTU フレームなど) のバイトの範囲 (開始, 終わり)
+    pub modbus: Option<(usize, usize)>,
+}
+
+impl Dissection {
+    fn mark_modbus(&mut self, offset: usize, length: usize) {
+        let (start, end) = self.modbus.unwrap_or((offset, offset + length));
+        self.modbus = Some((start.min(offset), end.max(offset + length)));
+    }
+}
+
+fn u16_at(frame: &[u8], offset: usize) -> Option<u16> {
+    let bytes = frame.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(frame: &[u8], offset: usize) -> Option<u32> {
+    let bytes = frame.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn mac_at(frame: &[u8], offset: usize) -> Option<MacAddr> {
+    let b = frame.get(offset..offset + 6)?;
+    Some(MacAddr(b[0], b[1], b[2], b[3], b[4], b[5]))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// フレームの一部分のスライスがフレームの先頭から何バイト目にあるか
+fn offset_of(frame: &[u8], part: &[u8]) -> usize {
+    part.as_ptr() as usize - frame.as_ptr() as usize
+}
+
+/// フレームを層ごとに分解する。serial_request はシリアルバスのフレームをリクエストとみなすか
+pub fn dissect(
+    linktype: LinkType,
+    frame: &[u8],
+    modbus_ports: &HashMap<u16, Framing>,
+    serial_request: bool,
+) -> Dissection {
+    let mut dissection = Dissection::default();
+    if linktype == LinkType::Serial {
+        dissect_rtu(&mut dissection, frame, 0, frame.len(), serial_request);
+        return dissection;
+    }
+    let (mut ethertype, mut offset) = match dissect_link(&mut dissection, linktype, frame) {
+        Some(next) => next,
+        None => return dissection,
+    };
+    /* VLAN タグ */
+    while let EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ = ethertype {
+        let tci = match u16_at(frame, offset) {
+            Some(tci) => tci,
+            None => return dissection,
+        };
+        let inner = match u16_at(frame, offset + 2) {
+            Some(inner) => EtherType(inner),
+            None => return dissection,
+        };
+        let mut node = Node::new("802.1Q VLAN tag", offset, VLAN_TAG_LENGTH);
+        node.field("priority", tci >> 13, offset, 2);
+        node.field("vlan id", tci & 0x0fff, offset, 2);
+        node.field("type", inner, offset + 2, 2);
+        dissection.layers.push(node);
+        ethertype = inner;
+        offset += VLAN_TAG_LENGTH;
+    }
+    match ethertype {
+        EtherTypes::Ipv4 => dissect_ipv4(&mut dissection, frame, offset, modbus_ports),
+        EtherTypes::Ipv6 => dissect_ipv6(&mut dissection, frame, offset, modbus_ports),
+        _ => dissect_data(&mut dissection, frame, offset, frame.len()),
+    }
+    dissection
+}
+
+/// リンク層ヘッダ。(中身の EtherType, 中身のオフセット) を返す
+fn dissect_link(
+    dissection: &mut Dissection,
+    linktype: LinkType,
+    frame: &[u8],
+) -> Option<(EtherType, usize)> {
+    if linktype == LinkType::Ethernet {
+        let ethertype = EtherType(u16_at(frame, 12)?);
+        let mut node = Node::new("Ethernet II", 0, ETHERNET_HEADER_LENGTH);
+        node.field("destination", mac_at(frame, 0)?, 0, 6);
+        node.field("source", mac_at(frame, 6)?, 6, 6);
+        node.field("type", ethertype, 12, 2);
+        dissection.layers.push(node);
+        return Some((ethertype, ETHERNET_HEADER_LENGTH));
+    }
+    let decoded = link::decode(linktype, frame)?;
+    let offset = offset_of(frame, decoded.payload);
+    let mut node = Node::new(format!("{:?} header", linktype), 0, offset);
+    if let Some(source) = decoded.source {
+        node.field("source", source, 0, offset);
+    }
+    node.field("protocol", decoded.ethertype, 0, offset);
+    if offset > 0 {
+        dissection.layers.push(node);
+    }
+    Some((decoded.ethertype, offset))
+}
+
+fn dissect_ipv4(
+    dissection: &mut Dissection,
+    frame: &[u8],
+    offset: usize,
+    modbus_ports: &HashMap<u16, Framing>,
+) {
+    let header = match frame.get(offset..offset + IPV4_MINIMUM_HEADER_LENGTH) {
+        Some(header) => header,
+        None => return dissect_data(dissection, frame, offset, frame.len()),
+    };
+    let header_length = (header[0] & 0x0f) as usize * 4;
+    /* IHL が 5 未満のヘッダは解析でも Malformed にするので中身をそのまま示す */
+    if header_length < IPV4_MINIMUM_HEADER_LENGTH {
+        return dissect_data(dissection, frame, offset, frame.len());
+    }
+    let total_length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let flags_fragment = u16::from_be_bytes([header[6], header[7]]);
+    let protocol = IpNextHeaderProtocol(header[9]);
+    let source = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+    let destination = Ipv4Addr::new(header[16], header[17], header[18], header[19]);
+    let mut node = Node::new("Internet Protocol Version 4", offset, header_length);
+    node.field("version", header[0] >> 4, offset, 1);
+    node.field("header length", header_length, offset, 1);
+    node.field("total length", total_length, offset + 2, 2);
+    node.field(
+        "identification",
+        u16::from_be_bytes([header[4], header[5]]),
+        offset + 4,
+        2,
+    );
+    node.field(
+        "flags",
+        format!("{:#x}", flags_fragment >> 13),
+        offset + 6,
+        2,
+    );
+    node.field(
+        "fragment offset",
+        (flags_fragment & 0x1fff) as usize * 8,
+        offset + 6,
+        2,
+    );
+    node.field("time to live", header[8], offset + 8, 1);
+    node.field("protocol", protocol, offset + 9, 1);
+    node.field(
+        "checksum",
+        format!("{:#06x}", u16::from_be_bytes([header[10], header[11]])),
+        offset + 10,
+        2,
+    );
+    node.field("source", source, offset + 12, 4);
+    node.field("destination", destination, offset + 16, 4);
+    dissection.layers.push(node);
+
+    let end = (offset + total_length).min(frame.len());
+    let payload_offset = offset + header_length;
+    if payload_offset > end {
+        return;
+    }
+    /* フラグメントは組み立て前なので中身をそのまま示す */
+    if flags_fragment & 0x1fff != 0 || flags_fragment & 0x2000 != 0 {
+        return dissect_data(dissection, frame, payload_offset, end);
+    }
+    dissect_transport(
+        dissection,
+        frame,
+        payload_offset,
+        end,
+        protocol,
+        modbus_ports,
+    );
+}
+
+fn dissect_ipv6(
+    dissection: &mut Dissection,
+    frame: &[u8],
+    offset: usize,
+    modbus_ports: &HashMap<u16, Framing>,
+) {
+    let header = match frame.get(offset..offset + IPV6_HEADER_LENGTH) {
+        Some(header) => header,
+        None => return dissect_data(dissection, frame, offset, frame.len()),
+    };
+    let payload_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let next_header = IpNextHeaderProtocol(header[6]);
+    let mut address = [0u8; 16];
+    address.copy_from_slice(&header[8..24]);
+    let source = Ipv6Addr::from(address);
+    address.copy_from_slice(&header[24..40]);
+    let destination = Ipv6Addr::from(address);
+    let mut node = Node::new("Internet Protocol Version 6", offset, IPV6_HEADER_LENGTH);
+    node.field("version", header[0] >> 4, offset, 1);
+    node.field("payload length", payload_length, offset + 4, 2);
+    node.field("next header", next_header, offset + 6, 1);
+    node.field("hop limit", header[7], offset + 7, 1);
+    node.field("source", source, offset + 8, 16);
+    node.field("destination", destination, offset + 24, 16);
+
+    let payload_offset = offset + IPV6_HEADER_LENGTH;
+    let end = (payload_offset + payload_length).min(frame.len());
+    let upper = skip_ipv6_extensions(next_header, &frame[payload_offset.min(end)..end]);
+    match upper {
+        Some((protocol, payload)) if protocol != IpNextHeaderProtocols::Ipv6Frag => {
+            let upper_offset = offset_of(frame, payload);
+            if upper_offset > payload_offset {
+                node.field(
+                    "extension headers",
+                    upper_offset - payload_offset,
+                    payload_offset,
+                    upper_offset - payload_offset,
+                );
+            }
+            dissection.layers.push(node);
+            dissect_transport(dissection, frame, upper_offset, end, protocol, modbus_ports);
+        }
+        _ => {
+            dissection.layers.push(node);
+            dissect_data(dissection, frame, payload_offset, end);
+        }
+    }
+}
+
+fn dissect_transport(
+    dissection: &mut Dissection,
+    frame: &[u8],
+    offset: usize,
+    end: usize,
+    protocol: IpNextHeaderProtocol,
+    modbus_ports: &HashMap<u16, Framing>,
+) {
+    let (source_port, destination_port, header_length) = match protocol {
+        IpNextHeaderProtocols::Tcp => {
+            let header = match frame.get(offset..offset + TCP_MINIMUM_HEADER_LENGTH) {
+                Some(header) if offset + TCP_MINIMUM_HEADER_LENGTH <= end => header,
+                _ => return dissect_data(dissection, frame, offset, end),
+            };
+            let header_length = (header[12] >> 4) as usize * 4;
+            if header_length < TCP_MINIMUM_HEADER_LENGTH {
+                return dissect_data(dissection, frame, offset, end);
+            }
+            let source_port = u16::from_be_bytes([header[0], header[1]]);
+            let destination_port = u16::from_be_bytes([header[2], header[3]]);
+            let mut node = Node::new("Transmission Control Protocol", offset, header_length);
+            node.field("source port", source_port, offset, 2);
+            node.field("destination port", destination_port, offset + 2, 2);
+            node.field(
+                "sequence number",
+                u32_at(frame, offset + 4).unwrap_or(0),
+                offset + 4,
+                4,
+            );
+            node.field(
+                "acknowledgment number",
+                u32_at(frame, offset + 8).unwrap_or(0),
+                offset + 8,
+                4,
+            );
+            node.field("header length", header_length, offset + 12, 1);
+            node.field(
+                "flags",
+                format!(
+                    "{:#05x}",
+                    u16::from_be_bytes([header[12], header[13]]) & 0x0fff
+                ),
+                offset + 12,
+                2,
+            );
+            node.field(
+                "window",
+                u16::from_be_bytes([header[14], header[15]]),
+                offset + 14,
+                2,
+            );
+            node.field(
+                "checksum",
+                format!("{:#06x}", u16::from_be_bytes([header[16], header[17]])),
+                offset + 16,
+                2,
+            );
+            dissection.layers.push(node);
+            (source_port, destination_port, header_length)
+        }
+        IpNextHeaderProtocols::Udp => {
+            let header = match frame.get(offset..offset + UDP_HEADER_LENGTH) {
+                Some(header) if offset + UDP_HEADER_LENGTH <= end => header,
+                _ => return dissect_data(dissection, frame, offset, end),
+            };
+            let source_port = u16::from_be_bytes([header[0], header[1]]);
+            let destination_port = u16::from_be_bytes([header[2], header[3]]);
+            let mut node = Node::new("User Datagram Protocol", offset, UDP_HEADER_LENGTH);
+            node.field("source port", source_port, offset, 2);
+            node.field("destination port", destination_port, offset + 2, 2);
+            node.field(
+                "length",
+                u16::from_be_bytes([header[4], header[5]]),
+                offset + 4,
+                2,
+            );
+            node.field(
+                "checksum",
+                format!("{:#06x}", u16::from_be_bytes([header[6], header[7]])),
+                offset + 6,
+                2,
+            );
+            dissection.layers.push(node);
+            (source_port, destination_port, UDP_HEADER_LENGTH)
+        }
+        _ => return dissect_data(dissection, frame, offset, end),
+    };
+    let payload_offset = (offset + header_length).min(end);
+    if payload_offset == end {
+        return;
+    }
+    /* (送信元, 送信先) のどちらが Modbus のポートか */
+    let modbus = match (
+        modbus_ports.get(&source_port),
+        modbus_ports.get(&destination_port),
+    ) {
+        (_, Some(framing)) => Some((*framing, true)),
+        (Some(framing), _) => Some((*framing, false)),
+        (_, _) => None,
+    };
+    match modbus {
+        Some((Framing::Tcp, is_request)) => {
+            dissect_mbap(dissection, frame, payload_offset, end, is_request)
+        }
+        Some((Framing::Rtu, is_request)) => {
+            dissect_rtu(dissection, frame, payload_offset, end, is_request)
+        }
+        Some((Framing::Ascii, _)) => {
+            dissection.mark_modbus(payload_offset, end - payload_offset);
+            dissection.layers.push(Node::new(
+                "Modbus ASCII",
+                payload_offset,
+                end - payload_offset,
+            ));
+        }
+        Some((Framing::Tls, _)) => {
+            dissection.layers.push(Node::new(
+                "Transport Layer Security",
+                payload_offset,
+                end - payload_offset,
+            ));
+        }
+        None => dissect_data(dissection, frame, payload_offset, end),
+    }
+}
+
+/// MBAP ヘッダ付きの ADU (1 つのセグメントに複数あればすべて)
+fn dissect_mbap(
+    dissection: &mut Dissection,
+    frame: &[u8],
+    offset: usize,
+    end: usize,
+    is_request: bool,
+) {
+    let mut offset = offset;
+    while offset + MBAP_HEADER_LENGTH < end {
+        let length = u16_at(frame, offset + 4).unwrap_or(0) as usize;
+        let adu_end = (offset + 6 + length).min(end);
+        if length < 2 {
+            break;
+        }
+        let mut node = Node::new("Modbus/TCP", offset, adu_end - offset);
+        node.field("transaction", u16_at(frame, offset).unwrap_or(0), offset, 2);
+        node.field(
+            "protocol",
+            u16_at(frame, offset + 2).unwrap_or(0),
+            offset + 2,
+            2,
+        );
+        node.field("length", length, offset + 4, 2);
+        node.field("unit", frame[offset + 6], offset + 6, 1);
+        node.children.push(dissect_pdu(
+            frame,
+            offset + MBAP_HEADER_LENGTH,
+            adu_end,
+            is_request,
+        ));
+        dissection.mark_modbus(offset, adu_end - offset);
+        dissection.layers.push(node);
+        offset = adu_end;
+    }
+    if offset < end {
+        dissect_data(dissection, frame, offset, end);
+    }
+}
+
+fn dissect_rtu(
+    dissection: &mut Dissection,
+    frame: &[u8],
+    offset: usize,
+    end: usize,
+    is_request: bool,
+) {
+    if end < offset + 2 + RTU_CRC_LENGTH {
+        return dissect_data(dissection, frame, offset, end);
+    }
+    let crc_offset = end - RTU_CRC_LENGTH;
+    let mut node = Node::new("Modbus RTU", offset, end - offset);
+    node.field("address", frame[offset], offset, 1);
+    node.children
+        .push(dissect_pdu(frame, offset + 1, crc_offset, is_request));
+    node.field(
+        "crc",
+        format!(
+            "{:#06x}",
+            u16::from_le_bytes([frame[crc_offset], frame[crc_offset + 1]])
+        ),
+        crc_offset,
+        RTU_CRC_LENGTH,
+    );
+    dissection.mark_modbus(offset, end - offset);
+    dissection.layers.push(node);
+}
+
+/// ファンクションコードからの PDU。値は解析と同じ AduFields::decode の結果を使い、
+/// ここではそれぞれのフィールドが PDU のどこにあるかだけを決める
+fn dissect_pdu(frame: &[u8], offset: usize, end: usize, is_request: bool) -> Node {
+    let pdu = &frame[offset..end];
+    let mut node = Node::new(
+        if is_request {
+            "PDU (request)"
+        } else {
+            "PDU (reply)"
+        },
+        offset,
+        pdu.len(),
+    );
+    let function = match pdu.first() {
+        Some(function) => *function,
+        None => return node,
+    };
+    let fields = match AduFields::decode(&to_mbap(0, 0, pdu), is_request) {
+        Some(fields) => fields,
+        None => return node,
+    };
+    let name = FunctionField(fields.function).name().unwrap_or("unknown");
+    node.field("function", format!("{} ({})", function, name), offset, 1);
+    let mut field = |name: &str, value: Option<String>, at: usize, length: usize| {
+        if let Some(value) = value {
+            node.field(name, value, offset + at, length.min(pdu.len().saturating_sub(at)));
+        }
+    };
+    let address = fields.address.map(|address| match fields.reference() {
+        Some(reference) => format!("{} (reference {})", address, reference),
+        None => address.to_string(),
+    });
+    let data = Some(&fields.data).filter(|data| !data.is_empty()).map(|data| hex(data));
+    let values = Some(&fields.values).filter(|values| !values.is_empty()).map(|values| format!("{:?}", values));
+    let byte_count = fields.byte_count.map(|byte_count| byte_count.to_string());
+    let data_length = fields.data.len();
+    match (FunctionField(fields.function), is_request) {
+        _ if fields.exception => {
+            field("exception code", fields.exception_code.map(|code| code.to_string()), 1, 1);
+        }
+        (FunctionFieldValues::ReadCoilStatus, true)
+        | (FunctionFieldValues::ReadInputStatus, true)
+        | (FunctionFieldValues::ReadHoldingRegister, true)
+        | (FunctionFieldValues::ReadInputRegister, true)
+        | (FunctionFieldValues::ForceMultipleCoils, false)
+        | (FunctionFieldValues::PresetMultipleRegisters, false) => {
+            field("address", address, 1, 2);
+            field("quantity", fields.quantity.map(|quantity| quantity.to_string()), 3, 2);
+        }
+        (FunctionFieldValues::ForceSingleCoil, _) => {
+            field("address", address, 1, 2);
+            field("value", fields.data_value.map(|value| CoilValue(value).to_string()), 3, 2);
+        }
+        (FunctionFieldValues::PresetSingleRegister, _) => {
+            field("address", address, 1, 2);
+            field("value", fields.data_value.map(|value| value.to_string()), 3, 2);
+        }
+        (FunctionFieldValues::ForceMultipleCoils, true)
+        | (FunctionFieldValues::PresetMultipleRegisters, true) => {
+            field("address", address, 1, 2);
+            field("quantity", fields.quantity.map(|quantity| quantity.to_string()), 3, 2);
+            field("byte count", byte_count, 5, 1);
+            field("data", data, 6, data_length);
+            field("values", values, 6, data_length);
+        }
+        (FunctionFieldValues::ReadCoilStatus, false)
+        | (FunctionFieldValues::ReadInputStatus, false)
+        | (FunctionFieldValues::ReadHoldingRegister, false)
+        | (FunctionFieldValues::ReadInputRegister, false) => {
+            field("byte count", byte_count, 1, 1);
+            field("data", data, 2, data_length);
+            field("values", values, 2, data_length);
+        }
+        (FunctionFieldValues::Diagnostics, _) => {
+            field("sub code", fields.sub_code.map(|code| code.to_string()), 1, 2);
+            field("data", fields.data_value.map(|value| value.to_string()), 3, 2);
+        }
+        (FunctionFieldValues::FetchCommunicationEventCounter, false) => {
+            field("status", fields.status.map(|status| status.to_string()), 1, 2);
+            field("event counter", fields.event_counter.map(|counter| counter.to_string()), 3, 2);
+        }
+        (FunctionFieldValues::FetchCommunicationEventCounterLog, false) => {
+            field("byte count", byte_count, 1, 1);
+            field("status", fields.status.map(|status| status.to_string()), 2, 2);
+            field("event counter", fields.event_counter.map(|counter| counter.to_string()), 4, 2);
+            field("message counter", fields.message_counter.map(|counter| counter.to_string()), 6, 2);
+            field("events", data, 8, data_length);
+        }
+        (FunctionFieldValues::ReportSlaveID, false) => {
+            field("byte count", byte_count, 1, 1);
+            field("data", data, 2, data_length);
+        }
+        /* 形の分からないファンクションはファンクションコードの後をそのまま示す */
+        _ => field("data", Some(hex(&pdu[1..])).filter(|data| !data.is_empty()), 1, pdu.len() - 1),
+    }
+    if let Some(malformed) = &fields.malformed {
+        node.field("malformed", malformed, offset, pdu.len());
+    }
+    node
+}
+
+fn dissect_data(dissection: &mut Dissection, frame: &[u8], offset: usize, end: usize) {
+    if offset < end {
+        let mut node = Node::new("Data", offset, end - offset);
+        node.field("data", hex(&frame[offset..end]), offset, end - offset);
+        dissection.layers.push(node);
+    }
+}
+
+fn print_node(node: &Node, depth: usize) {
+    println!(
+        "{}{} [offset {}, length {}]",
+        "    ".repeat(depth),
+        node.label,
+        node.offset,
+        node.length
+    );
+    for child in &node.children {
+        print_node(child, depth + 1);
+    }
+}
+
+pub fn print_tree(dissection: &Dissection) {
+    for layer in &dissection.layers {
+        print_node(layer, 1);
+    }
+}
+
+/// 16 バイトずつの 16 進と ASCII。Modbus のバイトは、color なら反転表示し、
+/// そうでなければ下の行に ^^ で示す
+pub fn print_hex_dump(frame: &[u8], modbus: Option<(usize, usize)>, color: bool) {
+    let in_modbus = |i: usize| modbus.is_some_and(|(start, end)| start <= i && i < end);
+    for (line, chunk) in frame.chunks(HEX_DUMP_WIDTH).enumerate() {
+        let base = line * HEX_DUMP_WIDTH;
+        let mut hex_part = String::new();
+        let mut marks = String::new();
+        let mut ascii_part = String::new();
+        for (i, byte) in chunk.iter().enumerate() {
+            let highlighted = in_modbus(base + i);
+            let c = if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            };
+            if color && highlighted {
+                hex_part.push_str(&format!(
+                    " {}{:02x}{}",
+                    HIGHLIGHT_START, byte, HIGHLIGHT_END
+                ));
+                ascii_part.push_str(&format!("{}{}{}", HIGHLIGHT_START, c, HIGHLIGHT_END));
+            } else {
+                hex_part.push_str(&format!(" {:02x}", byte));
+                ascii_part.push(c);
+            }
+            marks.push_str(if highlighted { " ^^" } else { "   " });
+        }
+        let padding = "   ".repeat(HEX_DUMP_WIDTH - chunk.len());
+        println!("    {:04x} {}{}  {}", base, hex_part, padding, ascii_part);
+        if !color && marks.contains('^') {
+            println!("         {}", marks.trim_end());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pdu: &[u8], is_request: bool) -> Vec<String> {
+        dissect_pdu(pdu, 0, pdu.len(), is_request)
+            .children
+            .into_iter()
+            .map(|child| child.label)
+            .collect()
+    }
+
+    #[test]
+    fn pdu_fields() {
+        assert_eq!(
+            labels(&[3, 0, 89, 0, 21], true),
+            ["function: 3 (read_holding_register)", "address: 89 (reference 40090)", "quantity: 21"]
+        );
+        assert_eq!(
+            labels(&[5, 0, 0, 0xff, 0], true),
+            ["function: 5 (force_single_coil)", "address: 0 (reference 1)", "value: ON"]
+        );
+        assert_eq!(
+            labels(&[3, 4, 0, 1, 0, 2], false),
+            ["function: 3 (read_holding_register)", "byte count: 4", "data: 00010002", "values: [1, 2]"]
+        );
+        assert_eq!(
+            labels(&[0x83, 2], false),
+            ["function: 131 (read_holding_register)", "exception code: 2"]
+        );
+    }
+
+    #[test]
+    fn malformed_pdu() {
+        assert_eq!(
+            labels(&[3, 4, 0], false),
+            [
+                "function: 3 (read_holding_register)",
+                "byte count: 4",
+                "data: 00",
+                "malformed: byte count 4 but 1 bytes follow"
+            ]
+        );
+        assert_eq!(
+            labels(&[0x83], false),
+            [
+                "function: 131 (read_holding_register)",
+                "malformed: PDU of 1 bytes, function 131 needs at least 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn short_ipv4_header() {
+        /* IHL が 4 (16 バイト) のヘッダは IP として読まない */
+        let mut frame = vec![0u8; ETHERNET_HEADER_LENGTH];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0x44; 40]);
+        let dissection = dissect(LinkType::Ethernet, &frame, &HashMap::new(), false);
+        let labels: Vec<&str> = dissection.layers.iter().map(|layer| layer.label.as_str()).collect();
+        assert_eq!(labels, ["Ethernet II", "Data"]);
+    }
+}
@@ -0,0 +1,249 @@
+//! ミラーリング用のトンネル (GRE, ERSPAN type II/III, VXLAN) を外し、中のフレームを取り出す。
+//! GRE
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |C| |K|S| Reserved0       | Ver |         Protocol Type         |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |      Checksum (optional)      |       Reserved1 (Optional)    |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                         Key (optional)                        |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                 Sequence Number (Optional)                    |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!
+//! VXLAN (UDP 4789)
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |R|R|R|R|I|R|R|R|            Reserved                           |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                VXLAN Network Identifier (VNI) |   Reserved    |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+
+use pnet::packet::ethernet::EtherType;
+use std::fmt;
+
+use super::link::{self, Frame, LinkType};
+
+pub const VXLAN_PORT: u16 = 4789;
+
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+
+/// GRE の Protocol Type
+const PROTOCOL_TRANSPARENT_ETHERNET: u16 = 0x6558;
+const PROTOCOL_ERSPAN_II: u16 = 0x88be;
+const PROTOCOL_ERSPAN_III: u16 = 0x22eb;
+
+const ERSPAN_II_HEADER_LENGTH: usize = 8;
+const ERSPAN_III_HEADER_LENGTH: usize = 12;
+/// ERSPAN type III の O フラグが立っていると付く platform specific subheader
+const ERSPAN_III_SUBHEADER_LENGTH: usize = 8;
+
+const VXLAN_HEADER_LENGTH: usize = 8;
+const VXLAN_FLAG_VNI: u8 = 0x08;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tunnel {
+    Gre,
+    ErspanII { session: u16 },
+    ErspanIII { session: u16 },
+    Vxlan { vni: u32 },
+}
+
+impl fmt::Display for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tunnel::Gre => write!(f, "GRE"),
+            Tunnel::ErspanII { session } => write!(f, "ERSPAN II session {}", session),
+            Tunnel::ErspanIII { session } => write!(f, "ERSPAN III session {}", session),
+            Tunnel::Vxlan { vni } => write!(f, "VXLAN VNI {}", vni),
+        }
+    }
+}
+
+/// ERSPAN のヘッダの先頭 2 バイト目からの 10 ビットのセッション ID
+fn erspan_session(header: &[u8]) -> u16 {
+    u16::from_be_bytes([header[2], header[3]]) & 0x03ff
+}
+
+/// GRE のヘッダを外す。中身を解析できなければ None
+pub fn decode_gre(packet: &[u8]) -> Option<(Tunnel, Frame<'_>)> {
+    if packet.len() < 4 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([packet[0], packet[1]]);
+    let protocol = u16::from_be_bytes([packet[2], packet[3]]);
+    let mut length = 4;
+    for flag in &[GRE_CHECKSUM, GRE_KEY, GRE_SEQUENCE] {
+        if flags & flag != 0 {
+            length += 4;
+        }
+    }
+    if packet.len() < length {
+        return None;
+    }
+    let payload = &packet[length..];
+    match protocol {
+        PROTOCOL_TRANSPARENT_ETHERNET => {
+            Some((Tunnel::Gre, link::decode(LinkType::Ethernet, payload)?))
+        }
+        PROTOCOL_ERSPAN_II if flags & GRE_SEQUENCE == 0 => {
+            /* シーケンス番号のない ERSPAN type I はヘッダなしで Ethernet が続く */
+            Some((Tunnel::ErspanII { session: 0 }, link::decode(LinkType::Ethernet, payload)?))
+        }
+        PROTOCOL_ERSPAN_II => {
+            if payload.len() < ERSPAN_II_HEADER_LENGTH {
+                return None;
+            }
+            Some((
+                Tunnel::ErspanII {
+                    session: erspan_session(payload),
+                },
+                link::decode(LinkType::Ethernet, &payload[ERSPAN_II_HEADER_LENGTH..])?,
+            ))
+        }
+        PROTOCOL_ERSPAN_III => {
+            if payload.len() < ERSPAN_III_HEADER_LENGTH {
+                return None;
+            }
+            let mut header_length = ERSPAN_III_HEADER_LENGTH;
+            if payload[ERSPAN_III_HEADER_LENGTH - 1] & 0x01 != 0 {
+                header_length += ERSPAN_III_SUBHEADER_LENGTH;
+            }
+            if payload.len() < header_length {
+                return None;
+            }
+            Some((
+                Tunnel::ErspanIII {
+                    session: erspan_session(payload),
+                },
+                link::decode(LinkType::Ethernet, &payload[header_length..])?,
+            ))
+        }
+        /* それ以外は Protocol Type が中身の EtherType (IPv4 など) */
+        _ => Some((
+            Tunnel::Gre,
+            Frame {
+                source: None,
+                destination: None,
+                ethertype: EtherType(protocol),
+                payload,
+            },
+        )),
+    }
+}
+
+/// VXLAN のヘッダを外す
+pub fn decode_vxlan(packet: &[u8]) -> Option<(Tunnel, Frame<'_>)> {
+    if packet.len() < VXLAN_HEADER_LENGTH || packet[0] & VXLAN_FLAG_VNI == 0 {
+        return None;
+    }
+    let vni = (packet[4] as u32) << 16 | (packet[5] as u32) << 8 | packet[6] as u32;
+    Some((
+        Tunnel::Vxlan { vni },
+        link::decode(LinkType::Ethernet, &packet[VXLAN_HEADER_LENGTH..])?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::EtherTypes;
+
+    /// 宛先, 送信元, EtherType IPv4, IPv4 の先頭
+    const ETHERNET: [u8; 18] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x08, 0x00, 0x45, 0,
+        0, 20,
+    ];
+
+    /// (トンネル, EtherType, 中身の長さ)
+    fn decode(
+        decoder: fn(&[u8]) -> Option<(Tunnel, Frame<'_>)>,
+        packet: &[u8],
+    ) -> Option<(Tunnel, EtherType, usize)> {
+        let (tunnel, frame) = decoder(packet)?;
+        Some((tunnel, frame.ethertype, frame.payload.len()))
+    }
+
+    fn gre(flags: u16, protocol: u16, rest: &[&[u8]]) -> Vec<u8> {
+        let mut packet = [flags.to_be_bytes(), protocol.to_be_bytes()].concat();
+        for part in rest {
+            packet.extend_from_slice(part);
+        }
+        packet
+    }
+
+    #[test]
+    fn gre_options() {
+        let ip = [0x45, 0, 0, 20];
+        assert_eq!(
+            decode(decode_gre, &gre(0, 0x0800, &[&ip])),
+            Some((Tunnel::Gre, EtherTypes::Ipv4, 4))
+        );
+        /* C, K, S のそれぞれが 4 バイトずつ足す */
+        let option = [0xaa; 4];
+        for (flags, options) in [(0x8000, 1), (0x2000, 1), (0x1000, 1), (0xa000, 2), (0xb000, 3)] {
+            let parts: Vec<&[u8]> = std::iter::repeat_n(&option[..], options).chain([&ip[..]]).collect();
+            assert_eq!(
+                decode(decode_gre, &gre(flags, 0x0800, &parts)),
+                Some((Tunnel::Gre, EtherTypes::Ipv4, 4)),
+                "flags {:#06x}",
+                flags
+            );
+            let truncated = gre(flags, 0x0800, &vec![&option[..]; options - 1]);
+            assert!(decode_gre(&truncated).is_none(), "flags {:#06x}", flags);
+        }
+        assert_eq!(
+            decode(decode_gre, &gre(0x2000, 0x6558, &[&option, &ETHERNET])),
+            Some((Tunnel::Gre, EtherTypes::Ipv4, 4))
+        );
+        assert!(decode_gre(&gre(0, 0x6558, &[&ETHERNET[..13]])).is_none());
+        assert!(decode_gre(&[0, 0, 0x08]).is_none());
+    }
+
+    #[test]
+    fn erspan() {
+        let sequence = [0, 0, 0, 1];
+        /* type I: シーケンス番号がなければヘッダなしで Ethernet が続く */
+        assert_eq!(
+            decode(decode_gre, &gre(0, 0x88be, &[&ETHERNET])),
+            Some((Tunnel::ErspanII { session: 0 }, EtherTypes::Ipv4, 4))
+        );
+        /* type II: 8 バイトのヘッダ, セッション ID は 3, 4 バイト目の下位 10 ビット */
+        let header = [0x10, 0x00, 0xfc, 0x2a, 0, 0, 0, 0];
+        assert_eq!(
+            decode(decode_gre, &gre(0x1000, 0x88be, &[&sequence, &header, &ETHERNET])),
+            Some((Tunnel::ErspanII { session: 0x02a }, EtherTypes::Ipv4, 4))
+        );
+        assert!(decode_gre(&gre(0x1000, 0x88be, &[&sequence, &header[..7]])).is_none());
+        /* type III: 12 バイトのヘッダ, 最後のバイトの O ビットで 8 バイトの subheader が付く */
+        let mut header = [0x20, 0x00, 0x01, 0x05, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            decode(decode_gre, &gre(0x1000, 0x22eb, &[&sequence, &header, &ETHERNET])),
+            Some((Tunnel::ErspanIII { session: 0x105 }, EtherTypes::Ipv4, 4))
+        );
+        assert!(decode_gre(&gre(0x1000, 0x22eb, &[&sequence, &header[..11]])).is_none());
+        header[11] = 0x01;
+        let subheader = [0xbb; 8];
+        assert_eq!(
+            decode(decode_gre, &gre(0x1000, 0x22eb, &[&sequence, &header, &subheader, &ETHERNET])),
+            Some((Tunnel::ErspanIII { session: 0x105 }, EtherTypes::Ipv4, 4))
+        );
+        assert!(decode_gre(&gre(0x1000, 0x22eb, &[&sequence, &header, &subheader[..7]])).is_none());
+    }
+
+    #[test]
+    fn vxlan() {
+        let header = [0x08, 0, 0, 0, 0x01, 0x02, 0x03, 0];
+        let packet = [&header[..], &ETHERNET].concat();
+        assert_eq!(
+            decode(decode_vxlan, &packet),
+            Some((Tunnel::Vxlan { vni: 0x010203 }, EtherTypes::Ipv4, 4))
+        );
+        /* I フラグがなければ VXLAN として扱わない */
+        let mut packet = packet;
+        packet[0] = 0;
+        assert!(decode_vxlan(&packet).is_none());
+        assert!(decode_vxlan(&header[..7]).is_none());
+        assert!(decode_vxlan(&[&header[..], &ETHERNET[..13]].concat()).is_none());
+    }
+}